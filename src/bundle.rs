@@ -0,0 +1,105 @@
+use crate::Entity;
+use crate::ComponentType;
+use crate::ComponentManager;
+
+use std::any::Any;
+
+// Set of components inserted at once, so system membership is evaluated only when the whole set
+// is in place. Implemented for tuples, user structs can forward to a tuple of their fields.
+pub trait Bundle {
+    fn component_types(&self) -> Vec<ComponentType>;
+    fn add_to(self, e: Entity, cm: &mut ComponentManager);
+
+    fn reserve(_cm: &mut ComponentManager, _additional: usize) where Self: Sized {}
+}
+
+macro_rules! impl_bundle {
+    ($($name:ident),+) => {
+        impl<$($name: Any),+> Bundle for ($($name,)+) {
+            fn component_types(&self) -> Vec<ComponentType> {
+                vec![$(ComponentType::of::<$name>()),+]
+            }
+
+            #[allow(non_snake_case)]
+            fn add_to(self, e: Entity, cm: &mut ComponentManager) {
+                let ($($name,)+) = self;
+                $(cm.add(e, $name);)+
+            }
+
+            fn reserve(cm: &mut ComponentManager, additional: usize) {
+                $(cm.reserve::<$name>(additional);)+
+            }
+        }
+    };
+}
+
+impl Bundle for () {
+    fn component_types(&self) -> Vec<ComponentType> {
+        Vec::new()
+    }
+
+    fn add_to(self, _e: Entity, _cm: &mut ComponentManager) {}
+}
+
+impl_bundle!(A);
+impl_bundle!(A, B);
+impl_bundle!(A, B, C);
+impl_bundle!(A, B, C, D);
+impl_bundle!(A, B, C, D, E);
+impl_bundle!(A, B, C, D, E, F);
+impl_bundle!(A, B, C, D, E, F, G);
+impl_bundle!(A, B, C, D, E, F, G, H);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+
+    #[derive(Debug, PartialEq)]
+    struct Health(u32);
+
+    struct Unit {
+        health: Health,
+        level: u8,
+    }
+
+    impl Bundle for Unit {
+        fn component_types(&self) -> Vec<ComponentType> {
+            vec![ComponentType::of::<Health>(), ComponentType::of::<u8>()]
+        }
+
+        fn add_to(self, e: Entity, cm: &mut ComponentManager) {
+            (self.health, self.level).add_to(e, cm);
+        }
+    }
+
+    #[test]
+    fn test_tuple_bundle() {
+        let mut cm = ComponentManager::new();
+        cm.register::<u32>();
+        cm.register::<Health>();
+
+        let bundle = (7u32, Health(10));
+        assert_eq!(vec![ComponentType::of::<u32>(), ComponentType::of::<Health>()], bundle.component_types());
+
+        let e: Entity = 1;
+        bundle.add_to(e, &mut cm);
+        assert_eq!(Some(&7), cm.get::<u32>(&e));
+        assert_eq!(Some(&Health(10)), cm.get::<Health>(&e));
+    }
+
+    #[test]
+    fn test_struct_bundle() {
+        let mut cm = ComponentManager::new();
+        cm.register::<u8>();
+        cm.register::<Health>();
+
+        let e: Entity = 1;
+        Unit { health: Health(3), level: 2 }.add_to(e, &mut cm);
+
+        let expected: HashSet<ComponentType> = HashSet::from_iter(vec![ComponentType::of::<Health>(), ComponentType::of::<u8>()]);
+        assert_eq!(expected, cm.get_component_types(e));
+        assert_eq!(Some(&2), cm.get::<u8>(&e));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::Entity;
+use crate::ComponentType;
+
+use std::collections::HashMap;
+use std::any::TypeId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Spawn,
+    Despawn,
+    Add,
+    Remove,
+    Relate,
+    Unrelate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub entity: Entity, // source for Relate/Unrelate
+    pub component_type: Option<ComponentType>, // None for Spawn/Despawn, relation type for Relate/Unrelate
+    pub kind: ChangeKind,
+    pub target: Option<Entity>, // Relate/Unrelate only
+}
+
+impl Change {
+    pub fn spawn(entity: Entity) -> Change {
+        Change { entity, component_type: None, kind: ChangeKind::Spawn, target: None }
+    }
+
+    pub fn despawn(entity: Entity) -> Change {
+        Change { entity, component_type: None, kind: ChangeKind::Despawn, target: None }
+    }
+
+    pub fn add(entity: Entity, component_type: ComponentType) -> Change {
+        Change { entity, component_type: Some(component_type), kind: ChangeKind::Add, target: None }
+    }
+
+    pub fn remove(entity: Entity, component_type: ComponentType) -> Change {
+        Change { entity, component_type: Some(component_type), kind: ChangeKind::Remove, target: None }
+    }
+
+    pub fn relate(source: Entity, relation: TypeId, target: Entity) -> Change {
+        Change { entity: source, component_type: Some(relation), kind: ChangeKind::Relate, target: Some(target) }
+    }
+
+    pub fn unrelate(source: Entity, relation: TypeId, target: Entity) -> Change {
+        Change { entity: source, component_type: Some(relation), kind: ChangeKind::Unrelate, target: Some(target) }
+    }
+}
+
+pub type SubscriberId = u32;
+
+// Changes are recorded only while there is at least one subscriber. Every subscriber keeps
+// its own cursor into the shared log, and entries are dropped once all subscribers drained them.
+// There are no command buffers besides the deferred closures returned by systems, those go
+// through regular Coordinator calls and are logged like any other change.
+pub struct ChangeLog {
+    changes: Vec<Change>,
+    offset: usize, // absolute position of changes[0]
+    cursors: HashMap<SubscriberId, usize>,
+    next_id: SubscriberId,
+}
+
+impl ChangeLog {
+    pub fn new() -> ChangeLog {
+        ChangeLog {
+            changes: Vec::new(),
+            offset: 0,
+            cursors: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn subscribe(&mut self) -> SubscriberId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.cursors.insert(id, self.offset + self.changes.len());
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: SubscriberId) {
+        self.cursors.remove(&id);
+        self.compact();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.cursors.is_empty()
+    }
+
+    pub fn push(&mut self, change: Change) {
+        if self.is_enabled() {
+            self.changes.push(change);
+        }
+    }
+
+    pub fn drain(&mut self, id: SubscriberId) -> Vec<Change> {
+        let end = self.offset + self.changes.len();
+        let cursor = match self.cursors.get_mut(&id) {
+            Some(cursor) => cursor,
+            None => return Vec::new(),
+        };
+
+        let drained = self.changes[*cursor - self.offset..].to_vec();
+        *cursor = end;
+        self.compact();
+        drained
+    }
+
+    // Priv
+
+    fn compact(&mut self) {
+        let consumed = match self.cursors.values().min() {
+            Some(min) => *min - self.offset,
+            None => self.changes.len(),
+        };
+        self.changes.drain(..consumed);
+        self.offset += consumed;
+    }
+}
+
+impl Default for ChangeLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_change_log_is_opt_in() {
+        let mut log = ChangeLog::new();
+        log.push(Change::spawn(1));
+
+        let id = log.subscribe();
+        assert_eq!(Vec::<Change>::new(), log.drain(id), "Changes before subscription are not recorded");
+
+        log.push(Change::spawn(2));
+        assert_eq!(vec![Change::spawn(2)], log.drain(id));
+        assert_eq!(Vec::<Change>::new(), log.drain(id));
+    }
+
+    #[test]
+    fn test_change_log_drains_per_subscriber() {
+        let t = ComponentType::of::<u32>();
+        let mut log = ChangeLog::new();
+        let first = log.subscribe();
+
+        log.push(Change::spawn(1));
+        let second = log.subscribe();
+        log.push(Change::add(1, t));
+
+        assert_eq!(vec![Change::spawn(1), Change::add(1, t)], log.drain(first));
+        log.push(Change::remove(1, t));
+        assert_eq!(vec![Change::remove(1, t)], log.drain(first));
+        assert_eq!(vec![Change::add(1, t), Change::remove(1, t)], log.drain(second));
+
+        log.unsubscribe(first);
+        log.unsubscribe(second);
+        log.push(Change::despawn(1));
+        assert!(!log.is_enabled());
+        assert_eq!(Vec::<Change>::new(), log.drain(first));
+    }
+}
@@ -1,26 +1,62 @@
 use crate::Entity;
 use crate::ComponentType;
+use crate::ComponentRegistry;
+use crate::registry::CloneError;
+use crate::index::{AnyIndex, IndexKind, Indexed, ValueIndex};
+use crate::spatial::{Located, SpatialGrid, SpatialIndex};
+use crate::pool::EntityReserver;
 
 use std::collections::HashSet;
 use std::collections::HashMap;
 use std::any::Any;
+use std::ops::RangeBounds;
+use std::rc::Rc;
+
+pub trait AnyComponentArray {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    fn name(&self) -> &str;
+    fn set_name(&mut self, name: &str);
+
+    fn empty(&self) -> Box<dyn AnyComponentArray>; // new array for the same type and name
+    fn remove_entity(&mut self, e: Entity) -> bool;
+    fn clear(&mut self);
+    fn entities(&self) -> Vec<Entity>;
+
+    fn get_any(&self, e: Entity) -> Option<&dyn Any>;
+    fn insert_any(&mut self, e: Entity, component: Box<dyn Any>) -> bool; // false on type mismatch
+}
 
 pub struct ComponentArray<T> {
+    name: String,
     components: HashMap<Entity, T>,
-                                                  // string type as a key
 }
 
 impl<T> ComponentArray<T> {
-    pub fn new(_name: &'static str) -> ComponentArray<T> { // TODO: _name are is not used...
+    pub fn new(name: &str) -> ComponentArray<T> {
         ComponentArray {
+            name: String::from(name),
             components: HashMap::new(),
         }
     }
 
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = String::from(name);
+    }
+
     pub fn add(&mut self, e: Entity, component: T) {
         self.components.insert(e, component);
     }
 
+    pub fn reserve(&mut self, additional: usize) {
+        self.components.reserve(additional);
+    }
+
     pub fn get(&mut self, e: &Entity) -> Option<&T> {
         self.components.get(e)
     }
@@ -34,10 +70,75 @@ impl<T> ComponentArray<T> {
     }
 }
 
+impl<T: Any> AnyComponentArray for ComponentArray<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        ComponentArray::name(self)
+    }
+
+    fn set_name(&mut self, name: &str) {
+        ComponentArray::set_name(self, name)
+    }
+
+    fn empty(&self) -> Box<dyn AnyComponentArray> {
+        Box::new(ComponentArray::<T>::new(&self.name))
+    }
+
+    fn remove_entity(&mut self, e: Entity) -> bool {
+        self.components.remove(&e).is_some()
+    }
+
+    fn clear(&mut self) {
+        self.components.clear();
+    }
+
+    fn entities(&self) -> Vec<Entity> {
+        self.components.keys().copied().collect()
+    }
+
+    fn get_any(&self, e: Entity) -> Option<&dyn Any> {
+        self.components.get(&e).map(|c| c as &dyn Any)
+    }
+
+    fn insert_any(&mut self, e: Entity, component: Box<dyn Any>) -> bool {
+        match component.downcast::<T>() {
+            Ok(component) => {
+                self.components.insert(e, *component);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+type EntityTypes = HashMap<Entity, HashSet<ComponentType>>;
+
+// Read only copy of all component arrays. Arrays not modified since previous checkpoint are
+// shared with it instead of being copied again.
+pub struct Checkpoint {
+    arrays: HashMap<ComponentType, (u64, Rc<dyn AnyComponentArray>)>,
+    entity_types: (u64, Rc<EntityTypes>),
+}
+
 pub struct ComponentManager {
     component_types: HashSet<ComponentType>,
-    component_arrays: HashMap<ComponentType, Box<dyn Any>>,
-    entity_to_component_types: HashMap<Entity, HashSet<ComponentType>>,
+    component_arrays: HashMap<ComponentType, Box<dyn AnyComponentArray>>,
+    entity_to_component_types: EntityTypes,
+    registry: ComponentRegistry,
+    // Every mutable access stamps the array (or entity types for structural changes) with a
+    // new version, so equal versions mean equal content.
+    versions: HashMap<ComponentType, u64>,
+    entity_types_version: u64,
+    next_version: u64,
+    indexes: HashMap<ComponentType, Vec<Box<dyn AnyIndex>>>,
+    reserver: EntityReserver,
 }
 
 impl ComponentManager {
@@ -46,14 +147,87 @@ impl ComponentManager {
             component_types: HashSet::new(),
             component_arrays: HashMap::new(),
             entity_to_component_types: HashMap::new(),
+            registry: ComponentRegistry::new(),
+            versions: HashMap::new(),
+            entity_types_version: 0,
+            next_version: 1,
+            indexes: HashMap::new(),
+            reserver: EntityReserver::new(),
         }
     }
 
+    // Entities
+    // Usable inside System::apply, the id is spawned once apply is over. None when the pool
+    // is out of ids or when called outside of apply.
+    pub fn reserve_entity(&self) -> Option<Entity> {
+        self.reserver.reserve()
+    }
+
+    pub fn reserver(&self) -> &EntityReserver {
+        &self.reserver
+    }
+
     // Entities Components
     pub fn register<T: Any>(&mut self) {
+        self.register_named::<T>(std::any::type_name::<T>());
+    }
+
+    // Registering a known type again keeps its components and name, see rename
+    pub fn register_named<T: Any>(&mut self, name: &str) {
+        if self.component_arrays.contains_key(&ComponentType::of::<T>()) {
+            return;
+        }
+        self.registry.register::<T>(name);
         self.component_types.insert(ComponentType::of::<T>());
-        let arr: ComponentArray<T>  = ComponentArray::new("coords");
+        let arr: ComponentArray<T>  = ComponentArray::new(name);
         self.component_arrays.insert(ComponentType::of::<T>(), Box::new(arr));
+        self.touch(ComponentType::of::<T>());
+        self.rebuild_index(ComponentType::of::<T>());
+    }
+
+    // Index is filled with components already in place
+    pub fn register_index<T: Indexed>(&mut self, kind: IndexKind) {
+        let id = ComponentType::of::<T>();
+        if !self.component_types.contains(&id) {
+            panic!("Component type shoud be registered prior to its use");
+        }
+        self.add_index(id, Box::new(ValueIndex::<T>::new(kind)));
+    }
+
+    pub fn register_spatial_index<T: Located>(&mut self, cell_size: f32) {
+        let id = ComponentType::of::<T>();
+        if !self.component_types.contains(&id) {
+            panic!("Component type shoud be registered prior to its use");
+        }
+        self.add_index(id, Box::new(SpatialIndex::<T>::new(cell_size)));
+    }
+
+    pub fn lookup<T: Indexed>(&mut self, key: &T::Key) -> Vec<Entity> {
+        self.index::<ValueIndex<T>>(ComponentType::of::<T>()).get(key)
+    }
+
+    pub fn range<T: Indexed, R: RangeBounds<T::Key>>(&mut self, range: R) -> Vec<Entity> {
+        self.index::<ValueIndex<T>>(ComponentType::of::<T>()).range(range)
+    }
+
+    pub fn spatial<T: Located>(&mut self) -> &SpatialGrid {
+        self.index::<SpatialIndex<T>>(ComponentType::of::<T>()).grid()
+    }
+
+    // Gives already known type a new stable name
+    pub fn rename<T: Any>(&mut self, name: &str) {
+        self.registry.register::<T>(name);
+        if let Some(array) = self.component_arrays.get_mut(&ComponentType::of::<T>()) {
+            array.set_name(name);
+        }
+    }
+
+    pub fn registry(&self) -> &ComponentRegistry {
+        &self.registry
+    }
+
+    pub fn registry_mut(&mut self) -> &mut ComponentRegistry {
+        &mut self.registry
     }
 
     pub fn add<T: Any>(&mut self, e: Entity, component: T) {
@@ -65,33 +239,206 @@ impl ComponentManager {
             panic!("Component type shoud be registered prior to its use");
         }
 
-        if let Some(hash_set) = self.entity_to_component_types.get_mut(&e) {
-            hash_set.insert(id);
-        } else {
-            self.entity_to_component_types.insert(e, HashSet::from_iter(vec![id]));
+        self.touch(id);
+        self.insert_entity_type(e, id);
+        self.update_index(id, e);
+    }
+
+    // Changes whenever anything in the array may have changed, 0 for unknown types
+    pub fn version(&self, id: ComponentType) -> u64 {
+        self.versions.get(&id).copied().unwrap_or(0)
+    }
+
+    pub fn is_registered(&self, id: ComponentType) -> bool {
+        self.component_types.contains(&id)
+    }
+
+    // Type erased counterparts of add/get, used where concrete type is not known statically
+    pub fn add_any(&mut self, e: Entity, id: ComponentType, component: Box<dyn Any>) {
+        let array = match self.component_arrays.get_mut(&id) {
+            Some(array) => array,
+            None => panic!("Component type shoud be registered prior to its use"),
         };
+        if !array.insert_any(e, component) {
+            panic!("Component value does not match its component type");
+        }
+
+        self.touch(id);
+        self.insert_entity_type(e, id);
+        self.update_index(id, e);
+    }
+
+    pub fn get_any(&self, e: Entity, id: ComponentType) -> Option<&dyn Any> {
+        self.component_arrays.get(&id)?.get_any(e)
+    }
+
+    pub fn entities(&self) -> Vec<Entity> {
+        self.entity_to_component_types.keys().copied().collect()
+    }
+
+    pub fn entities_with(&self, id: ComponentType) -> Vec<Entity> {
+        match self.component_arrays.get(&id) {
+            Some(array) => array.entities(),
+            None => Vec::new(),
+        }
+    }
+
+    // Deep copy made with clone functions from the registry. Fails if there is a component
+    // of type without one, types with no components at the moment don't need it.
+    pub fn try_clone(&self) -> Result<ComponentManager, CloneError> {
+        let mut component_arrays = HashMap::with_capacity(self.component_arrays.len());
+        for (id, array) in self.component_arrays.iter() {
+            component_arrays.insert(*id, self.clone_array(*id, array.as_ref())?);
+        }
+
+        Ok(ComponentManager {
+            component_types: self.component_types.clone(),
+            component_arrays,
+            entity_to_component_types: self.entity_to_component_types.clone(),
+            registry: self.registry.clone(),
+            versions: self.versions.clone(),
+            entity_types_version: self.entity_types_version,
+            next_version: self.next_version,
+            indexes: self.indexes.iter()
+                .map(|(id, indexes)| (*id, indexes.iter().map(|index| index.clone_box()).collect()))
+                .collect(),
+            reserver: EntityReserver::new(),
+        })
+    }
+
+    pub fn checkpoint(&self, previous: Option<&Checkpoint>) -> Result<Checkpoint, CloneError> {
+        let mut arrays = HashMap::with_capacity(self.component_arrays.len());
+        for (id, array) in self.component_arrays.iter() {
+            let version = self.versions[id];
+            let shared = previous
+                .and_then(|p| p.arrays.get(id))
+                .filter(|(previous_version, _)| *previous_version == version);
+            let copy = match shared {
+                Some((_, copy)) => copy.clone(),
+                None => Rc::from(self.clone_array(*id, array.as_ref())?),
+            };
+            arrays.insert(*id, (version, copy));
+        }
+
+        let entity_types = match previous {
+            Some(p) if p.entity_types.0 == self.entity_types_version => p.entity_types.1.clone(),
+            _ => Rc::new(self.entity_to_component_types.clone()),
+        };
+
+        Ok(Checkpoint { arrays, entity_types: (self.entity_types_version, entity_types) })
+    }
+
+    // Only arrays which changed since the checkpoint are copied back
+    pub fn restore(&mut self, checkpoint: &Checkpoint) {
+        let ids: Vec<ComponentType> = self.component_arrays.keys().copied().collect();
+        for id in ids {
+            match checkpoint.arrays.get(&id) {
+                Some((version, _)) if *version == self.versions[&id] => {},
+                Some((version, saved)) => {
+                    let copy = self.clone_array(id, saved.as_ref())
+                        .expect("Checkpointed components are cloneable");
+                    self.component_arrays.insert(id, copy);
+                    self.versions.insert(id, *version);
+                    self.rebuild_index(id);
+                },
+                None => { // Registered after the checkpoint
+                    self.component_arrays.get_mut(&id).unwrap().clear();
+                    self.touch(id);
+                    self.rebuild_index(id);
+                },
+            }
+        }
+
+        let (version, entity_types) = &checkpoint.entity_types;
+        if *version != self.entity_types_version {
+            self.entity_to_component_types = entity_types.as_ref().clone();
+            self.entity_types_version = *version;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        let ids: Vec<ComponentType> = self.component_arrays.keys().copied().collect();
+        for id in ids {
+            self.component_arrays.get_mut(&id).unwrap().clear();
+            self.touch(id);
+            self.rebuild_index(id);
+        }
+        self.entity_to_component_types.clear();
+        self.touch_entity_types();
+    }
+
+    pub fn reserve<T: Any>(&mut self, additional: usize) {
+        self.entity_to_component_types.reserve(additional);
+        let array = self.get_component_array::<T>();
+        array.reserve(additional);
     }
 
     pub fn get<T: Any>(&mut self, e: &Entity) -> Option<&T> {
         let array = self.get_component_array(); // TODO: get_component_array and get_component_array_mut?
-        array.get(&e)
+        array.get(e)
     }
 
     pub fn get_mut<T: Any>(&mut self, e: &Entity) -> Option<&mut T> {
+        self.touch(ComponentType::of::<T>());
+        for index in self.indexes.get_mut(&ComponentType::of::<T>()).into_iter().flatten() {
+            index.mark_dirty(*e);
+        }
         let array = self.get_component_array();
-        array.get_mut(&e)
+        array.get_mut(e)
     }
 
     pub fn remove<T: Any>(&mut self, e: &Entity) -> Option<T> {
         let id = ComponentType::of::<T>();
-        if let Some(hash_set) = self.entity_to_component_types.get_mut(&e) {
-            hash_set.remove(&id);
+        if let Some(hash_set) = self.entity_to_component_types.get_mut(e) {
+            if hash_set.remove(&id) {
+                self.touch_entity_types();
+            }
         }
 
+        self.touch(id);
+        self.remove_from_index(id, *e);
         let array = self.get_component_array();
         array.remove(e)
     }
 
+    pub fn remove_any(&mut self, e: Entity, id: ComponentType) -> bool {
+        if let Some(hash_set) = self.entity_to_component_types.get_mut(&e) {
+            if hash_set.remove(&id) {
+                self.touch_entity_types();
+            }
+        }
+
+        let removed = match self.component_arrays.get_mut(&id) {
+            Some(array) => array.remove_entity(e),
+            None => false,
+        };
+        if removed {
+            self.touch(id);
+            self.remove_from_index(id, e);
+        }
+        removed
+    }
+
+    pub fn remove_all(&mut self, e: Entity) -> Vec<ComponentType> {
+        let types = match self.entity_to_component_types.remove(&e) {
+            Some(types) => types,
+            None => return Vec::new(),
+        };
+        self.touch_entity_types();
+
+        let mut removed = Vec::with_capacity(types.len());
+        for id in types {
+            if let Some(array) = self.component_arrays.get_mut(&id) {
+                if array.remove_entity(e) {
+                    removed.push(id);
+                    self.touch(id);
+                    self.remove_from_index(id, e);
+                }
+            }
+        }
+        removed
+    }
+
     pub fn get_component_types(&self, e: Entity) -> HashSet<ComponentType> {
         match self.entity_to_component_types.get(&e) {
             Some(types) => types.clone(),
@@ -99,25 +446,99 @@ impl ComponentManager {
         }
     }
 
+    pub fn get_component_types_ref(&self, e: Entity) -> Option<&HashSet<ComponentType>> {
+        self.entity_to_component_types.get(&e)
+    }
+
     // Priv
 
+    fn touch(&mut self, id: ComponentType) {
+        self.versions.insert(id, self.next_version);
+        self.next_version += 1;
+    }
+
+    fn touch_entity_types(&mut self) {
+        self.entity_types_version = self.next_version;
+        self.next_version += 1;
+    }
+
+    fn insert_entity_type(&mut self, e: Entity, id: ComponentType) {
+        if self.entity_to_component_types.entry(e).or_default().insert(id) {
+            self.touch_entity_types();
+        }
+    }
+
+    fn update_index(&mut self, id: ComponentType, e: Entity) {
+        for index in self.indexes.get_mut(&id).into_iter().flatten() {
+            index.update(e, self.component_arrays[&id].get_any(e).unwrap());
+        }
+    }
+
+    fn remove_from_index(&mut self, id: ComponentType, e: Entity) {
+        for index in self.indexes.get_mut(&id).into_iter().flatten() {
+            index.remove(e);
+        }
+    }
+
+    fn rebuild_index(&mut self, id: ComponentType) {
+        for index in self.indexes.get_mut(&id).into_iter().flatten() {
+            index.rebuild(self.component_arrays[&id].as_ref());
+        }
+    }
+
+    // Replaces index of the same kind
+    fn add_index(&mut self, id: ComponentType, mut index: Box<dyn AnyIndex>) {
+        index.rebuild(self.component_arrays[&id].as_ref());
+        let indexes = self.indexes.entry(id).or_default();
+        indexes.retain(|other| other.as_any().type_id() != index.as_any().type_id());
+        indexes.push(index);
+    }
+
+    // Brought up to date with values changed through get_mut
+    fn index<I: Any>(&mut self, id: ComponentType) -> &I {
+        let array = self.component_arrays.get(&id).map(|array| array.as_ref());
+        let index = self.indexes.get_mut(&id).into_iter().flatten()
+            .find(|index| index.as_any().is::<I>());
+        let (index, array) = match (index, array) {
+            (Some(index), Some(array)) => (index, array),
+            _ => panic!("Component type has no such index registered"),
+        };
+        index.refresh(array);
+        index.as_any().downcast_ref::<I>().unwrap()
+    }
+
+    fn clone_array(&self, id: ComponentType, array: &dyn AnyComponentArray) -> Result<Box<dyn AnyComponentArray>, CloneError> {
+        let mut copy = array.empty();
+        for e in array.entities() {
+            let value = array.get_any(e).unwrap();
+            let cloned = self.registry.clone_value(id, value)
+                .ok_or_else(|| CloneError::Component(String::from(array.name())))?;
+            copy.insert_any(e, cloned);
+        }
+        Ok(copy)
+    }
+
     fn get_component_array<T: Any>(&mut self) -> &mut ComponentArray<T> {
         let id = ComponentType::of::<T>();
-        self.component_arrays.get_mut(&id).unwrap().downcast_mut::<ComponentArray<T>>().unwrap()
+        self.component_arrays.get_mut(&id).unwrap().as_any_mut().downcast_mut::<ComponentArray<T>>().unwrap()
     }
 
 }
 
+impl Default for ComponentManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_component_array() {
-        let mut a = ComponentArray::new("test_array"); // TODO: we are
-                                                                                   // not using
-                                                                                   // name at the
-                                                                                   // moment
+        let mut a = ComponentArray::new("test_array");
+        assert_eq!("test_array", a.name());
         let e1: Entity = 1;
         let e2: Entity = 2;
         a.add(e1, "one");
@@ -152,12 +573,16 @@ mod tests {
 
         let mut cm = ComponentManager::new();
         cm.register::<i32>();
-        cm.register::<Coords>();
+        cm.register_named::<Coords>("coords");
+        assert_eq!(Some("i32"), cm.registry().name_of(ComponentType::of::<i32>()));
+        assert_eq!(Some(ComponentType::of::<Coords>()), cm.registry().type_of("coords"));
 
         cm.add(e1, 1);
         cm.add(e1, Coords { x: 5, y: 10 });
 
         cm.add(e2, 2);
+        cm.register::<Coords>(); // Already known, no-op
+        assert_eq!(Some(ComponentType::of::<Coords>()), cm.registry().type_of("coords"));
 
         assert_eq!(Some(&1), cm.get::<i32>(&e1));
         assert_eq!(Some(&2), cm.get::<i32>(&e2));
@@ -189,9 +614,79 @@ mod tests {
 
     #[test]
     #[should_panic]
+    #[allow(clippy::approx_constant)]
     fn test_cm_panics_if_entity_added_without_prior_type_registration() {
         let mut cm = ComponentManager::new();
         let e: Entity = 1;
         cm.add(e, 3.14);
     }
+
+    #[test]
+    fn test_checkpoint_restore() {
+        let e1: Entity = 1;
+        let e2: Entity = 2;
+
+        let mut cm = ComponentManager::new();
+        cm.register::<i32>();
+        cm.register::<Coords>();
+        cm.registry_mut().set_clone::<i32>();
+        cm.add(e1, 1);
+        cm.add(e1, Coords { x: 0, y: 0 }); // Coords is not cloneable
+
+        assert!(cm.checkpoint(None).is_err());
+        cm.remove::<Coords>(&e1);
+        let first = cm.checkpoint(None).unwrap();
+
+        *cm.get_mut::<i32>(&e1).unwrap() = 10;
+        cm.add(e2, 20);
+        let second = cm.checkpoint(Some(&first)).unwrap();
+        let unchanged = cm.checkpoint(Some(&second)).unwrap();
+        assert!(Rc::ptr_eq(&second.arrays[&ComponentType::of::<i32>()].1, &unchanged.arrays[&ComponentType::of::<i32>()].1),
+            "Unchanged array should be shared");
+        assert!(Rc::ptr_eq(&first.arrays[&ComponentType::of::<Coords>()].1, &second.arrays[&ComponentType::of::<Coords>()].1));
+
+        cm.remove_all(e1);
+        cm.restore(&first);
+        assert_eq!(Some(&1), cm.get::<i32>(&e1));
+        assert_eq!(None, cm.get::<i32>(&e2));
+        assert_eq!(HashSet::from_iter(vec![ComponentType::of::<i32>()]), cm.get_component_types(e1));
+        assert_eq!(HashSet::new(), cm.get_component_types(e2));
+
+        cm.restore(&second);
+        assert_eq!(Some(&10), cm.get::<i32>(&e1));
+        assert_eq!(Some(&20), cm.get::<i32>(&e2));
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct NetworkId(u32);
+
+    impl Indexed for NetworkId {
+        type Key = u32;
+
+        fn key(&self) -> u32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_index_follows_changes() {
+        let mut cm = ComponentManager::new();
+        cm.register::<NetworkId>();
+        cm.add(1, NetworkId(42));
+        cm.register_index::<NetworkId>(IndexKind::Ordered);
+        cm.add(2, NetworkId(7));
+        cm.add_any(3, ComponentType::of::<NetworkId>(), Box::new(NetworkId(42)));
+        assert_eq!(vec![1, 3], cm.lookup::<NetworkId>(&42));
+
+        cm.get_mut::<NetworkId>(&3).unwrap().0 = 8;
+        assert_eq!(vec![2, 3], cm.range::<NetworkId, _>(7..=8));
+
+        cm.remove::<NetworkId>(&2);
+        cm.remove_all(1);
+        assert!(cm.lookup::<NetworkId>(&42).is_empty());
+        assert_eq!(vec![3], cm.range::<NetworkId, _>(..));
+
+        cm.clear();
+        assert!(cm.range::<NetworkId, _>(..).is_empty());
+    }
 }
@@ -1,14 +1,43 @@
 // TODO: change name of coordinator to just ecssth or sth ecs
 //       this is just public interface to ecs
 use crate::EntitiesPool;
+use crate::pool::Reuse;
 use crate::Entity;
+use crate::EntityRef;
 use crate::ComponentManager;
 use crate::SystemManager;
 use crate::System;
-use crate::SystemType;
+use crate::Bundle;
+use crate::signature::Disabled;
+use crate::Globals;
+use crate::ComponentRegistry;
+use crate::registry::{CloneError, ClonePolicy};
+use crate::ComponentType;
+use crate::changes::{Change, ChangeLog, SubscriberId};
+use crate::observer::{Observer, ObserverManager};
+use crate::rollback::{RollbackBuffer, RollbackError, Tick};
+use crate::hash::{self, StateHash};
+use crate::hierarchy::{self, Ancestors, Children, DepthFirst, HierarchyError, Parent};
+use crate::relation::{Cleanup, RelationStore};
+use crate::names::{Name, Tags};
+use crate::prefab::{Instance, Prefab};
+use crate::index::{IndexKind, Indexed};
+use crate::spatial::Located;
+use crate::transform::{GlobalTransform2D, GlobalTransform3D, LocalTransform2D, LocalTransform3D};
+#[cfg(feature = "snapshot")]
+use crate::snapshot::{self, Persist, SnapshotError};
+#[cfg(feature = "snapshot")]
+use crate::delta::{self, WorldState};
+#[cfg(feature = "scene")]
+use crate::scene::{self, SceneComponent, SceneError, SceneRegistry};
 
-use std::collections::hash_set::Iter;
-use std::any::Any;
+use std::collections::HashSet;
+use std::collections::HashMap;
+use std::collections::BTreeSet;
+use std::collections::btree_set::Iter;
+use std::any::{Any, TypeId};
+use std::ops::RangeBounds;
+use std::hash::Hash;
 use std::rc::Rc;
 use std::cell::RefCell;
 
@@ -16,245 +45,2713 @@ pub struct Coordinator {
     pool: EntitiesPool,
     cm: ComponentManager,
     sm: SystemManager,
+    changes: ChangeLog,
+    om: ObserverManager,
+    globals: Globals,
+    relations: RelationStore,
+    tags: Tags,
+    clone_policy: ClonePolicy,
+    rollback: RollbackBuffer,
+    #[cfg(feature = "scene")]
+    scene: SceneRegistry,
 }
 
 impl Coordinator {
     pub fn new() -> Coordinator {
+        Coordinator::with_pool(EntitiesPool::new())
+    }
+
+    pub fn with_capacity(max_entities: u32) -> Coordinator {
+        Coordinator::with_pool(EntitiesPool::with_capacity(max_entities))
+    }
+
+    fn with_pool(pool: EntitiesPool) -> Coordinator {
+        let mut cm = ComponentManager::new();
+        cm.register_named::<Parent>("Parent");
+        cm.register_named::<Children>("Children");
+        cm.register_named::<Disabled>("Disabled");
+        cm.register_named::<Name>("Name");
+        cm.register_named::<LocalTransform2D>("LocalTransform2D");
+        cm.register_named::<GlobalTransform2D>("GlobalTransform2D");
+        cm.register_named::<LocalTransform3D>("LocalTransform3D");
+        cm.register_named::<GlobalTransform3D>("GlobalTransform3D");
+        cm.register_index::<Name>(IndexKind::Hash);
+        let registry = cm.registry_mut();
+        registry.set_clone::<Disabled>();
+        registry.set_hash::<Disabled>();
+        registry.set_clone::<Name>();
+        registry.set_hash::<Name>();
+        registry.set_clone::<Parent>();
+        registry.set_clone::<Children>();
+        registry.set_clone::<LocalTransform2D>();
+        registry.set_clone::<GlobalTransform2D>();
+        registry.set_clone::<LocalTransform3D>();
+        registry.set_clone::<GlobalTransform3D>();
+        registry.set_hash::<Parent>();
+        registry.set_hash::<Children>();
+        #[cfg(feature = "snapshot")]
+        registry.set_serializer::<Disabled>();
+        #[cfg(feature = "snapshot")]
+        registry.set_serializer::<Name>();
+        #[cfg(feature = "snapshot")]
+        registry.set_serializer::<Parent>();
+        #[cfg(feature = "snapshot")]
+        registry.set_serializer::<Children>();
+
         Coordinator {
-            pool: EntitiesPool::new(),
-            cm: ComponentManager::new(),
+            pool,
+            cm,
             sm: SystemManager::new(),
+            changes: ChangeLog::new(),
+            om: ObserverManager::new(),
+            globals: Globals::new(),
+            relations: RelationStore::new(),
+            tags: Tags::new(),
+            clone_policy: ClonePolicy::Error,
+            rollback: RollbackBuffer::default(),
+            #[cfg(feature = "scene")]
+            scene: SceneRegistry::new(),
         }
     }
 
+    // Systems and observers are copied with their box_clone, so every one of them has to
+    // provide it. Change subscriptions and saved ticks are not copied.
+    pub fn try_clone(&self) -> Result<Coordinator, CloneError> {
+        Ok(Coordinator {
+            pool: self.pool.clone(),
+            cm: self.cm.try_clone()?,
+            sm: self.sm.try_clone()?,
+            changes: ChangeLog::new(),
+            om: self.om.try_clone()?,
+            globals: self.globals.try_clone(self.cm.registry())?,
+            relations: self.relations.clone(),
+            tags: self.tags.clone(),
+            clone_policy: self.clone_policy,
+            rollback: RollbackBuffer::default(),
+            #[cfg(feature = "scene")]
+            scene: self.scene.clone(),
+        })
+    }
+
     // Entities
     pub fn entity_take(&mut self) -> Entity {
-        self.pool.take()
+        let e = self.pool.take();
+        self.record(Change::spawn(e));
+        e
+    }
+
+    pub fn spawn<B: Bundle>(&mut self, bundle: B) -> Entity {
+        let e = self.pool.take();
+        self.record(Change::spawn(e));
+        self.add_bundle(e, bundle);
+        e
+    }
+
+    // Panics before spawning anything if there are not enough free entities for the batch
+    pub fn spawn_batch<B, I>(&mut self, bundles: I) -> Vec<Entity>
+    where
+        B: Bundle,
+        I: IntoIterator<Item = B>,
+    {
+        let bundles: Vec<B> = bundles.into_iter().collect();
+        let available = self.pool.available_iter().len();
+        if bundles.len() > available {
+            panic!("Batch of {} entities doesn't fit into {} free entities", bundles.len(), available);
+        }
+        B::reserve(&mut self.cm, bundles.len());
+
+        let mut spawned = Vec::with_capacity(bundles.len());
+        for bundle in bundles {
+            let e = self.pool.take();
+            self.record(Change::spawn(e));
+            let added = bundle.component_types();
+            bundle.add_to(e, &mut self.cm);
+            for id in added {
+                self.record(Change::add(e, id));
+            }
+            spawned.push(e);
+        }
+
+        self.update_systems_batch(&spawned);
+        self.run_observers();
+        spawned
     }
 
+    // Descendants are despawned together with the entity, as are sources of relations with
+    // Cleanup::DespawnSource pointing to any of them
     pub fn entity_back(&mut self, e: Entity) {
-        self.pool.back(e)
+        if !self.pool.is_taken(e) {
+            return;
+        }
+        self.om.hold();
+        let mut queue = vec![e];
+        while let Some(root) = queue.pop() {
+            if !self.pool.is_taken(root) {
+                continue; // Already despawned through other path
+            }
+
+            self.detach(root);
+            let despawned: Vec<Entity> = DepthFirst::new(&self.cm, root).collect();
+            for e in despawned {
+                let mut unlinked = Vec::new();
+                queue.extend(self.relations.remove_entity(e, &mut unlinked));
+                self.record_unlinked(unlinked);
+                self.tags.remove_entity(e);
+                for id in self.cm.remove_all(e) {
+                    self.record(Change::remove(e, id));
+                }
+                self.sm.remove_entity(e);
+                self.pool.back(e);
+                self.record(Change::despawn(e));
+            }
+        }
+        self.om.release();
+        self.run_observers();
     }
 
+    // Ascending order
     pub fn entities_iter(&self) -> Iter<'_, Entity> {
         self.pool.taken_iter()
     }
 
-    // Components
-    pub fn register_component<T: Any>(&mut self) {
-        self.cm.register::<T>();
+    pub fn set_entity_reuse(&mut self, reuse: Reuse) {
+        self.pool.set_reuse(reuse);
     }
 
-    pub fn add_component<T: Any>(&mut self, e: Entity, c: T) {
-        self.cm.add(e, c);
-        let component_types_for_entity = self.cm.get_component_types(e);
-        self.sm.add_component(e, &component_types_for_entity);
+    // Despawns everything, ids then start over as in a fresh coordinator. Generations don't,
+    // handles from before the reset resolve to nothing.
+    pub fn reset_entities(&mut self) {
+        let entities: Vec<Entity> = self.pool.taken_iter().copied().collect();
+        for e in entities {
+            if self.pool.is_taken(e) {
+                self.entity_back(e);
+            }
+        }
+        self.pool.reset();
     }
 
-    pub fn get<T: Any>(&mut self, e: &Entity) -> Option<&T> {
-        self.cm.get(e)
+    // Disabled entity leaves every system but keeps its components. Entities which are not
+    // alive are left alone.
+    pub fn set_enabled(&mut self, e: Entity, enabled: bool) {
+        if !self.pool.is_taken(e) {
+            return;
+        }
+        match (enabled, self.is_enabled(e)) {
+            (true, false) => { self.remove_component::<Disabled>(e); },
+            (false, true) => self.add_component(e, Disabled),
+            _ => {},
+        }
     }
 
-    pub fn get_mut<T: Any>(&mut self, e: &Entity) -> Option<&mut T> {
-        self.cm.get_mut(e)
+    pub fn is_enabled(&self, e: Entity) -> bool {
+        self.cm.get_any(e, ComponentType::of::<Disabled>()).is_none()
     }
 
-    // Systems
-    pub fn register_system<T: System + Any>(&mut self, s: Rc<RefCell<T>>) {
-        self.sm.register(s);
+    // Handle for storing inside components instead of plain Entity, None if e is not alive
+    pub fn entity_ref(&self, e: Entity) -> Option<EntityRef> {
+        self.pool.entity_ref(e)
     }
 
-    pub fn apply_all(&mut self) { // TODO: change name to just 'apply'
-        let updates = self.sm.apply_all(&mut self.cm);
-        for update in updates {
-            update(self);
+    // None once the referenced entity is despawned, even if its id was taken again
+    pub fn resolve(&self, handle: EntityRef) -> Option<Entity> {
+        self.pool.resolve(handle)
+    }
+
+    pub fn set_clone_policy(&mut self, policy: ClonePolicy) {
+        self.clone_policy = policy;
+    }
+
+    // Copy gets every component with a clone function, Name included, and all tags, and becomes
+    // a sibling of e. Children and relations are not copied. With ClonePolicy::Error nothing is
+    // spawned if any component can't be cloned.
+    pub fn clone_entity(&mut self, e: Entity) -> Result<Entity, CloneError> {
+        if !self.pool.is_taken(e) {
+            return Err(CloneError::NotAlive(e));
+        }
+        if self.pool.available_iter().len() == 0 {
+            return Err(CloneError::OutOfEntities { needed: 1, available: 0 });
+        }
+        let skipped = [ComponentType::of::<Parent>(), ComponentType::of::<Children>()];
+        let registry = self.cm.registry();
+        let mut components = Vec::new();
+        for id in self.cm.get_component_types(e) {
+            if skipped.contains(&id) {
+                continue;
+            }
+            match registry.clone_value(id, self.cm.get_any(e, id).unwrap()) {
+                Some(value) => components.push((id, value)),
+                None if self.clone_policy == ClonePolicy::Skip => {},
+                None => {
+                    let name = registry.name_of(id).unwrap_or_default();
+                    return Err(CloneError::Component(String::from(name)));
+                },
+            }
+        }
+
+        let copy = self.pool.take();
+        self.om.hold();
+        self.record(Change::spawn(copy));
+        self.spawn_instance(copy, Instance { components, children: Vec::new() });
+        if let Some(parent) = self.parent(e) {
+            self.attach(copy, parent);
         }
+        self.tags.copy_entity(e, copy);
+        self.om.release();
+        self.run_observers();
+        Ok(copy)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::ComponentType;
-    use super::*;
+    // Prefabs
+    pub fn instantiate(&mut self, prefab: &Prefab) -> Result<Entity, CloneError> {
+        self.instantiate_with(prefab, ())
+    }
 
-    use std::collections::HashSet;
+    // Overrides replace prefab values of the same type on the root entity. Nothing is spawned
+    // if any prefab value can't be cloned or the tree doesn't fit into free entities.
+    pub fn instantiate_with<B: Bundle>(&mut self, prefab: &Prefab, overrides: B) -> Result<Entity, CloneError> {
+        let mut instance = prefab.clone_values(self.cm.registry())?;
+        let available = self.pool.available_iter().len();
+        if instance.count() > available {
+            return Err(CloneError::OutOfEntities { needed: instance.count(), available });
+        }
+        let overridden = overrides.component_types();
+        instance.components.retain(|(id, _)| !overridden.contains(id));
 
-    struct SimpleSystem{
-        entities: HashSet<Entity>,
-        component_types: HashSet<ComponentType>,
+        let e = self.pool.take();
+        self.om.hold();
+        self.record(Change::spawn(e));
+        overrides.add_to(e, &mut self.cm);
+        for id in overridden {
+            self.record(Change::add(e, id));
+        }
+        self.spawn_instance(e, instance);
+        self.om.release();
+        self.run_observers();
+        Ok(e)
     }
 
-    impl SimpleSystem {
-        fn new() -> SimpleSystem {
-            SimpleSystem {
-                entities: HashSet::new(),
-                component_types: vec![ComponentType::of::<u32>()].into_iter().collect(),
+    // One prefab per scene entity
+    #[cfg(feature = "scene")]
+    pub fn load_prefabs(&mut self, text: &str) -> Result<Vec<Prefab>, SceneError> {
+        let mut prefabs = Vec::new();
+        for parsed in scene::parse(text)? {
+            let mut prefab = Prefab::new();
+            for component in parsed.iter() {
+                let (id, value) = self.scene.build(component)?;
+                if !self.cm.is_registered(id) {
+                    return Err(SceneError {
+                        line: component.line,
+                        column: component.column,
+                        message: format!("component '{}' is not registered", component.name),
+                    });
+                }
+                prefab = prefab.with_boxed(id, value);
             }
+            prefabs.push(prefab);
         }
+        Ok(prefabs)
     }
 
-    impl System for SimpleSystem {
-        fn add(&mut self, e: Entity) {
-            self.entities.insert(e);
+    // Hierarchy
+    // Moves the child under a new parent, nothing changes if that would make a cycle.
+    // Observers run once both sides are updated.
+    pub fn set_parent(&mut self, child: Entity, parent: Entity) -> Result<(), HierarchyError> {
+        if let Some(e) = [child, parent].into_iter().find(|e| !self.pool.is_taken(*e)) {
+            return Err(HierarchyError::NotAlive(e));
         }
-
-        fn remove(&mut self, e: Entity) {
-            self.entities.remove(&e);
+        if child == parent || self.ancestors(parent).any(|a| a == child) {
+            return Err(HierarchyError::Cycle { child, parent });
         }
+        self.om.hold();
+        self.attach(child, parent);
+        self.om.release();
+        self.run_observers();
+        Ok(())
+    }
 
-        fn get_component_types(&self) -> &HashSet<ComponentType> {
-            &self.component_types
+    pub fn remove_parent(&mut self, child: Entity) {
+        self.om.hold();
+        if self.detach(child) {
+            self.remove_component::<Parent>(child);
         }
+        self.om.release();
+        self.run_observers();
+    }
 
-        fn apply(&mut self, cm: &mut ComponentManager) -> Box<dyn Fn(&mut Coordinator)> {
-            for e in self.entities.iter() {
-                let v = cm.get_mut::<u32>(e).unwrap();
-                *v += 1;
-            }
-            Box::new(| coordinator: &mut Coordinator | {
-                coordinator.add_component(50, 100u32); 
-            })
-        }
+    pub fn parent(&self, e: Entity) -> Option<Entity> {
+        hierarchy::parent_of(&self.cm, e)
     }
 
-    #[test]
-    fn test_system_returning_closure() { // TODO: returned closures are not externally accesible,
-                                         // maybe whole update should be appliead just by executing
-                                         // closure after system.apply is done?
-        let mut c = Coordinator::new();
+    pub fn children(&self, e: Entity) -> &[Entity] {
+        hierarchy::children_of(&self.cm, e)
+    }
 
-        let s = Rc::new(RefCell::new(SimpleSystem::new())); // TODO: rename SimpleSystem to just TestSystem
-        c.register_system(s.clone());
+    pub fn ancestors(&self, e: Entity) -> Ancestors<'_> {
+        Ancestors::new(&self.cm, e)
+    }
 
-        c.register_component::<u32>();
-        let e1: u32 = c.entity_take();
-        let v1: u32 = 1;
-        c.add_component(e1, v1);
+    pub fn descendants(&self, e: Entity) -> impl Iterator<Item = Entity> + '_ {
+        DepthFirst::new(&self.cm, e).skip(1)
+    }
 
-        // Our system is expected to:
-        // 1) instanlty update e1's v1 comonent
-        // 2) create closure, which after execution
-        //   will cause addition of new entity with u32 component
-        c.apply_all();
+    pub fn depth_first(&self, root: Entity) -> DepthFirst<'_> {
+        DepthFirst::new(&self.cm, root)
+    }
 
-        let expected_e2: u32 = 50;
-        let expected_v2: u32 = 100;
+    // Indexes
+    pub fn register_index<T: Indexed>(&mut self, kind: IndexKind) {
+        self.cm.register_index::<T>(kind);
+    }
 
-        // Check 1). e1:c1 updated, no additional entity in the ECS
-        assert_eq!(Some(&(v1+1)), c.get::<u32>(&e1));
-        assert_eq!(Some(&expected_v2), c.get::<u32>(&expected_e2));
+    // Disabled entities are skipped
+    pub fn lookup<T: Indexed>(&mut self, key: &T::Key) -> Vec<Entity> {
+        let mut found = self.cm.lookup::<T>(key);
+        found.retain(|e| self.is_enabled(*e));
+        found
     }
 
-    #[test]
-    fn test_coordinator_for_simple_component() {
-        let mut c = Coordinator::new();
+    // Needs IndexKind::Ordered, disabled entities are skipped
+    pub fn range<T: Indexed, R: RangeBounds<T::Key>>(&mut self, range: R) -> Vec<Entity> {
+        let mut found = self.cm.range::<T, R>(range);
+        found.retain(|e| self.is_enabled(*e));
+        found
+    }
 
-        let s = Rc::new(RefCell::new(SimpleSystem::new()));
-        c.register_system(s); // TODO: this test should work also if system is
-                                                   // registered after componets are added (see
-                                                   // TODO: below)
+    // Grid over the location of T, cell size should be about the typical query radius
+    pub fn register_spatial_index<T: Located>(&mut self, cell_size: f32) {
+        self.cm.register_spatial_index::<T>(cell_size);
+    }
 
-        let e1 = c.entity_take();
-        let e2 = c.entity_take();
+    pub fn within_radius<T: Located>(&mut self, center: [f32; 2], radius: f32) -> Vec<Entity> {
+        let mut found = self.cm.spatial::<T>().within_radius(center, radius);
+        found.retain(|e| self.is_enabled(*e));
+        found
+    }
 
-        c.register_component::<u32>();
-        let v1: u32 = 1;
-        let v2: u32 = 1;
-        c.add_component(e1, v1);
-        c.add_component(e2, v2);
+    pub fn within_aabb<T: Located>(&mut self, min: [f32; 2], max: [f32; 2]) -> Vec<Entity> {
+        let mut found = self.cm.spatial::<T>().within_aabb(min, max);
+        found.retain(|e| self.is_enabled(*e));
+        found
+    }
 
-        //c.register_system(s); // TODO: this test should work also if system is
-        //                                           // registered after componets are added (see
-        //                                           // TODO: above)
+    // Asks the grid for more entities while disabled ones leave fewer than k
+    pub fn nearest<T: Located>(&mut self, point: [f32; 2], k: usize) -> Vec<Entity> {
+        let mut wanted = k;
+        loop {
+            let grid = self.cm.spatial::<T>();
+            let total = grid.len();
+            let mut found = grid.nearest(point, wanted);
+            found.retain(|e| self.is_enabled(*e));
+            if found.len() >= k || wanted >= total {
+                found.truncate(k);
+                return found;
+            }
+            wanted = wanted.saturating_mul(2);
+        }
+    }
 
-        c.apply_all();
+    // Names and tags, disabled entities are skipped in lookups
+    // Lowest entity with the Name if it is not unique
+    pub fn find_by_name(&mut self, name: &str) -> Option<Entity> {
+        self.find_all_by_name(name).first().copied()
+    }
 
-        assert_eq!(Some(&(v1+1)), c.get::<u32>(&e1));
-        assert_eq!(Some(&(v2+1)), c.get::<u32>(&e2));
+    // Ascending order
+    pub fn find_all_by_name(&mut self, name: &str) -> Vec<Entity> {
+        self.lookup::<Name>(&String::from(name))
     }
 
-    #[derive(Debug, PartialEq)]
-    struct Position { x: i32, y: i32, }
-    struct Velocity { vx: i32, vy: i32, }
+    // Tags of this type can be loaded from snapshots and deltas, those carry tags of types
+    // with a serializer (see register_serializer)
+    pub fn register_tag<T: Hash + Eq + Clone + Any>(&mut self) {
+        self.tags.register::<T>();
+    }
 
-    struct ComplexSystem{
-        entities: HashSet<Entity>,
-        component_types: HashSet<ComponentType>,
+    pub fn add_tag<T: Hash + Eq + Clone + Any>(&mut self, e: Entity, tag: T) {
+        self.tags.add(e, tag);
     }
 
-    impl ComplexSystem {
-        fn new() -> ComplexSystem {
-            ComplexSystem {
-                entities: HashSet::new(),
-                component_types: vec![
-                    ComponentType::of::<Position>(),
-                    ComponentType::of::<Velocity>(),
-                ].into_iter().collect(),
-            }
+    pub fn remove_tag<T: Hash + Eq + Clone + Any>(&mut self, e: Entity, tag: &T) -> bool {
+        self.tags.remove(e, tag)
+    }
+
+    pub fn has_tag<T: Hash + Eq + Clone + Any>(&self, e: Entity, tag: &T) -> bool {
+        self.tags.has(e, tag)
+    }
+
+    // Ascending order
+    pub fn tagged<T: Hash + Eq + Clone + Any>(&self, tag: &T) -> Vec<Entity> {
+        let mut found: Vec<Entity> = self.tags.tagged(tag).iter()
+            .copied()
+            .filter(|e| self.is_enabled(*e))
+            .collect();
+        found.sort_unstable();
+        found
+    }
+
+    // Relations
+    pub fn register_relation<R: Any>(&mut self, cleanup: Cleanup) {
+        self.relations.register::<R>(cleanup);
+    }
+
+    // Name is what identifies the relation in snapshots, deltas and state hashes
+    pub fn register_relation_named<R: Any>(&mut self, name: &str, cleanup: Cleanup) {
+        self.relations.register_named::<R>(name, cleanup);
+    }
+
+    // False if either entity doesn't exist or the pair is there already
+    pub fn relate<R: Any>(&mut self, source: Entity, target: Entity) -> bool {
+        if !self.pool.is_taken(source) || !self.pool.is_taken(target) {
+            return false;
+        }
+        if !self.relations.add::<R>(source, target) {
+            return false;
         }
+        self.record(Change::relate(source, TypeId::of::<R>(), target));
+        true
     }
 
-    impl System for ComplexSystem {
-        fn add(&mut self, e: Entity) {
-            self.entities.insert(e);
+    pub fn unrelate<R: Any>(&mut self, source: Entity, target: Entity) -> bool {
+        if !self.relations.remove::<R>(source, target) {
+            return false;
         }
+        self.record(Change::unrelate(source, TypeId::of::<R>(), target));
+        true
+    }
 
-        fn remove(&mut self, e: Entity) {
-            self.entities.remove(&e);
+    pub fn has_relation<R: Any>(&self, source: Entity, target: Entity) -> bool {
+        self.relations.contains::<R>(source, target)
+    }
+
+    // What source R's, e.g. what does X Like
+    pub fn targets<R: Any>(&self, source: Entity) -> impl Iterator<Item = Entity> + '_ {
+        self.relations.targets::<R>(source)
+    }
+
+    // Who R's target, e.g. all entities that Like X
+    pub fn sources<R: Any>(&self, target: Entity) -> impl Iterator<Item = Entity> + '_ {
+        self.relations.sources::<R>(target)
+    }
+
+    // Components
+    pub fn register_component<T: Any>(&mut self) {
+        self.cm.register::<T>();
+    }
+
+    pub fn register_component_named<T: Any>(&mut self, name: &str) {
+        self.cm.register_named::<T>(name);
+    }
+
+    pub fn registry(&self) -> &ComponentRegistry {
+        self.cm.registry()
+    }
+
+    pub fn registry_mut(&mut self) -> &mut ComponentRegistry {
+        self.cm.registry_mut()
+    }
+
+    // Replacing a value the entity already has is not a change
+    pub fn add_component<T: Any>(&mut self, e: Entity, c: T) {
+        let replaced = self.has_type(e, ComponentType::of::<T>());
+        self.cm.add(e, c);
+        self.update_systems(e);
+        if !replaced {
+            self.record(Change::add(e, ComponentType::of::<T>()));
         }
+        self.run_observers();
+    }
 
-        fn get_component_types(&self) -> &HashSet<ComponentType> {
-            &self.component_types
+    pub fn add_bundle<B: Bundle>(&mut self, e: Entity, bundle: B) {
+        let added: Vec<ComponentType> = bundle.component_types().into_iter()
+            .filter(|id| !self.has_type(e, *id))
+            .collect();
+        bundle.add_to(e, &mut self.cm);
+        self.update_systems(e);
+        for id in added {
+            self.record(Change::add(e, id));
         }
+        self.run_observers();
+    }
 
-        fn apply(&mut self, cm: &mut ComponentManager)
-            -> Box<dyn Fn(&mut Coordinator)> {
+    pub fn insert_batch<T, I>(&mut self, components: I)
+    where
+        T: Any,
+        I: IntoIterator<Item = (Entity, T)>,
+    {
+        let components = components.into_iter();
+        self.cm.reserve::<T>(components.size_hint().0);
 
-            for e in self.entities.iter() {
-                let position = cm.get::<Position>(e).unwrap();  // TODO: get_mut, and modify
-                                                                // without copying
-                let (x, y) = (position.x, position.y);
-                let velocity = cm.get::<Velocity>(e).unwrap();
-                let new_pos = Position { x: x + velocity.vx, y: y + velocity.vy };
-                cm.add(*e, new_pos);
+        let mut entities = Vec::with_capacity(components.size_hint().0);
+        for (e, c) in components {
+            let replaced = self.has_type(e, ComponentType::of::<T>());
+            self.cm.add(e, c);
+            if !replaced {
+                self.record(Change::add(e, ComponentType::of::<T>()));
             }
+            entities.push(e);
+        }
 
-            Box::new(| _coordinator: &mut Coordinator | {
-            })
+        self.update_systems_batch(&entities);
+        self.run_observers();
+    }
+
+    pub fn remove_component<T: Any>(&mut self, e: Entity) -> Option<T> {
+        let removed = self.cm.remove::<T>(&e)?;
+        self.update_systems(e);
+        self.record(Change::remove(e, ComponentType::of::<T>()));
+        self.run_observers();
+        Some(removed)
+    }
+
+    pub fn get<T: Any>(&mut self, e: &Entity) -> Option<&T> {
+        self.cm.get(e)
+    }
+
+    pub fn get_mut<T: Any>(&mut self, e: &Entity) -> Option<&mut T> {
+        self.cm.get_mut(e)
+    }
+
+    // Systems
+    pub fn register_system<T: System + Any>(&mut self, s: Rc<RefCell<T>>) {
+        self.sm.register(s);
+    }
+
+    pub fn system_entities<T: System + Any>(&self) -> &[Entity] {
+        self.sm.entities::<T>()
+    }
+
+    pub fn apply_all(&mut self) { // TODO: change name to just 'apply'
+        let loan = self.cm.reserver().clone().lend_from(&mut self.pool);
+        let updates = self.sm.apply_all(&mut self.cm);
+        let reserved = loan.finish();
+
+        for e in reserved {
+            self.record(Change::spawn(e));
+        }
+        self.run_observers();
+        for update in updates {
+            self.om.hold();
+            update(self);
+            self.om.release();
+            self.run_observers();
         }
     }
 
-    #[test]
-    fn test_coordinator_for_complex_two_componets() {
-        let mut c = Coordinator::new();
+    // Globals
+    pub fn globals(&self) -> &Globals {
+        &self.globals
+    }
 
-        let s = Rc::new(RefCell::new(ComplexSystem::new()));
-        c.register_system(s);
+    pub fn globals_mut(&mut self) -> &mut Globals {
+        &mut self.globals
+    }
 
-        let e1 = c.entity_take();
-        let e2 = c.entity_take();
+    // Observers
+    pub fn register_observer<T: Observer + Any>(&mut self, o: Rc<RefCell<T>>) {
+        self.om.register(o);
+    }
 
-        c.register_component::<Position>();
-        c.register_component::<Velocity>();
+    // Structural changes
+    pub fn subscribe_changes(&mut self) -> SubscriberId {
+        self.changes.subscribe()
+    }
 
-        let pos1 = Position { x: 1, y: 2 };
-        let vel1 = Velocity { vx: 1, vy: 2 };
-        c.add_component(e1, pos1);
-        c.add_component(e1, vel1);
-        let pos2 = Position { x: 1, y: 2 };
-        let vel2 = Velocity { vx: -1, vy: -2 };
-        c.add_component(e2, pos2);
-        c.add_component(e2, vel2);
+    pub fn unsubscribe_changes(&mut self, id: SubscriberId) {
+        self.changes.unsubscribe(id)
+    }
 
-        c.apply_all();
+    pub fn drain_changes(&mut self, id: SubscriberId) -> Vec<Change> {
+        self.changes.drain(id)
+    }
 
-        let expected_pos1 = &Position { x: 2, y: 4 };
-        assert_eq!(expected_pos1, c.get::<Position>(&e1).unwrap());
+    // Rollback
+    // Number of saved ticks kept, 0 (the default) disables saving
+    pub fn set_rollback_capacity(&mut self, capacity: usize) {
+        self.rollback.set_capacity(capacity);
+    }
 
-        let expected_pos2 = &mut Position { x: 0, y: 0 };
-        assert_eq!(expected_pos2, c.get::<Position>(&e2).unwrap());
+    // Component arrays not modified since the previous saved tick are shared with it, so each
+    // tick only costs the arrays which changed. Every saved component and global needs a clone
+    // function in the registry. Nothing is saved with rollback capacity 0 (the default), the
+    // result is None then.
+    pub fn save_tick(&mut self) -> Result<Option<Tick>, CloneError> {
+        if self.rollback.capacity() == 0 {
+            return Ok(None);
+        }
+        let previous = self.rollback.latest().map(|saved| &saved.components);
+        let components = self.cm.checkpoint(previous)?;
+        let globals = self.globals.try_clone(self.cm.registry())?;
+        Ok(self.rollback.push(self.pool.clone(), components, globals, self.relations.clone(), self.tags.clone()))
     }
 
-}
+    // Restores the world as it was when the tick was saved and drops all later ticks.
+    // Structural differences to the current world are logged as changes, entity whose id was
+    // reused meanwhile counts as despawned and spawned again. Observers run once the whole
+    // world is restored.
+    pub fn rollback_to(&mut self, tick: Tick) -> Result<(), RollbackError> {
+        let before: HashMap<Entity, (EntityRef, HashSet<ComponentType>)> = self.pool.taken_iter()
+            .map(|e| (*e, (self.pool.entity_ref(*e).unwrap(), self.cm.get_component_types(*e))))
+            .collect();
+        let pairs_before = self.relation_pairs();
+
+        let saved = self.rollback.rewind(tick)?;
+        self.pool = saved.pool.clone();
+        self.cm.restore(&saved.components);
+        self.globals = saved.globals.try_clone(self.cm.registry())
+            .expect("Saved globals are cloneable");
+        self.relations = saved.relations.clone();
+        self.tags = saved.tags.clone();
 
+        self.om.hold();
+        let pairs_after = self.relation_pairs();
+        self.record_unlinked(pairs_before.difference(&pairs_after).copied().collect());
+        let mut touched: Vec<Entity> = before.keys().copied().collect();
+        touched.extend(self.pool.taken_iter().copied());
+        touched.sort_unstable();
+        touched.dedup();
+        let mut added: Vec<(Entity, HashSet<ComponentType>)> = Vec::new();
+        for e in touched.iter() {
+            let now = match self.pool.is_taken(*e) {
+                true => self.cm.get_component_types(*e),
+                false => HashSet::new(),
+            };
+            match before.get(e) {
+                Some((handle, types)) if self.pool.resolve(*handle).is_some() => {
+                    self.record_types(*e, types.difference(&now), Change::remove);
+                    added.push((*e, now.difference(types).copied().collect()));
+                },
+                Some((_, types)) => {
+                    self.record_types(*e, types.iter(), Change::remove);
+                    self.record(Change::despawn(*e));
+                    if self.pool.is_taken(*e) {
+                        added.push((*e, now));
+                    }
+                },
+                None => added.push((*e, now)),
+            }
+        }
+        // Removals and despawns go first, so a reused id is despawned before it is spawned
+        for (e, types) in added {
+            if before.get(&e).is_none_or(|(handle, _)| self.pool.resolve(*handle).is_none()) {
+                self.record(Change::spawn(e));
+            }
+            self.record_types(e, types.iter(), Change::add);
+        }
+        self.record_linked(pairs_after.difference(&pairs_before).copied().collect());
+
+        // Only entities whose components differ join or leave systems
+        self.update_systems_batch(&touched);
+        self.om.release();
+        self.run_observers();
+        Ok(())
+    }
+
+    // Checksum for comparing worlds across peers, see ComponentRegistry::set_hash
+    pub fn state_hash(&self) -> StateHash {
+        hash::hash_world(&self.pool, &self.cm, &self.relations, &self.tags)
+    }
 
+    // Snapshots
+    // Name is what identifies the type in saved files, so it should not change between versions
+    #[cfg(feature = "snapshot")]
+    pub fn register_serializer<T: Persist + Any>(&mut self, name: &str) {
+        self.cm.rename::<T>(name);
+        self.cm.registry_mut().set_serializer::<T>();
+    }
+
+    #[cfg(feature = "snapshot")]
+    pub fn save_snapshot(&self) -> Vec<u8> {
+        snapshot::encode(&self.pool, &self.cm, &self.relations, &self.tags, &self.globals)
+    }
+
+    // Replaces all entities and components with the snapshot content, globals stored in the
+    // snapshot overwrite current ones. World is left untouched if the snapshot can't be read.
+    // Logged as despawn of every current entity followed by spawn of every loaded one, observers
+    // run once the whole snapshot is in place.
+    #[cfg(feature = "snapshot")]
+    pub fn load_snapshot(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let decoded = snapshot::decode(bytes, self.cm.registry())?;
+        let mut pool = self.pool.clone();
+        if !pool.restore(decoded.available, decoded.taken, decoded.generations) {
+            return Err(SnapshotError::InvalidData(String::from("inconsistent entity pool")));
+        }
+        for (e, id, _) in decoded.components.iter() {
+            if !self.cm.is_registered(*id) {
+                let name = self.cm.registry().name_of(*id).unwrap_or_default();
+                return Err(SnapshotError::UnknownComponent(String::from(name)));
+            }
+            if !pool.is_taken(*e) {
+                return Err(SnapshotError::InvalidData(format!("entity {} does not exist", e)));
+            }
+        }
+        let pairs = self.resolve_pairs(decoded.relations, |e| pool.is_taken(e))?;
+        self.check_tags(&decoded.tags, |e| pool.is_taken(e))?;
+
+        self.om.hold();
+        self.record_unlinked(self.relation_pairs().into_iter().collect());
+        let current: Vec<Entity> = self.pool.taken_iter().copied().collect();
+        for e in current {
+            for id in self.cm.remove_all(e) {
+                self.record(Change::remove(e, id));
+            }
+            self.sm.remove_entity(e);
+            self.record(Change::despawn(e));
+        }
+        self.cm.clear();
+        self.relations.clear();
+        self.tags.clear();
+
+        self.pool = pool;
+        let entities: Vec<Entity> = self.pool.taken_iter().copied().collect();
+        for e in entities.iter() {
+            self.record(Change::spawn(*e));
+        }
+        for (e, id, value) in decoded.components {
+            self.cm.add_any(e, id, value);
+            self.record(Change::add(e, id));
+        }
+        for (relation, source, target) in pairs.iter() {
+            self.relations.add_pair(*relation, *source, *target);
+        }
+        self.record_linked(pairs);
+        for (e, id, tag) in decoded.tags {
+            self.tags.add_any(id, e, tag);
+        }
+        for (key, value) in decoded.globals {
+            self.globals.add_boxed(&key, value);
+        }
+
+        self.update_systems_batch(&entities);
+        self.om.release();
+        self.run_observers();
+        Ok(())
+    }
+
+    // Replication
+    // Sender keeps the last state the receiver has and sends it `acked.diff(&current)`, where
+    // current is kept up to date with refresh_state
+    #[cfg(feature = "snapshot")]
+    pub fn capture_state(&self) -> WorldState {
+        WorldState::capture(&self.pool, &self.cm, &self.relations, &self.tags)
+    }
+
+    // Only component arrays modified since the previous refresh are serialized again
+    #[cfg(feature = "snapshot")]
+    pub fn refresh_state(&self, state: &mut WorldState) {
+        state.refresh(&self.pool, &self.cm, &self.relations, &self.tags);
+    }
+
+    // Applies bytes from WorldState::diff. Structural changes are logged and trigger observers
+    // as if they were made locally. World is left untouched if the delta doesn't fit it.
+    #[cfg(feature = "snapshot")]
+    pub fn apply_delta(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let decoded = delta::decode(bytes, self.cm.registry())?;
+
+        let despawned: HashSet<Entity> = decoded.despawned.iter().copied().collect();
+        let spawned: HashSet<Entity> = decoded.spawned.iter().copied().collect();
+        let exists = |e: &Entity| (self.pool.is_taken(*e) && !despawned.contains(e)) || spawned.contains(e);
+        let invalid = |what: String| Err(SnapshotError::InvalidData(what));
+        if let Some(e) = decoded.despawned.iter().find(|e| !self.pool.is_taken(**e)) {
+            return invalid(format!("despawned entity {} does not exist", e));
+        }
+        if let Some(e) = decoded.spawned.iter().find(|e| !self.pool.is_available(**e)) {
+            return invalid(format!("spawned entity {} is not available", e));
+        }
+        if spawned.len() != decoded.spawned.len() {
+            return invalid(String::from("entity spawned twice"));
+        }
+        let available: Vec<Entity> = self.pool.available_iter().copied().collect();
+        let free = decoded.available.unwrap_or_else(|| delta::free_after(&available, &decoded.spawned, &decoded.despawned));
+        let expected: HashSet<Entity> = available.iter()
+            .filter(|e| !spawned.contains(e))
+            .chain(decoded.despawned.iter())
+            .copied()
+            .collect();
+        if free.len() != expected.len() || free.iter().collect::<HashSet<&Entity>>() != expected.iter().collect() {
+            return invalid(String::from("free ids don't match spawned and despawned entities"));
+        }
+        let values = decoded.added.iter().chain(decoded.changed.iter()).map(|(e, id, _)| (e, id));
+        for (e, id) in values.chain(decoded.removed.iter().map(|(e, id)| (e, id))) {
+            if !self.cm.is_registered(*id) {
+                let name = self.cm.registry().name_of(*id).unwrap_or_default();
+                return Err(SnapshotError::UnknownComponent(String::from(name)));
+            }
+            if !exists(e) {
+                return invalid(format!("entity {} does not exist", e));
+            }
+        }
+        let related = self.resolve_pairs(decoded.related, |e| exists(&e))?;
+        let unrelated = self.resolve_pairs(decoded.unrelated, |e| exists(&e))?;
+        if let Some((_, source, target)) = related.iter().find(|(id, s, t)| self.relations.contains_pair(*id, *s, *t)) {
+            return invalid(format!("entities {} and {} are related already", source, target));
+        }
+        if let Some((_, source, target)) = unrelated.iter().find(|(id, s, t)| !self.relations.contains_pair(*id, *s, *t)) {
+            return invalid(format!("entities {} and {} are not related", source, target));
+        }
+        self.check_tags(&decoded.tagged, |e| exists(&e))?;
+        self.check_tags(&decoded.untagged, |e| exists(&e))?;
+        if let Some((e, _, _)) = decoded.untagged.iter().find(|(e, id, tag)| !self.tags.contains_any(*id, *e, tag.as_ref())) {
+            return invalid(format!("entity {} doesn't have the removed tag", e));
+        }
+
+        self.om.hold();
+        for e in decoded.despawned {
+            let mut unlinked = Vec::new();
+            self.relations.remove_entity(e, &mut unlinked); // Dependent despawns come with the delta
+            self.record_unlinked(unlinked);
+            self.tags.remove_entity(e);
+            for id in self.cm.remove_all(e) {
+                self.record(Change::remove(e, id));
+            }
+            self.sm.remove_entity(e);
+            self.pool.back(e);
+            self.record(Change::despawn(e));
+        }
+        self.pool.take_entities(&decoded.spawned);
+        self.pool.reorder_available(free);
+        for e in decoded.spawned.iter() {
+            self.record(Change::spawn(*e));
+        }
+
+        let mut touched: Vec<Entity> = decoded.spawned;
+        for (e, id) in decoded.removed {
+            self.cm.remove_any(e, id);
+            self.record(Change::remove(e, id));
+            touched.push(e);
+        }
+        for (e, id, value) in decoded.added {
+            self.cm.add_any(e, id, value);
+            self.record(Change::add(e, id));
+            touched.push(e);
+        }
+        for (e, id, value) in decoded.changed {
+            self.cm.add_any(e, id, value);
+        }
+        for (relation, source, target) in unrelated.iter() {
+            self.relations.remove_pair(*relation, *source, *target);
+        }
+        self.record_unlinked(unrelated);
+        for (relation, source, target) in related.iter() {
+            self.relations.add_pair(*relation, *source, *target);
+        }
+        self.record_linked(related);
+        for (e, id, tag) in decoded.untagged {
+            self.tags.remove_any(id, e, tag.as_ref());
+        }
+        for (e, id, tag) in decoded.tagged {
+            self.tags.add_any(id, e, tag);
+        }
+
+        touched.sort_unstable();
+        touched.dedup();
+        self.update_systems_batch(&touched);
+        self.om.release();
+        self.run_observers();
+        Ok(())
+    }
+
+    // Scenes
+    #[cfg(feature = "scene")]
+    pub fn register_scene_component<T: SceneComponent + Any>(&mut self, name: &str) {
+        self.scene.register::<T>(name);
+    }
+
+    // Spawns a new entity for every scene entry. Nothing is spawned if any part of the scene is
+    // invalid.
+    #[cfg(feature = "scene")]
+    pub fn load_scene(&mut self, text: &str) -> Result<Vec<Entity>, SceneError> {
+        let mut entities = Vec::new();
+        for parsed in scene::parse(text)? {
+            let mut components = Vec::with_capacity(parsed.len());
+            for component in parsed.iter() {
+                let (id, value) = self.scene.build(component)?;
+                if !self.cm.is_registered(id) {
+                    return Err(SceneError {
+                        line: component.line,
+                        column: component.column,
+                        message: format!("component '{}' is not registered", component.name),
+                    });
+                }
+                components.push((id, value));
+            }
+            entities.push(components);
+        }
+        let available = self.pool.available_iter().len();
+        if entities.len() > available {
+            return Err(SceneError {
+                line: 1,
+                column: 1,
+                message: format!("scene of {} entities doesn't fit into {} free entities", entities.len(), available),
+            });
+        }
+
+        let mut spawned = Vec::with_capacity(entities.len());
+        for components in entities {
+            let e = self.pool.take();
+            self.record(Change::spawn(e));
+            for (id, value) in components {
+                self.cm.add_any(e, id, value);
+                self.record(Change::add(e, id));
+            }
+            spawned.push(e);
+        }
+
+        self.update_systems_batch(&spawned);
+        self.run_observers();
+        Ok(spawned)
+    }
+
+    // Writes every entity with components registered for scenes, other components are skipped
+    #[cfg(feature = "scene")]
+    pub fn export_scene(&self) -> String {
+        let mut entities: Vec<Entity> = self.pool.taken_iter().copied().collect();
+        entities.sort_unstable();
+
+        let described: Vec<scene::ParsedEntity> = entities.into_iter()
+            .map(|e| {
+                let mut components: Vec<scene::ParsedComponent> = self.scene.types()
+                    .filter_map(|id| self.scene.describe(id, self.cm.get_any(e, id)?))
+                    .collect();
+                components.sort_by(|a, b| a.name.cmp(&b.name));
+                components
+            })
+            .collect();
+        scene::write(&described)
+    }
+
+    // Priv
+
+    // Entity is already taken and may have some components in place. Caller holds observers
+    // until the whole tree is built.
+    fn spawn_instance(&mut self, e: Entity, instance: Instance) {
+        for (id, value) in instance.components {
+            self.cm.add_any(e, id, value);
+            self.record(Change::add(e, id));
+        }
+        self.update_systems(e);
+
+        for child in instance.children {
+            let c = self.pool.take();
+            self.record(Change::spawn(c));
+            self.spawn_instance(c, child);
+            self.attach(c, e);
+        }
+    }
+
+    // No cycle checks, the child may have a parent already
+    fn attach(&mut self, child: Entity, parent: Entity) {
+        self.detach(child);
+        match self.cm.get_mut::<Children>(&parent) {
+            Some(children) => children.0.push(child),
+            None => self.add_component(parent, Children(vec![child])),
+        }
+        self.add_component(child, Parent(parent));
+    }
+
+    // Takes the entity out of its parent's children, Parent itself is left for the caller
+    fn detach(&mut self, child: Entity) -> bool {
+        let parent = match self.parent(child) {
+            Some(parent) => parent,
+            None => return false,
+        };
+
+        let children = self.cm.get_mut::<Children>(&parent).unwrap();
+        children.0.retain(|c| *c != child);
+        if children.0.is_empty() {
+            self.remove_component::<Children>(parent);
+        }
+        true
+    }
+
+    fn has_type(&self, e: Entity, id: ComponentType) -> bool {
+        self.cm.get_component_types_ref(e).is_some_and(|types| types.contains(&id))
+    }
+
+    fn update_systems(&mut self, e: Entity) {
+        match self.cm.get_component_types_ref(e) {
+            Some(component_types) => self.sm.update_entity(e, component_types),
+            None => self.sm.update_entity(e, &HashSet::new()),
+        }
+    }
+
+    fn update_systems_batch(&mut self, entities: &[Entity]) {
+        let empty = HashSet::new();
+        let batch: Vec<_> = entities.iter()
+            .map(|e| (*e, self.cm.get_component_types_ref(*e).unwrap_or(&empty)))
+            .collect();
+        self.sm.update_entities(&batch);
+    }
+
+    fn relation_pairs(&self) -> BTreeSet<(TypeId, Entity, Entity)> {
+        self.relations.pairs().into_iter().map(|(_, id, source, target)| (id, source, target)).collect()
+    }
+
+    // Relation names are looked up, sources have to exist. Targets may be dead, pairs pointing
+    // to despawned entities stay with Cleanup::Keep.
+    #[cfg(feature = "snapshot")]
+    fn resolve_pairs(&self, pairs: Vec<(String, Entity, Entity)>, exists: impl Fn(Entity) -> bool) -> Result<Vec<(TypeId, Entity, Entity)>, SnapshotError> {
+        let mut resolved = Vec::with_capacity(pairs.len());
+        for (name, source, target) in pairs {
+            let id = self.relations.type_of(&name).ok_or(SnapshotError::UnknownRelation(name))?;
+            if !exists(source) {
+                return Err(SnapshotError::InvalidData(format!("entity {} does not exist", source)));
+            }
+            resolved.push((id, source, target));
+        }
+        Ok(resolved)
+    }
+
+    // Tag types have to be registered and tagged entities have to exist
+    #[cfg(feature = "snapshot")]
+    fn check_tags(&self, tags: &[(Entity, ComponentType, Box<dyn Any>)], exists: impl Fn(Entity) -> bool) -> Result<(), SnapshotError> {
+        for (e, id, _) in tags {
+            if !self.tags.is_registered(*id) {
+                let name = self.cm.registry().name_of(*id).unwrap_or_default();
+                return Err(SnapshotError::InvalidData(format!("'{}' is not a registered tag type", name)));
+            }
+            if !exists(*e) {
+                return Err(SnapshotError::InvalidData(format!("entity {} does not exist", e)));
+            }
+        }
+        Ok(())
+    }
+
+    // In a stable order, like record_types
+    fn record_unlinked(&mut self, mut pairs: Vec<(TypeId, Entity, Entity)>) {
+        pairs.sort_unstable();
+        for (relation, source, target) in pairs {
+            self.record(Change::unrelate(source, relation, target));
+        }
+    }
+
+    fn record_linked(&mut self, mut pairs: Vec<(TypeId, Entity, Entity)>) {
+        pairs.sort_unstable();
+        for (relation, source, target) in pairs {
+            self.record(Change::relate(source, relation, target));
+        }
+    }
+
+    // In a stable order
+    fn record_types<'a>(&mut self, e: Entity, types: impl Iterator<Item = &'a ComponentType>, change: fn(Entity, ComponentType) -> Change) {
+        let mut types: Vec<ComponentType> = types.copied().collect();
+        types.sort_unstable();
+        for id in types {
+            self.record(change(e, id));
+        }
+    }
+
+    fn record(&mut self, change: Change) {
+        self.changes.push(change);
+        self.om.notify(&change);
+    }
+
+    fn run_observers(&mut self) {
+        while let Some((e, observer)) = self.om.next_pending() {
+            // Held while the observer runs, so its own changes are queued for this loop
+            // instead of recursing into it
+            self.om.hold();
+            observer.borrow_mut().on_trigger(e, self);
+            self.om.release();
+        }
+    }
+}
+
+impl Default for Coordinator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Trigger;
+    use crate::Signature;
+    use crate::transform::{LocalTransform, Transform, Transform2D, TransformSystem};
+
+    struct SimpleSystem{
+        signature: Signature,
+    }
+
+    impl SimpleSystem {
+        fn new() -> SimpleSystem {
+            SimpleSystem {
+                signature: Signature::new().require::<u32>(),
+            }
+        }
+    }
+
+    impl System for SimpleSystem {
+        fn get_signature(&self) -> &Signature {
+            &self.signature
+        }
+
+        fn apply(&mut self, entities: &[Entity], cm: &mut ComponentManager) -> Box<dyn Fn(&mut Coordinator)> {
+            for e in entities {
+                let v = cm.get_mut::<u32>(e).unwrap();
+                *v += 1;
+            }
+            Box::new(| coordinator: &mut Coordinator | {
+                coordinator.add_component(50, 100u32); 
+            })
+        }
+    }
+
+    #[test]
+    fn test_system_returning_closure() { // TODO: returned closures are not externally accesible,
+                                         // maybe whole update should be appliead just by executing
+                                         // closure after system.apply is done?
+        let mut c = Coordinator::new();
+
+        let s = Rc::new(RefCell::new(SimpleSystem::new())); // TODO: rename SimpleSystem to just TestSystem
+        c.register_system(s.clone());
+
+        c.register_component::<u32>();
+        let e1: u32 = c.entity_take();
+        let v1: u32 = 1;
+        c.add_component(e1, v1);
+
+        // Our system is expected to:
+        // 1) instanlty update e1's v1 comonent
+        // 2) create closure, which after execution
+        //   will cause addition of new entity with u32 component
+        c.apply_all();
+
+        let expected_e2: u32 = 50;
+        let expected_v2: u32 = 100;
+
+        // Check 1). e1:c1 updated, no additional entity in the ECS
+        assert_eq!(Some(&(v1+1)), c.get::<u32>(&e1));
+        assert_eq!(Some(&expected_v2), c.get::<u32>(&expected_e2));
+    }
+
+    #[test]
+    fn test_coordinator_for_simple_component() {
+        let mut c = Coordinator::new();
+
+        let s = Rc::new(RefCell::new(SimpleSystem::new()));
+        c.register_system(s); // TODO: this test should work also if system is
+                                                   // registered after componets are added (see
+                                                   // TODO: below)
+
+        let e1 = c.entity_take();
+        let e2 = c.entity_take();
+
+        c.register_component::<u32>();
+        let v1: u32 = 1;
+        let v2: u32 = 1;
+        c.add_component(e1, v1);
+        c.add_component(e2, v2);
+
+        //c.register_system(s); // TODO: this test should work also if system is
+        //                                           // registered after componets are added (see
+        //                                           // TODO: above)
+
+        c.apply_all();
+
+        assert_eq!(Some(&(v1+1)), c.get::<u32>(&e1));
+        assert_eq!(Some(&(v2+1)), c.get::<u32>(&e2));
+    }
+
+    #[derive(Debug, Clone, PartialEq, Hash)]
+    struct Position { x: i32, y: i32, }
+    #[derive(Clone, Hash)]
+    struct Velocity { vx: i32, vy: i32, }
+
+    struct ComplexSystem{
+        signature: Signature,
+    }
+
+    impl ComplexSystem {
+        fn new() -> ComplexSystem {
+            ComplexSystem {
+                signature: Signature::new()
+                    .require::<Position>()
+                    .require::<Velocity>(),
+            }
+        }
+    }
+
+    impl System for ComplexSystem {
+        fn get_signature(&self) -> &Signature {
+            &self.signature
+        }
+
+        fn box_clone(&self) -> Option<Rc<RefCell<dyn System>>> {
+            Some(Rc::new(RefCell::new(ComplexSystem { signature: self.signature.clone() })))
+        }
+
+        fn apply(&mut self, entities: &[Entity], cm: &mut ComponentManager)
+            -> Box<dyn Fn(&mut Coordinator)> {
+
+            for e in entities {
+                let position = cm.get::<Position>(e).unwrap();  // TODO: get_mut, and modify
+                                                                // without copying
+                let (x, y) = (position.x, position.y);
+                let velocity = cm.get::<Velocity>(e).unwrap();
+                let new_pos = Position { x: x + velocity.vx, y: y + velocity.vy };
+                cm.add(*e, new_pos);
+            }
+
+            Box::new(| _coordinator: &mut Coordinator | {
+            })
+        }
+    }
+
+    #[test]
+    fn test_coordinator_for_complex_two_componets() {
+        let mut c = Coordinator::new();
+
+        let s = Rc::new(RefCell::new(ComplexSystem::new()));
+        c.register_system(s);
+
+        let e1 = c.entity_take();
+        let e2 = c.entity_take();
+
+        c.register_component::<Position>();
+        c.register_component::<Velocity>();
+
+        let pos1 = Position { x: 1, y: 2 };
+        let vel1 = Velocity { vx: 1, vy: 2 };
+        c.add_component(e1, pos1);
+        c.add_component(e1, vel1);
+        let pos2 = Position { x: 1, y: 2 };
+        let vel2 = Velocity { vx: -1, vy: -2 };
+        c.add_component(e2, pos2);
+        c.add_component(e2, vel2);
+
+        c.apply_all();
+
+        let expected_pos1 = &Position { x: 2, y: 4 };
+        assert_eq!(expected_pos1, c.get::<Position>(&e1).unwrap());
+
+        let expected_pos2 = &mut Position { x: 0, y: 0 };
+        assert_eq!(expected_pos2, c.get::<Position>(&e2).unwrap());
+    }
+
+    #[test]
+    fn test_structural_changes_are_logged() {
+        let mut c = Coordinator::new();
+        c.register_component::<u32>();
+
+        let early = c.entity_take(); // Not observed, nobody subscribed yet
+        let id = c.subscribe_changes();
+
+        let e = c.entity_take();
+        c.add_component(e, 1u32);
+        assert_eq!(Some(1u32), c.remove_component::<u32>(e));
+        assert_eq!(None, c.remove_component::<u32>(e), "Removing missing component is not a change");
+        c.add_component(e, 2u32);
+        c.add_component(e, 3u32); // Replaced value, not a change
+        c.add_bundle(e, (4u32,));
+        c.insert_batch([(e, 5u32)]);
+        c.entity_back(e);
+        c.entity_back(e); // Not alive anymore, nothing happens
+        c.entity_back(1000);
+
+        let t = ComponentType::of::<u32>();
+        assert_eq!(vec![
+            Change::spawn(e),
+            Change::add(e, t),
+            Change::remove(e, t),
+            Change::add(e, t),
+            Change::remove(e, t),
+            Change::despawn(e),
+        ], c.drain_changes(id));
+        assert_eq!(None, c.get::<u32>(&e));
+        assert!(!c.entities_iter().any(|taken| *taken == e));
+        assert!(c.entities_iter().any(|taken| *taken == early));
+    }
+
+    #[test]
+    fn test_structural_changes_from_deferred_closures() {
+        let mut c = Coordinator::new();
+        let s = Rc::new(RefCell::new(SimpleSystem::new()));
+        c.register_system(s.clone());
+        c.register_component::<u32>();
+
+        let e1 = c.entity_take();
+        c.add_component(e1, 1u32);
+
+        let id = c.subscribe_changes();
+        c.apply_all();
+
+        assert_eq!(vec![Change::add(50, ComponentType::of::<u32>())], c.drain_changes(id));
+        assert!(c.system_entities::<SimpleSystem>().contains(&50));
+    }
+
+    #[test]
+    fn test_removed_component_leaves_system() {
+        let mut c = Coordinator::new();
+        let s = Rc::new(RefCell::new(SimpleSystem::new()));
+        c.register_system(s.clone());
+        c.register_component::<u32>();
+
+        let e1 = c.entity_take();
+        let e2 = c.entity_take();
+        c.add_component(e1, 1u32);
+        c.add_component(e2, 1u32);
+        c.remove_component::<u32>(e1);
+        c.entity_back(e2);
+
+        assert!(c.system_entities::<SimpleSystem>().is_empty());
+    }
+
+    struct DerivedVelocity;
+
+    impl Observer for DerivedVelocity {
+        fn trigger(&self) -> Trigger {
+            Trigger::OnAdd
+        }
+
+        fn component_type(&self) -> ComponentType {
+            ComponentType::of::<Position>()
+        }
+
+        fn on_trigger(&mut self, e: Entity, coordinator: &mut Coordinator) {
+            coordinator.add_component(e, Velocity { vx: 0, vy: 0 });
+        }
+    }
+
+    struct RemovalRecorder {
+        seen: Vec<(Entity, bool)>, // entity and whether it still had u32 when observer ran
+    }
+
+    impl Observer for RemovalRecorder {
+        fn trigger(&self) -> Trigger {
+            Trigger::OnRemove
+        }
+
+        fn component_type(&self) -> ComponentType {
+            ComponentType::of::<u32>()
+        }
+
+        fn on_trigger(&mut self, e: Entity, coordinator: &mut Coordinator) {
+            let has_u32 = coordinator.get::<u32>(&e).is_some();
+            self.seen.push((e, has_u32));
+        }
+    }
+
+    #[test]
+    fn test_observer_attaches_derived_component() {
+        let mut c = Coordinator::new();
+        let s = Rc::new(RefCell::new(ComplexSystem::new()));
+        c.register_system(s.clone());
+        c.register_observer(Rc::new(RefCell::new(DerivedVelocity)));
+        c.register_component::<Position>();
+        c.register_component::<Velocity>();
+
+        let e = c.entity_take();
+        c.add_component(e, Position { x: 1, y: 1 });
+
+        assert!(c.get::<Velocity>(&e).is_some());
+        assert!(c.system_entities::<ComplexSystem>().contains(&e));
+    }
+
+    #[test]
+    fn test_observer_runs_after_deferred_closure() {
+        struct ChurnSystem {
+            signature: Signature,
+        }
+
+        impl System for ChurnSystem {
+            fn get_signature(&self) -> &Signature {
+                &self.signature
+            }
+
+            fn apply(&mut self, _entities: &[Entity], _cm: &mut ComponentManager) -> Box<dyn Fn(&mut Coordinator)> {
+                Box::new(| coordinator: &mut Coordinator | {
+                    coordinator.remove_component::<u32>(7);
+                    coordinator.add_component(7, 2u32);
+                })
+            }
+        }
+
+        let mut c = Coordinator::new();
+        let recorder = Rc::new(RefCell::new(RemovalRecorder { seen: vec![] }));
+        c.register_observer(recorder.clone());
+        c.register_component::<u32>();
+        let e = (0..8).map(|_| c.entity_take()).last().unwrap();
+        assert_eq!(7, e);
+        c.add_component(e, 1u32);
+        c.register_system(Rc::new(RefCell::new(ChurnSystem {
+            signature: Signature::new().require::<Position>(),
+        })));
+
+        c.apply_all();
+
+        assert_eq!(vec![(7, true)], recorder.borrow().seen, "Observer should see the state after the whole closure");
+
+        c.entity_back(7);
+        assert_eq!(vec![(7, true), (7, false)], recorder.borrow().seen);
+    }
+
+    #[test]
+    fn test_spawn_bundle_evaluates_membership_once() {
+        struct StaticOnly {
+            signature: Signature,
+            notified: Vec<(Entity, bool)>,
+        }
+
+        impl System for StaticOnly {
+            fn add(&mut self, e: Entity) {
+                self.notified.push((e, true));
+            }
+
+            fn remove(&mut self, e: Entity) {
+                self.notified.push((e, false));
+            }
+
+            fn get_signature(&self) -> &Signature {
+                &self.signature
+            }
+
+            fn apply(&mut self, _entities: &[Entity], _cm: &mut ComponentManager) -> Box<dyn Fn(&mut Coordinator)> {
+                Box::new(| _coordinator: &mut Coordinator | {})
+            }
+        }
+
+        let mut c = Coordinator::new();
+        let static_only = Rc::new(RefCell::new(StaticOnly {
+            signature: Signature::new().require::<Position>().exclude::<Velocity>(),
+            notified: vec![],
+        }));
+        c.register_system(static_only.clone());
+        c.register_system(Rc::new(RefCell::new(ComplexSystem::new())));
+        c.register_component::<Position>();
+        c.register_component::<Velocity>();
+        let id = c.subscribe_changes();
+
+        let e = c.spawn((Position { x: 1, y: 2 }, Velocity { vx: 1, vy: 1 }));
+
+        assert!(static_only.borrow().notified.is_empty(), "Partially built entity should never join");
+        assert_eq!(&[e], c.system_entities::<ComplexSystem>());
+        assert_eq!(Some(&Position { x: 1, y: 2 }), c.get::<Position>(&e));
+        assert_eq!(vec![
+            Change::spawn(e),
+            Change::add(e, ComponentType::of::<Position>()),
+            Change::add(e, ComponentType::of::<Velocity>()),
+        ], c.drain_changes(id));
+
+        c.apply_all();
+        assert_eq!(Some(&Position { x: 2, y: 3 }), c.get::<Position>(&e));
+    }
+
+    #[test]
+    fn test_spawn_batch_and_insert_batch() {
+        let mut c = Coordinator::with_capacity(20_000);
+        c.register_system(Rc::new(RefCell::new(ComplexSystem::new())));
+        c.register_component::<Position>();
+        c.register_component::<Velocity>();
+
+        let spawned = c.spawn_batch((0..10_000).map(|i| (Position { x: i, y: 0 },)));
+        assert_eq!(10_000, spawned.len());
+        assert!(c.system_entities::<ComplexSystem>().is_empty());
+
+        let moving: Vec<Entity> = spawned.iter().copied().step_by(2).collect();
+        c.insert_batch(moving.iter().map(|e| (*e, Velocity { vx: 1, vy: 1 })));
+
+        let mut expected = moving.clone();
+        expected.sort();
+        assert_eq!(&expected[..], c.system_entities::<ComplexSystem>());
+
+        c.apply_all();
+        for (i, e) in spawned.iter().enumerate().take(4) {
+            let d = if i % 2 == 0 { 1 } else { 0 };
+            assert_eq!(Some(&Position { x: i as i32 + d, y: d }), c.get::<Position>(e));
+        }
+    }
+
+    #[test]
+    fn test_spawn_batch_checks_capacity_first() {
+        let mut c = Coordinator::with_capacity(4);
+        c.register_component::<Position>();
+        c.entity_take();
+        let batch = || (0..4).map(|i| (Position { x: i, y: 0 },));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| c.spawn_batch(batch())));
+        assert!(result.is_err());
+        assert_eq!(1, c.entities_iter().count(), "Nothing of the batch is spawned");
+        assert_eq!(3, c.spawn_batch(batch().take(3)).len());
+    }
+
+    #[cfg(feature = "snapshot")]
+    impl Persist for Position {
+        fn write(&self, out: &mut Vec<u8>) {
+            self.x.write(out);
+            self.y.write(out);
+        }
+
+        fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+            Ok(Position { x: i32::read(input)?, y: i32::read(input)? })
+        }
+    }
+
+    #[cfg(feature = "snapshot")]
+    impl Persist for Velocity {
+        fn write(&self, out: &mut Vec<u8>) {
+            self.vx.write(out);
+            self.vy.write(out);
+        }
+
+        fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+            Ok(Velocity { vx: i32::read(input)?, vy: i32::read(input)? })
+        }
+    }
+
+    #[cfg(feature = "snapshot")]
+    fn snapshot_world() -> Coordinator {
+        let mut c = Coordinator::new();
+        c.register_system(Rc::new(RefCell::new(ComplexSystem::new())));
+        c.register_component::<Position>();
+        c.register_component::<Velocity>();
+        c.register_serializer::<Position>("position");
+        c.register_serializer::<Velocity>("velocity");
+        c.register_serializer::<u64>("u64");
+        c.register_relation_named::<Likes>("likes", Cleanup::RemoveRelation);
+        c
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_snapshot_save_load() {
+        let mut c = snapshot_world();
+        let moving = c.spawn((Position { x: 1, y: 1 }, Velocity { vx: 1, vy: 2 }));
+        let still = c.spawn((Position { x: 5, y: 5 },));
+        let empty = c.entity_take();
+        c.globals_mut().add("tick", 42u64);
+        c.relate::<Likes>(moving, still);
+        let bytes = c.save_snapshot();
+
+        let mut loaded = snapshot_world();
+        let replaced = loaded.spawn((Position { x: 9, y: 9 }, Velocity { vx: 0, vy: 0 })); // Replaced by load
+        loaded.relate::<Likes>(replaced, replaced);
+        loaded.load_snapshot(&bytes).unwrap();
+        assert!(loaded.has_relation::<Likes>(moving, still));
+        assert_eq!(vec![still], loaded.targets::<Likes>(moving).collect::<Vec<_>>());
+
+        assert_eq!(&[moving], loaded.system_entities::<ComplexSystem>());
+        assert_eq!(Some(&Position { x: 5, y: 5 }), loaded.get::<Position>(&still));
+        assert_eq!(Some(&42u64), loaded.globals().get::<u64>("tick"));
+        let mut entities: Vec<Entity> = loaded.entities_iter().copied().collect();
+        entities.sort();
+        let mut expected = vec![moving, still, empty];
+        expected.sort();
+        assert_eq!(expected, entities);
+        assert_eq!(bytes, loaded.save_snapshot());
+
+        loaded.apply_all();
+        assert_eq!(Some(&Position { x: 2, y: 3 }), loaded.get::<Position>(&moving));
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_snapshot_load_failure_keeps_world() {
+        let mut c = snapshot_world();
+        let e = c.spawn((Position { x: 1, y: 1 },));
+        let bytes = c.save_snapshot();
+
+        let mut other = Coordinator::new(); // Serializer known but component not registered
+        other.register_serializer::<Position>("position");
+        assert_eq!(Err(SnapshotError::UnknownComponent(String::from("position"))), other.load_snapshot(&bytes));
+
+        c.relate::<Likes>(e, e);
+        let related = c.save_snapshot();
+        let mut unrelated = Coordinator::new();
+        unrelated.register_component::<Position>();
+        unrelated.register_serializer::<Position>("position");
+        assert_eq!(Err(SnapshotError::UnknownRelation(String::from("likes"))), unrelated.load_snapshot(&related));
+
+        assert!(c.load_snapshot(&bytes[..bytes.len() - 2]).is_err());
+        assert_eq!(Some(&Position { x: 1, y: 1 }), c.get::<Position>(&e));
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_snapshot_load_is_logged() {
+        let mut c = snapshot_world();
+        let e = c.spawn((Position { x: 1, y: 1 },));
+        let handle = c.entity_ref(e).unwrap();
+        let bytes = c.save_snapshot();
+
+        c.entity_back(e);
+        let other = c.spawn((Velocity { vx: 1, vy: 1 },));
+        let log = c.subscribe_changes();
+        c.load_snapshot(&bytes).unwrap();
+        assert_eq!(Some(e), c.resolve(handle), "Generations come from the snapshot");
+        let changes = c.drain_changes(log);
+        assert_eq!(vec![
+            Change::remove(other, ComponentType::of::<Velocity>()),
+            Change::despawn(other),
+            Change::spawn(e),
+            Change::add(e, ComponentType::of::<Position>()),
+        ], changes);
+
+        let mut small = Coordinator::with_capacity(4);
+        small.register_component_named::<Position>("position");
+        small.registry_mut().set_serializer::<Position>();
+        assert_eq!(
+            Err(SnapshotError::InvalidData(String::from("inconsistent entity pool"))),
+            small.load_snapshot(&bytes));
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_replicate_with_deltas() {
+        let mut server = snapshot_world();
+        let mut client = snapshot_world();
+        let log = client.subscribe_changes();
+        let mut acked = WorldState::new();
+        let mut current = WorldState::new();
+        let mut send = |server: &Coordinator, client: &mut Coordinator| {
+            server.refresh_state(&mut current);
+            client.apply_delta(&acked.diff(&current)).unwrap();
+            acked = current.clone();
+        };
+
+        let moving = server.spawn((Position { x: 0, y: 0 }, Velocity { vx: 1, vy: 1 }));
+        let still = server.spawn((Position { x: 5, y: 5 },));
+        server.relate::<Likes>(moving, still);
+        server.relate::<Likes>(still, moving);
+        send(&server, &mut client);
+        assert_eq!(Some(&Position { x: 5, y: 5 }), client.get::<Position>(&still));
+        assert_eq!(&[moving], client.system_entities::<ComplexSystem>());
+        assert!(client.has_relation::<Likes>(moving, still));
+        assert_eq!(server.state_hash(), client.state_hash());
+
+        server.apply_all();
+        server.remove_component::<Velocity>(moving);
+        server.entity_back(still);
+        client.drain_changes(log);
+        send(&server, &mut client);
+        assert_eq!(Some(&Position { x: 1, y: 1 }), client.get::<Position>(&moving));
+        assert!(client.get::<Velocity>(&moving).is_none());
+        assert!(!client.entities_iter().any(|e| *e == still));
+        assert!(client.system_entities::<ComplexSystem>().is_empty());
+        assert_eq!(0, client.sources::<Likes>(still).count());
+        let kinds: Vec<crate::ChangeKind> = client.drain_changes(log).iter().map(|c| c.kind).collect();
+        let expected = vec![
+            crate::ChangeKind::Unrelate,
+            crate::ChangeKind::Unrelate,
+            crate::ChangeKind::Remove,
+            crate::ChangeKind::Despawn,
+            crate::ChangeKind::Remove,
+        ];
+        assert_eq!(expected, kinds);
+
+        server.relate::<Likes>(moving, moving);
+        send(&server, &mut client);
+        assert!(client.has_relation::<Likes>(moving, moving));
+        server.unrelate::<Likes>(moving, moving);
+        send(&server, &mut client);
+        assert_eq!(0, client.targets::<Likes>(moving).count());
+
+        server.set_entity_reuse(Reuse::Lifo); // Client keeps the default
+        let (a, b) = (server.entity_take(), server.entity_take());
+        send(&server, &mut client);
+        server.entity_back(a);
+        server.entity_back(b);
+        send(&server, &mut client);
+        assert_eq!(server.state_hash(), client.state_hash(), "Free ids are in the same order");
+        assert_eq!(acked, client.capture_state());
+        assert_eq!(server.entity_take(), client.entity_take());
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_delta_not_matching_world_is_rejected() {
+        let mut server = snapshot_world();
+        let e = server.spawn((Position { x: 1, y: 1 },));
+        let bytes = WorldState::new().diff(&server.capture_state());
+
+        let mut client = snapshot_world();
+        client.apply_delta(&bytes).unwrap();
+        assert!(matches!(client.apply_delta(&bytes), Err(SnapshotError::InvalidData(_))), "Entity already exists");
+
+        let mut small = Coordinator::with_capacity(0);
+        small.register_component::<Position>();
+        small.register_serializer::<Position>("position");
+        assert!(small.apply_delta(&bytes).is_err());
+        assert_eq!(None, small.get::<Position>(&e));
+    }
+
+    #[cfg(feature = "scene")]
+    impl SceneComponent for Position {
+        fn from_value(value: &scene::Value) -> Result<Self, String> {
+            Ok(Position { x: i32::from_value(value.field("x")?)?, y: i32::from_value(value.field("y")?)? })
+        }
+
+        fn to_value(&self) -> scene::Value {
+            scene::Value::Struct(vec![
+                (String::from("x"), self.x.to_value()),
+                (String::from("y"), self.y.to_value()),
+            ])
+        }
+    }
+
+    #[cfg(feature = "scene")]
+    impl SceneComponent for Velocity {
+        fn from_value(value: &scene::Value) -> Result<Self, String> {
+            Ok(Velocity { vx: i32::from_value(value.field("vx")?)?, vy: i32::from_value(value.field("vy")?)? })
+        }
+
+        fn to_value(&self) -> scene::Value {
+            scene::Value::Struct(vec![
+                (String::from("vx"), self.vx.to_value()),
+                (String::from("vy"), self.vy.to_value()),
+            ])
+        }
+    }
+
+    #[cfg(feature = "scene")]
+    fn scene_world() -> Coordinator {
+        let mut c = Coordinator::new();
+        c.register_system(Rc::new(RefCell::new(ComplexSystem::new())));
+        c.register_component::<Position>();
+        c.register_component::<Velocity>();
+        c.register_scene_component::<Position>("Position");
+        c.register_scene_component::<Velocity>("Velocity");
+        c
+    }
+
+    #[cfg(feature = "scene")]
+    #[test]
+    fn test_scene_load_and_export() {
+        let text = "entity {\n    Position { x: 1, y: 2 }\n    Velocity { vx: 1, vy: 1 }\n}\n\nentity {\n    Position { x: 5, y: 5 }\n}\n";
+        let mut c = scene_world();
+        let spawned = c.load_scene(text).unwrap();
+
+        assert_eq!(2, spawned.len());
+        assert_eq!(&spawned[..1], c.system_entities::<ComplexSystem>());
+        assert_eq!(Some(&Position { x: 5, y: 5 }), c.get::<Position>(&spawned[1]));
+
+        // Entities are exported in id order, compare them regardless of ids given by the pool
+        let blocks = |scene: String| {
+            let mut blocks: Vec<String> = scene.split("\n\n").map(|b| String::from(b.trim_end())).collect();
+            blocks.sort();
+            blocks
+        };
+        assert_eq!(blocks(String::from(text)), blocks(c.export_scene()));
+
+        let mut reloaded = scene_world();
+        reloaded.load_scene(&c.export_scene()).unwrap();
+        assert_eq!(blocks(c.export_scene()), blocks(reloaded.export_scene()));
+    }
+
+    #[cfg(feature = "scene")]
+    #[test]
+    fn test_scene_errors_leave_world_untouched() {
+        let mut c = scene_world();
+
+        let err = c.load_scene("entity {\n  Position { x: 1, y: 2 }\n}\nentity {\n  Position { x: 1 }\n}").unwrap_err();
+        assert_eq!((5, 3), (err.line, err.column));
+        assert_eq!("Position: missing field 'y'", err.message);
+        assert_eq!(0, c.entities_iter().count());
+
+        let mut unregistered = Coordinator::new();
+        unregistered.register_scene_component::<Position>("Position");
+        let err = unregistered.load_scene("entity {\n  Position { x: 1, y: 2 }\n}").unwrap_err();
+        assert_eq!("component 'Position' is not registered", err.message);
+
+        let mut small = Coordinator::with_capacity(2);
+        small.register_component::<Position>();
+        small.register_scene_component::<Position>("Position");
+        small.entity_take();
+        let err = small.load_scene("entity {\n  Position { x: 1, y: 2 }\n}\nentity {}").unwrap_err();
+        assert_eq!("scene of 2 entities doesn't fit into 1 free entities", err.message);
+        assert_eq!(1, small.entities_iter().count());
+    }
+
+    #[test]
+    fn test_rollback_to_saved_tick() {
+        let mut c = Coordinator::new();
+        c.set_rollback_capacity(3);
+        c.register_system(Rc::new(RefCell::new(ComplexSystem::new())));
+        c.register_component::<Position>();
+        c.register_component::<Velocity>();
+        c.registry_mut().set_clone::<Position>();
+        c.registry_mut().set_clone::<Velocity>();
+        c.registry_mut().set_clone::<u32>();
+        let moving = c.spawn((Position { x: 0, y: 0 }, Velocity { vx: 1, vy: 2 }));
+        c.globals_mut().add("frame", 0u32);
+
+        let first = c.save_tick().unwrap().unwrap();
+        c.apply_all();
+        *c.globals_mut().get_mut::<u32>("frame").unwrap() = 1;
+        let second = c.save_tick().unwrap().unwrap();
+        c.apply_all();
+        c.entity_back(moving);
+        let other = c.spawn((Position { x: 9, y: 9 },));
+        assert!(c.system_entities::<ComplexSystem>().is_empty());
+
+        c.rollback_to(second).unwrap();
+        assert_eq!(Some(&Position { x: 1, y: 2 }), c.get::<Position>(&moving));
+        assert_eq!(Some(&1), c.globals().get::<u32>("frame"));
+        assert_eq!(1, c.entities_iter().count(), "Entity {} spawned after the tick is gone", other);
+        assert_eq!(&[moving], c.system_entities::<ComplexSystem>());
+
+        // Re-simulating saves over dropped ticks
+        c.apply_all();
+        assert_eq!(Some(second + 1), c.save_tick().unwrap());
+        c.rollback_to(first).unwrap();
+        assert_eq!(Some(&Position { x: 0, y: 0 }), c.get::<Position>(&moving));
+        assert_eq!(Err(RollbackError::UnknownTick(second)), c.rollback_to(second));
+    }
+
+    struct NotifiedSystem {
+        signature: Signature,
+        notified: Rc<RefCell<Vec<(Entity, bool)>>>,
+    }
+
+    impl System for NotifiedSystem {
+        fn add(&mut self, e: Entity) {
+            self.notified.borrow_mut().push((e, true));
+        }
+
+        fn remove(&mut self, e: Entity) {
+            self.notified.borrow_mut().push((e, false));
+        }
+
+        fn get_signature(&self) -> &Signature {
+            &self.signature
+        }
+
+        fn apply(&mut self, _entities: &[Entity], _cm: &mut ComponentManager) -> Box<dyn Fn(&mut Coordinator)> {
+            Box::new(|_: &mut Coordinator| {})
+        }
+    }
+
+    #[test]
+    fn test_rollback_logs_differences() {
+        let mut c = Coordinator::new();
+        c.set_rollback_capacity(1);
+        let notified = Rc::new(RefCell::new(Vec::new()));
+        let signature = Signature::new().require::<Position>();
+        c.register_system(Rc::new(RefCell::new(NotifiedSystem { signature, notified: notified.clone() })));
+        c.register_component::<Position>();
+        c.register_component::<Velocity>();
+        c.registry_mut().set_clone::<Position>();
+        c.registry_mut().set_clone::<Velocity>();
+        c.register_relation::<Likes>(Cleanup::RemoveRelation);
+        let still = c.spawn((Position { x: 0, y: 0 },));
+        let moving = c.spawn((Position { x: 0, y: 0 }, Velocity { vx: 1, vy: 1 }));
+        c.relate::<Likes>(still, moving);
+        let tick = c.save_tick().unwrap().unwrap();
+
+        c.relate::<Likes>(still, still);
+        c.get_mut::<Position>(&still).unwrap().x = 5;
+        c.remove_component::<Velocity>(moving);
+        c.entity_back(moving);
+        let reused = c.spawn((Velocity { vx: 0, vy: 0 },));
+        notified.borrow_mut().clear();
+        let log = c.subscribe_changes();
+        c.rollback_to(tick).unwrap();
+
+        assert_eq!(vec![(moving, true)], *notified.borrow(), "Unchanged entity {} is left alone", still);
+        let changes = c.drain_changes(log);
+        let likes = TypeId::of::<Likes>();
+        assert_eq!(7, changes.len());
+        assert_eq!(vec![
+            Change::unrelate(still, likes, still),
+            Change::remove(reused, ComponentType::of::<Velocity>()),
+            Change::despawn(reused),
+            Change::spawn(moving),
+        ], changes[..4]);
+        assert!(changes[4..6].contains(&Change::add(moving, ComponentType::of::<Position>())));
+        assert!(changes[4..6].contains(&Change::add(moving, ComponentType::of::<Velocity>())));
+        assert_eq!(Change::relate(still, likes, moving), changes[6]);
+    }
+
+    #[test]
+    fn test_rollback_buffer_is_bounded() {
+        let mut c = Coordinator::new();
+        c.register_component::<Position>();
+        c.registry_mut().set_clone::<Position>();
+        assert_eq!(Ok(None), c.save_tick(), "Nothing is kept by default");
+
+        c.set_rollback_capacity(2);
+        let e = c.spawn((Position { x: 0, y: 0 },));
+        let ticks: Vec<Tick> = (0..3).map(|i| {
+            c.get_mut::<Position>(&e).unwrap().x = i;
+            c.save_tick().unwrap().unwrap()
+        }).collect();
+
+        assert!(c.rollback_to(ticks[0]).is_err());
+        c.rollback_to(ticks[1]).unwrap();
+        assert_eq!(Some(&Position { x: 1, y: 0 }), c.get::<Position>(&e));
+
+        let mut unsaved = Coordinator::new();
+        unsaved.globals_mut().add("seed", 7u64); // No clone function
+        assert_eq!(Ok(None), unsaved.save_tick(), "Nothing is copied when no tick is kept");
+    }
+
+    #[test]
+    fn test_state_hash_matches_across_peers() {
+        let mut a = Coordinator::new();
+        a.register_system(Rc::new(RefCell::new(ComplexSystem::new())));
+        a.register_component_named::<Position>("position");
+        a.register_component_named::<Velocity>("velocity");
+        a.registry_mut().set_hash::<Position>();
+        a.registry_mut().set_hash::<Velocity>();
+        a.registry_mut().set_clone::<Position>();
+        a.registry_mut().set_clone::<Velocity>();
+        let e = a.spawn((Position { x: 0, y: 0 }, Velocity { vx: 1, vy: 0 }));
+        a.spawn((Position { x: 7, y: 7 },));
+
+        let mut b = a.try_clone().unwrap();
+        a.apply_all();
+        b.apply_all();
+        assert_eq!(a.state_hash(), b.state_hash());
+
+        b.get_mut::<Position>(&e).unwrap().y = 1;
+        let (ha, hb) = (a.state_hash(), b.state_hash());
+        assert_ne!(ha.total, hb.total);
+        assert_eq!(vec!["position"], ha.mismatches(&hb));
+
+        a.register_relation_named::<Likes>("likes", Cleanup::RemoveRelation);
+        let before = a.state_hash();
+        a.relate::<Likes>(e, e);
+        assert_ne!(before.relations, a.state_hash().relations);
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        let mut c = Coordinator::new();
+        let root = c.entity_take();
+        let a = c.entity_take();
+        let b = c.entity_take();
+        let leaf = c.entity_take();
+        c.set_parent(a, root).unwrap();
+        c.set_parent(b, root).unwrap();
+        c.set_parent(leaf, a).unwrap();
+
+        assert_eq!(Some(root), c.parent(a));
+        assert_eq!(&[a, b], c.children(root));
+        assert_eq!(vec![a, root], c.ancestors(leaf).collect::<Vec<_>>());
+        assert_eq!(vec![a, leaf, b], c.descendants(root).collect::<Vec<_>>());
+        assert_eq!(vec![root, a, leaf, b], c.depth_first(root).collect::<Vec<_>>());
+
+        c.set_parent(leaf, b).unwrap();
+        assert_eq!(None, c.get::<Children>(&a), "Empty children are removed");
+        assert_eq!(&[leaf], c.children(b));
+
+        c.remove_parent(b);
+        assert_eq!(None, c.parent(b));
+        assert_eq!(&[a], c.children(root));
+        assert_eq!(vec![leaf], c.descendants(b).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_hierarchy_rejects_cycles() {
+        let mut c = Coordinator::new();
+        let a = c.entity_take();
+        let b = c.entity_take();
+        c.set_parent(b, a).unwrap();
+        assert_eq!(Err(HierarchyError::Cycle { child: a, parent: b }), c.set_parent(a, b));
+        assert_eq!(Err(HierarchyError::Cycle { child: a, parent: a }), c.set_parent(a, a));
+        assert_eq!(None, c.parent(a));
+        assert_eq!(&[b], c.children(a));
+
+        let dead = c.entity_take();
+        c.entity_back(dead);
+        assert_eq!(Err(HierarchyError::NotAlive(dead)), c.set_parent(a, dead));
+        assert_eq!(Err(HierarchyError::NotAlive(dead)), c.set_parent(dead, a));
+        assert_eq!(None, c.parent(a));
+        assert!(c.get::<Children>(&dead).is_none());
+    }
+
+    #[test]
+    fn test_hierarchy_observers_see_both_sides() {
+        struct ChildrenRecorder {
+            trigger: Trigger,
+            seen: Vec<(Entity, bool)>, // entity and whether both sides agreed when observer ran
+        }
+
+        impl Observer for ChildrenRecorder {
+            fn trigger(&self) -> Trigger {
+                self.trigger
+            }
+
+            fn component_type(&self) -> ComponentType {
+                ComponentType::of::<Children>()
+            }
+
+            fn on_trigger(&mut self, e: Entity, coordinator: &mut Coordinator) {
+                let children = coordinator.children(e).to_vec();
+                let consistent = children.iter().all(|c| coordinator.parent(*c) == Some(e))
+                    && !coordinator.entities_iter().any(|c| !children.contains(c) && coordinator.parent(*c) == Some(e));
+                self.seen.push((e, consistent));
+            }
+        }
+
+        let mut c = Coordinator::new();
+        let added = Rc::new(RefCell::new(ChildrenRecorder { trigger: Trigger::OnAdd, seen: vec![] }));
+        let removed = Rc::new(RefCell::new(ChildrenRecorder { trigger: Trigger::OnRemove, seen: vec![] }));
+        c.register_observer(added.clone());
+        c.register_observer(removed.clone());
+        let a = c.entity_take();
+        let b = c.entity_take();
+        let child = c.entity_take();
+        c.set_parent(child, a).unwrap();
+        c.set_parent(child, b).unwrap();
+        assert_eq!(vec![(a, true), (b, true)], added.borrow().seen);
+        assert_eq!(vec![(a, true)], removed.borrow().seen);
+
+        c.entity_back(child);
+        assert_eq!(vec![(a, true), (b, true)], removed.borrow().seen);
+    }
+
+    #[test]
+    fn test_despawn_is_recursive() {
+        let mut c = Coordinator::new();
+        c.register_component::<u32>();
+        let root = c.entity_take();
+        let child = c.spawn((1u32,));
+        let grandchild = c.spawn((2u32,));
+        let sibling = c.entity_take();
+        c.set_parent(child, root).unwrap();
+        c.set_parent(sibling, root).unwrap();
+        c.set_parent(grandchild, child).unwrap();
+
+        c.entity_back(child);
+        let alive: HashSet<Entity> = c.entities_iter().copied().collect();
+        assert_eq!(HashSet::from_iter(vec![root, sibling]), alive);
+        assert_eq!(&[sibling], c.children(root));
+        assert_eq!(None, c.get::<u32>(&grandchild));
+
+        c.entity_back(root);
+        assert_eq!(0, c.entities_iter().count());
+    }
+
+    #[test]
+    fn test_transform_propagation() {
+        let mut c = Coordinator::new();
+        let system = Rc::new(RefCell::new(TransformSystem::<Transform2D>::new()));
+        c.register_system(system.clone());
+        let local = |x| (LocalTransform(Transform2D::from_translation(x, 0.0)),);
+        let root = c.spawn(local(1.0));
+        let child = c.spawn(local(2.0));
+        let grandchild = c.spawn(local(4.0));
+        let other = c.spawn(local(8.0));
+        c.set_parent(child, root).unwrap();
+        c.set_parent(grandchild, child).unwrap();
+
+        c.apply_all();
+        assert_eq!(4, system.borrow().recomputed().len());
+        assert_eq!([7.0, 0.0], c.get::<GlobalTransform2D>(&grandchild).unwrap().0.translation);
+
+        c.apply_all();
+        assert!(system.borrow().recomputed().is_empty(), "Nothing changed");
+
+        c.get_mut::<LocalTransform2D>(&child).unwrap().0.translation[0] = 3.0;
+        c.apply_all();
+        assert_eq!(&[child, grandchild], system.borrow().recomputed());
+        assert_eq!([8.0, 0.0], c.get::<GlobalTransform2D>(&grandchild).unwrap().0.translation);
+
+        c.set_parent(other, grandchild).unwrap();
+        c.apply_all();
+        assert_eq!(&[other], system.borrow().recomputed());
+        assert_eq!([16.0, 0.0], c.get::<GlobalTransform2D>(&other).unwrap().0.translation);
+
+        let mut fork = c.try_clone().unwrap();
+        fork.get_mut::<LocalTransform2D>(&root).unwrap().0.translation[0] = 2.0;
+        fork.apply_all();
+        assert_eq!([17.0, 0.0], fork.get::<GlobalTransform2D>(&other).unwrap().0.translation);
+        assert_eq!(&[other], system.borrow().recomputed(), "Fork has its own system");
+    }
+
+    #[test]
+    fn test_transform_cycles_are_reported() {
+        let mut c = Coordinator::new();
+        let system = Rc::new(RefCell::new(TransformSystem::<Transform2D>::new()));
+        c.register_system(system.clone());
+        let a = c.spawn((LocalTransform(Transform2D::IDENTITY),));
+        let b = c.spawn((LocalTransform(Transform2D::IDENTITY),));
+        let fine = c.spawn((LocalTransform(Transform2D::IDENTITY),));
+        c.add_component(a, Parent(b)); // Bypasses set_parent checks
+        c.add_component(b, Parent(a));
+
+        c.apply_all();
+        let cycles = system.borrow().cycles().to_vec();
+        assert_eq!(1, cycles.len());
+        assert_eq!(HashSet::from_iter(vec![a, b]), cycles[0].iter().copied().collect::<HashSet<_>>());
+        assert_eq!(&[fine], system.borrow().recomputed());
+        assert!(c.get::<GlobalTransform2D>(&a).is_none());
+    }
+
+    struct Likes;
+    struct OwnedBy;
+
+    #[test]
+    fn test_relations() {
+        let mut c = Coordinator::new();
+        c.register_relation::<Likes>(Cleanup::RemoveRelation);
+        c.register_relation::<OwnedBy>(Cleanup::DespawnSource);
+        let alice = c.entity_take();
+        let bob = c.entity_take();
+        let sword = c.entity_take();
+        let gem = c.entity_take();
+        c.relate::<Likes>(alice, bob);
+        c.relate::<Likes>(alice, sword);
+        c.relate::<Likes>(bob, sword);
+        c.relate::<OwnedBy>(sword, bob);
+        c.relate::<OwnedBy>(gem, sword);
+
+        let mut likers: Vec<Entity> = c.sources::<Likes>(sword).collect();
+        likers.sort_unstable();
+        let mut expected = vec![alice, bob];
+        expected.sort_unstable();
+        assert_eq!(expected, likers);
+        assert!(c.has_relation::<Likes>(alice, bob));
+
+        assert!(!c.relate::<Likes>(alice, bob), "Pair is there already");
+
+        c.entity_back(bob);
+        let alive: HashSet<Entity> = c.entities_iter().copied().collect();
+        assert_eq!(HashSet::from_iter(vec![alice]), alive, "Owned entities go with the owner, transitively");
+        assert_eq!(0, c.targets::<Likes>(alice).count());
+        assert!(!c.relate::<Likes>(alice, bob), "Bob is despawned");
+        assert!(!c.relate::<Likes>(bob, alice));
+        assert_eq!(0, c.targets::<Likes>(bob).count());
+    }
+
+    #[test]
+    fn test_relations_are_logged() {
+        let mut c = Coordinator::new();
+        c.register_relation::<Likes>(Cleanup::RemoveRelation);
+        let alice = c.entity_take();
+        let bob = c.entity_take();
+        let log = c.subscribe_changes();
+        let likes = TypeId::of::<Likes>();
+
+        c.relate::<Likes>(alice, bob);
+        c.relate::<Likes>(bob, alice);
+        assert!(c.unrelate::<Likes>(alice, bob));
+        assert!(!c.unrelate::<Likes>(alice, bob));
+        c.entity_back(alice);
+        let expected = vec![
+            Change::relate(alice, likes, bob),
+            Change::relate(bob, likes, alice),
+            Change::unrelate(alice, likes, bob),
+            Change::unrelate(bob, likes, alice),
+            Change::despawn(alice),
+        ];
+        assert_eq!(expected, c.drain_changes(log));
+    }
+
+    #[test]
+    fn test_entity_ref_resolves_only_live_entity() {
+        struct Target(EntityRef);
+
+        let mut c = Coordinator::with_capacity(2);
+        c.register_component::<Target>();
+        let enemy = c.entity_take();
+        let hunter = c.spawn((Target(c.entity_ref(enemy).unwrap()),));
+        let target = c.get::<Target>(&hunter).unwrap().0;
+        assert_eq!(Some(enemy), c.resolve(target));
+
+        c.entity_back(enemy);
+        let reused = c.entity_take();
+        assert_eq!(enemy, reused, "Only free id is reused");
+        assert_eq!(None, c.resolve(target));
+        assert_eq!(None, c.entity_ref(5), "Out of capacity");
+        assert_eq!(None, c.resolve(EntityRef { entity: 5, generation: 0 }));
+    }
+
+    fn prefab_world() -> Coordinator {
+        let mut c = Coordinator::new();
+        c.register_system(Rc::new(RefCell::new(ComplexSystem::new())));
+        c.register_component::<Position>();
+        c.register_component::<Velocity>();
+        c.registry_mut().set_clone::<Position>();
+        c.registry_mut().set_clone::<Velocity>();
+        c
+    }
+
+    #[test]
+    fn test_instantiate_prefab() {
+        let mut c = prefab_world();
+        let wheel = Rc::new(Prefab::new().with(Position { x: 0, y: 0 }));
+        let cart = Rc::new(Prefab::new()
+            .with(Position { x: 1, y: 1 })
+            .with(Velocity { vx: 1, vy: 0 })
+            .with_child(wheel.clone())
+            .with_child(wheel));
+        let train = Prefab::new().with(Position { x: 0, y: 0 }).with_child(cart);
+
+        let first = c.instantiate(&train).unwrap();
+        let second = c.instantiate_with(&train, (Position { x: 5, y: 5 },)).unwrap();
+        assert_eq!(Some(&Position { x: 0, y: 0 }), c.get::<Position>(&first));
+        assert_eq!(Some(&Position { x: 5, y: 5 }), c.get::<Position>(&second));
+
+        assert_eq!(4, c.depth_first(first).count());
+        let cart = c.children(second)[0];
+        assert_eq!(2, c.children(cart).len());
+        assert_eq!(Some(&Position { x: 1, y: 1 }), c.get::<Position>(&cart));
+        assert_eq!(2, c.system_entities::<ComplexSystem>().len(), "Every cart moves");
+        assert_eq!(8, c.entities_iter().count());
+    }
+
+    #[test]
+    fn test_instantiate_observers_see_whole_tree() {
+        struct TreeRecorder {
+            seen: Vec<(Entity, bool)>, // entity and whether it was already in the tree
+        }
+
+        impl Observer for TreeRecorder {
+            fn trigger(&self) -> Trigger {
+                Trigger::OnAdd
+            }
+
+            fn component_type(&self) -> ComponentType {
+                ComponentType::of::<Position>()
+            }
+
+            fn on_trigger(&mut self, e: Entity, coordinator: &mut Coordinator) {
+                let in_tree = coordinator.parent(e).is_some() || !coordinator.children(e).is_empty();
+                self.seen.push((e, in_tree));
+            }
+        }
+
+        let mut c = prefab_world();
+        let recorder = Rc::new(RefCell::new(TreeRecorder { seen: vec![] }));
+        c.register_observer(recorder.clone());
+        let log = c.subscribe_changes();
+        let wheel = Rc::new(Prefab::new().with(Position { x: 0, y: 0 }));
+        let cart = Prefab::new().with(Velocity { vx: 1, vy: 0 }).with_child(wheel.clone()).with_child(wheel);
+
+        let e = c.instantiate_with(&cart, (Position { x: 5, y: 5 },)).unwrap();
+        assert_eq!(3, recorder.borrow().seen.len());
+        assert!(recorder.borrow().seen.iter().all(|(_, in_tree)| *in_tree));
+        let changes = c.drain_changes(log);
+        assert_eq!(vec![Change::spawn(e), Change::add(e, ComponentType::of::<Position>())], changes[..2]);
+    }
+
+    #[test]
+    fn test_instantiate_reports_non_cloneable() {
+        let mut c = prefab_world();
+        c.register_component::<u64>();
+        let prefab = Prefab::new()
+            .with(Position { x: 0, y: 0 })
+            .with_child(Rc::new(Prefab::new().with(7u64)));
+
+        let name = String::from(std::any::type_name::<u64>());
+        assert_eq!(Some(CloneError::Component(name)), c.instantiate(&prefab).err());
+        assert_eq!(0, c.entities_iter().count());
+    }
+
+    #[test]
+    fn test_instantiate_checks_free_entities() {
+        let mut c = Coordinator::with_capacity(3);
+        c.register_component::<Position>();
+        c.registry_mut().set_clone::<Position>();
+        let wheel = Rc::new(Prefab::new().with(Position { x: 0, y: 0 }));
+        let cart = Prefab::new().with(Position { x: 1, y: 1 }).with_child(wheel.clone()).with_child(wheel);
+        c.entity_take();
+
+        assert_eq!(Some(CloneError::OutOfEntities { needed: 3, available: 2 }), c.instantiate(&cart).err());
+        assert_eq!(1, c.entities_iter().count());
+    }
+
+    #[cfg(feature = "scene")]
+    #[test]
+    fn test_prefabs_from_scene() {
+        let mut c = scene_world();
+        c.registry_mut().set_clone::<Position>();
+        c.registry_mut().set_clone::<Velocity>();
+        let prefabs = c.load_prefabs("entity {\n  Position { x: 1, y: 2 }\n  Velocity { vx: 0, vy: 1 }\n}").unwrap();
+        assert_eq!(1, prefabs.len());
+
+        let e = c.instantiate(&prefabs[0]).unwrap();
+        assert_eq!(Some(&Position { x: 1, y: 2 }), c.get::<Position>(&e));
+        assert_eq!(&[e], c.system_entities::<ComplexSystem>());
+    }
+
+    #[test]
+    fn test_clone_entity() {
+        let mut c = prefab_world();
+        c.register_component::<u64>(); // No clone function
+        let root = c.entity_take();
+        let e = c.spawn((Position { x: 1, y: 2 }, Velocity { vx: 1, vy: 1 }, 7u64, Name::new("cart")));
+        c.set_parent(e, root).unwrap();
+
+        let name = String::from(std::any::type_name::<u64>());
+        assert_eq!(Some(CloneError::Component(name)), c.clone_entity(e).err());
+        assert_eq!(2, c.entities_iter().count());
+
+        c.set_clone_policy(ClonePolicy::Skip);
+        let copy = c.clone_entity(e).unwrap();
+        assert_eq!(Some(&Position { x: 1, y: 2 }), c.get::<Position>(&copy));
+        assert_eq!(None, c.get::<u64>(&copy));
+        assert_eq!(Some(&Name::new("cart")), c.get::<Name>(&copy));
+        assert_eq!(vec![e, copy], c.find_all_by_name("cart"));
+        assert_eq!(&[e, copy], c.children(root));
+        let mut moving = vec![e, copy];
+        moving.sort_unstable();
+        assert_eq!(moving, c.system_entities::<ComplexSystem>());
+
+        c.get_mut::<Position>(&copy).unwrap().x = 10;
+        assert_eq!(Some(&Position { x: 1, y: 2 }), c.get::<Position>(&e), "Copy is independent");
+
+        c.entity_back(copy);
+        let count = c.entities_iter().count();
+        assert_eq!(Some(CloneError::NotAlive(copy)), c.clone_entity(copy).err());
+        assert_eq!(count, c.entities_iter().count(), "Nothing is spawned");
+    }
+
+    #[test]
+    fn test_disabled_entities_leave_systems() {
+        let mut c = prefab_world();
+        let e = c.spawn((Position { x: 0, y: 0 }, Velocity { vx: 1, vy: 1 }));
+
+        c.set_enabled(e, false);
+        c.set_enabled(e, false);
+        assert!(!c.is_enabled(e));
+        assert!(c.system_entities::<ComplexSystem>().is_empty());
+        c.apply_all();
+        assert_eq!(Some(&Position { x: 0, y: 0 }), c.get::<Position>(&e), "Components stay reachable");
+
+        c.set_enabled(e, true);
+        assert_eq!(&[e], c.system_entities::<ComplexSystem>());
+        c.apply_all();
+        assert_eq!(Some(&Position { x: 1, y: 1 }), c.get::<Position>(&e));
+
+        c.entity_back(e);
+        c.set_enabled(e, false);
+        assert_eq!(None, c.get::<Disabled>(&e), "Despawned entity is left alone");
+        assert_eq!(0, c.entities_iter().count());
+    }
+
+    #[test]
+    fn test_names_and_tags() {
+        let mut c = Coordinator::new();
+        let player = c.spawn((Name::new("player"),));
+        let door = c.spawn((Name::new("door"),));
+        c.add_tag(player, "hero");
+        c.add_tag(door, "hero");
+        c.add_tag(door, 3u8);
+
+        assert_eq!(Some(player), c.find_by_name("player"));
+        c.get_mut::<Name>(&door).unwrap().0 = String::from("boss_door");
+        assert_eq!(None, c.find_by_name("door"));
+        assert_eq!(Some(door), c.find_by_name("boss_door"));
+
+        assert_eq!(vec![player, door], c.tagged(&"hero"));
+        c.set_enabled(door, false);
+        c.register_component::<Name>();
+        c.register_component::<Disabled>();
+        assert_eq!(Some(player), c.find_by_name("player"), "Registering again keeps names");
+        assert!(!c.is_enabled(door));
+        assert_eq!(vec![player], c.tagged(&"hero"), "Disabled entities are skipped");
+        assert_eq!(None, c.find_by_name("boss_door"));
+        c.set_enabled(door, true);
+        assert_eq!(Some(door), c.find_by_name("boss_door"));
+
+        let copy = c.clone_entity(door).unwrap();
+        assert!(c.has_tag(copy, &"hero"));
+        assert!(c.has_tag(copy, &3u8));
+
+        c.entity_back(door);
+        assert_eq!(Some(copy), c.find_by_name("boss_door"), "Copy has the name too");
+        assert_eq!(vec![player, copy], c.tagged(&"hero"));
+        assert_eq!(vec![copy], c.tagged(&3u8));
+        assert!(c.remove_tag(player, &"hero"));
+        assert!(!c.has_tag(player, &"hero"));
+    }
+
+    #[cfg(feature = "snapshot")]
+    #[test]
+    fn test_tags_in_snapshots_and_deltas() {
+        let mut server = snapshot_world();
+        server.register_serializer::<String>("string");
+        let e = server.spawn((Position { x: 0, y: 0 },));
+        server.add_tag(e, String::from("hero"));
+        server.add_tag(e, "not persisted");
+        let bytes = server.save_snapshot();
+
+        let mut loaded = snapshot_world();
+        loaded.register_serializer::<String>("string");
+        let replaced = loaded.entity_take();
+        loaded.add_tag(replaced, String::from("villain"));
+        loaded.load_snapshot(&bytes).unwrap();
+        assert_eq!(vec![e], loaded.tagged(&String::from("hero")));
+        assert!(loaded.tagged(&String::from("villain")).is_empty());
+        assert!(!loaded.has_tag(e, &"not persisted"));
+
+        let mut client = snapshot_world();
+        client.register_serializer::<String>("string");
+        let delta = WorldState::new().diff(&server.capture_state());
+        assert!(matches!(client.apply_delta(&delta), Err(SnapshotError::InvalidData(_))), "String is not a tag type yet");
+        client.register_tag::<String>();
+        client.apply_delta(&delta).unwrap();
+        assert!(client.has_tag(e, &String::from("hero")));
+
+        let acked = server.capture_state();
+        server.remove_tag(e, &String::from("hero"));
+        server.add_tag(e, String::from("boss"));
+        client.apply_delta(&acked.diff(&server.capture_state())).unwrap();
+        assert_eq!(vec![e], client.tagged(&String::from("boss")));
+        assert!(!client.has_tag(e, &String::from("hero")));
+        client.add_tag(e, "not persisted");
+        assert_eq!(server.state_hash(), client.state_hash());
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    enum Team {
+        Red,
+        Blue,
+    }
+
+    impl crate::Indexed for Team {
+        type Key = Team;
+
+        fn key(&self) -> Team {
+            *self
+        }
+    }
+
+    #[test]
+    fn test_lookup_by_component_value() {
+        let mut c = Coordinator::new();
+        c.register_component::<Team>();
+        c.register_index::<Team>(IndexKind::Hash);
+        let red = c.spawn((Team::Red,));
+        let blue = c.spawn((Team::Blue,));
+        let traitor = c.spawn((Team::Red,));
+
+        *c.get_mut::<Team>(&traitor).unwrap() = Team::Blue;
+        assert_eq!(vec![red], c.lookup::<Team>(&Team::Red));
+        let mut blues = vec![blue, traitor];
+        blues.sort_unstable();
+        assert_eq!(blues, c.lookup::<Team>(&Team::Blue));
+
+        c.set_enabled(blue, false);
+        assert_eq!(vec![traitor], c.lookup::<Team>(&Team::Blue));
+        c.entity_back(red);
+        assert!(c.lookup::<Team>(&Team::Red).is_empty());
+    }
+
+    impl crate::Located for Position {
+        fn location(&self) -> [f32; 2] {
+            [self.x as f32, self.y as f32]
+        }
+    }
+
+    #[test]
+    fn test_spatial_queries() {
+        let mut c = Coordinator::new();
+        c.register_component::<Position>();
+        let origin = c.spawn((Position { x: 0, y: 0 },));
+        c.register_spatial_index::<Position>(4.0);
+        let near = c.spawn((Position { x: 3, y: 0 },));
+        let far = c.spawn((Position { x: 30, y: 40 },));
+        let hidden = c.spawn((Position { x: 1, y: 1 },));
+        c.set_enabled(hidden, false);
+
+        let mut close = vec![origin, near];
+        close.sort_unstable();
+        assert_eq!(close, c.within_radius::<Position>([0.0, 0.0], 3.0));
+        assert_eq!(vec![far], c.within_aabb::<Position>([10.0, 10.0], [50.0, 50.0]));
+        assert_eq!(vec![origin, near], c.nearest::<Position>([0.0, 0.0], 2));
+
+        c.get_mut::<Position>(&far).unwrap().x = -1;
+        c.get_mut::<Position>(&far).unwrap().y = 0;
+        assert_eq!(vec![far, origin], c.nearest::<Position>([-2.0, 0.0], 2));
+        c.entity_back(origin);
+        assert_eq!(vec![far, near], c.nearest::<Position>([-2.0, 0.0], 5));
+        assert!(c.within_aabb::<Position>([10.0, 10.0], [50.0, 50.0]).is_empty());
+    }
+
+    #[test]
+    fn test_entity_ids_are_deterministic() {
+        let run = |reuse| {
+            let mut c = Coordinator::with_capacity(8);
+            c.set_entity_reuse(reuse);
+            let first: Vec<Entity> = (0..4).map(|_| c.entity_take()).collect();
+            c.entity_back(first[2]);
+            c.entity_back(first[0]);
+            (0..3).map(|_| c.entity_take()).collect::<Vec<Entity>>()
+        };
+        assert_eq!(vec![4, 5, 6], run(Reuse::Fifo));
+        assert_eq!(vec![0, 2, 4], run(Reuse::Lifo));
+
+        let mut c = Coordinator::with_capacity(8);
+        c.register_component::<Position>();
+        let e = c.spawn((Position { x: 1, y: 2 },));
+        let handle = c.entity_ref(e).unwrap();
+        c.reset_entities();
+        assert_eq!(0, c.entities_iter().count());
+        assert_eq!(e, c.spawn((Position { x: 3, y: 4 },)));
+        assert_eq!(None, c.resolve(handle));
+    }
+
+    struct SpawningSystem {
+        signature: Signature,
+        spawned: Rc<RefCell<Vec<Entity>>>,
+    }
+
+    impl System for SpawningSystem {
+        fn get_signature(&self) -> &Signature {
+            &self.signature
+        }
+
+        fn apply(&mut self, entities: &[Entity], cm: &mut ComponentManager) -> Box<dyn Fn(&mut Coordinator)> {
+            let mut spawned = Vec::new();
+            for e in entities {
+                let child = match cm.reserve_entity() {
+                    Some(child) => child,
+                    None => break,
+                };
+                let position = Position { x: cm.get::<Position>(e).unwrap().x + 1, y: 0 };
+                spawned.push((child, position));
+            }
+            self.spawned.borrow_mut().extend(spawned.iter().map(|(child, _)| *child));
+            Box::new(move |c: &mut Coordinator| {
+                for (child, position) in spawned.iter() {
+                    c.add_component(*child, position.clone());
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn test_reserve_entity_during_apply() {
+        let mut c = Coordinator::with_capacity(8);
+        c.register_component::<Position>();
+        let spawned = Rc::new(RefCell::new(Vec::new()));
+        let signature = Signature::new().require::<Position>();
+        c.register_system(Rc::new(RefCell::new(SpawningSystem { signature, spawned: spawned.clone() })));
+        let subscriber = c.subscribe_changes();
+        let e = c.spawn((Position { x: 0, y: 0 },));
+        c.drain_changes(subscriber);
+
+        c.apply_all();
+        assert_eq!(vec![1], *spawned.borrow());
+        assert_eq!(Some(Position { x: 1, y: 0 }), c.get::<Position>(&1).cloned());
+        assert_eq!(vec![e, 1], c.entities_iter().copied().collect::<Vec<Entity>>());
+        let changes = c.drain_changes(subscriber);
+        assert_eq!(crate::ChangeKind::Spawn, changes[0].kind);
+
+        c.apply_all();
+        assert_eq!(vec![1, 2, 3], *spawned.borrow());
+
+        c.apply_all();
+        c.apply_all();
+        assert_eq!(7, spawned.borrow().len(), "Only as many as there are free ids");
+        assert_eq!(8, c.entities_iter().count());
+    }
+
+    struct PanickingSystem {
+        signature: Signature,
+    }
+
+    impl System for PanickingSystem {
+        fn get_signature(&self) -> &Signature {
+            &self.signature
+        }
+
+        fn apply(&mut self, _entities: &[Entity], cm: &mut ComponentManager) -> Box<dyn Fn(&mut Coordinator)> {
+            cm.reserve_entity();
+            panic!("System failed");
+        }
+    }
+
+    #[test]
+    fn test_panicking_system_leaves_pool() {
+        let mut c = Coordinator::with_capacity(4);
+        c.register_component::<Position>();
+        let e = c.spawn((Position { x: 0, y: 0 },));
+        let signature = Signature::new().require::<Position>();
+        c.register_system(Rc::new(RefCell::new(PanickingSystem { signature })));
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| c.apply_all()));
+        assert!(result.is_err());
+        assert!(!c.cm.reserver().is_lent());
+        assert_eq!(vec![e], c.entities_iter().copied().collect::<Vec<Entity>>());
+        assert_eq!(Some(e), c.resolve(c.entity_ref(e).unwrap()));
+        assert_eq!(vec![2, 3, 1], c.pool.available_iter().copied().collect::<Vec<Entity>>(), "Reserved id went back");
+    }
+
+    #[test]
+    fn test_try_clone() {
+        let mut c = Coordinator::new();
+        c.register_system(Rc::new(RefCell::new(ComplexSystem::new())));
+        c.register_component::<Position>();
+        c.register_component::<Velocity>();
+        c.registry_mut().set_clone::<Position>();
+        c.registry_mut().set_clone::<Velocity>();
+        c.registry_mut().set_clone::<String>();
+        let moving = c.spawn((Position { x: 1, y: 1 }, Velocity { vx: 1, vy: 1 }));
+        let still = c.spawn((Position { x: 0, y: 0 },));
+        c.globals_mut().add("level", String::from("intro"));
+
+        let mut fork = c.try_clone().unwrap();
+        fork.apply_all();
+        fork.apply_all();
+        fork.entity_back(still);
+        fork.globals_mut().get_mut::<String>("level").unwrap().push_str("-2");
+
+        assert_eq!(Some(&Position { x: 3, y: 3 }), fork.get::<Position>(&moving));
+        assert_eq!(Some(&Position { x: 1, y: 1 }), c.get::<Position>(&moving), "Original is not touched");
+        assert_eq!(Some(&Position { x: 0, y: 0 }), c.get::<Position>(&still));
+        assert!(c.entities_iter().any(|e| *e == still));
+        assert_eq!(Some(&String::from("intro")), c.globals().get::<String>("level"));
+        assert_eq!(&[moving], fork.system_entities::<ComplexSystem>());
+    }
+
+    struct CountingSystem {
+        signature: Signature,
+        applied: u32,
+    }
+
+    impl System for CountingSystem {
+        fn get_signature(&self) -> &Signature {
+            &self.signature
+        }
+
+        fn apply(&mut self, _entities: &[Entity], _cm: &mut ComponentManager) -> Box<dyn Fn(&mut Coordinator)> {
+            self.applied += 1;
+            Box::new(|_: &mut Coordinator| {})
+        }
+
+        fn box_clone(&self) -> Option<Rc<RefCell<dyn System>>> {
+            Some(Rc::new(RefCell::new(CountingSystem { signature: self.signature.clone(), applied: self.applied })))
+        }
+    }
+
+    #[test]
+    fn test_try_clone_copies_systems() {
+        let mut c = Coordinator::new();
+        let counting = Rc::new(RefCell::new(CountingSystem { signature: Signature::new(), applied: 0 }));
+        c.register_system(counting.clone());
+        c.apply_all();
+
+        let mut fork = c.try_clone().unwrap();
+        fork.apply_all();
+        fork.apply_all();
+        assert_eq!(1, counting.borrow().applied, "Fork runs its own copy");
+
+        let spawned = Rc::new(RefCell::new(Vec::new()));
+        let signature = Signature::new();
+        c.register_system(Rc::new(RefCell::new(SpawningSystem { signature, spawned })));
+        let name = String::from(std::any::type_name::<SpawningSystem>());
+        assert_eq!(Some(CloneError::System(name)), c.try_clone().err());
+    }
+
+    #[test]
+    fn test_try_clone_reports_non_cloneable() {
+        let mut c = Coordinator::new();
+        c.register_component_named::<Position>("position");
+        c.register_component::<Velocity>(); // No clone, but unused
+        c.registry_mut().set_clone::<Position>();
+        let e = c.spawn((Position { x: 1, y: 1 },));
+        assert!(c.try_clone().is_ok());
+
+        c.globals_mut().add("seed", 7u64);
+        assert_eq!(Some(CloneError::Global(String::from("seed"))), c.try_clone().err());
+        c.registry_mut().set_clone::<u64>();
+
+        c.add_component(e, Velocity { vx: 0, vy: 0 });
+        let name = String::from(std::any::type_name::<Velocity>());
+        assert_eq!(Some(CloneError::Component(name)), c.try_clone().err());
+    }
+}
@@ -0,0 +1,390 @@
+use crate::Entity;
+use crate::ComponentType;
+use crate::ComponentManager;
+use crate::EntitiesPool;
+use crate::ComponentRegistry;
+use crate::relation::RelationStore;
+use crate::names::Tags;
+use crate::snapshot::{self, take, Persist, SnapshotError};
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::any::Any;
+
+// Layout (same primitives as snapshots):
+//   magic "ECSD", version u32
+//   spawned entities, despawned entities (u32 count + u32 ids)
+//   free id order: bool, if set u32 count + ids in the order they will be taken
+//   u32 type count, per type: name, added (u32 count, per entity: id, blob),
+//     changed (same as added), removed entities
+//   u32 related pair count, per pair: relation name, source, target, unrelated pairs the same
+//   u32 added tag count, per tag: type name, entity, blob, removed tags the same
+// Components, relation pairs and tags of despawned entities are not listed, they go away with
+// the entity. Without a free id order, spawned ids leave the free ones and despawned ids are
+// appended in listed order, see free_after. The order is only written when that isn't enough.
+pub const DELTA_MAGIC: &[u8; 4] = b"ECSD";
+pub const DELTA_VERSION: u32 = 4;
+
+type Pair = (String, Entity, Entity);
+type Tag = (String, Entity, Vec<u8>);
+
+// Serialized view of a world, what the receiving side is known to have. Only components with
+// a serializer in the registry are part of it, comparing blobs tells what changed. Relation
+// pairs are all part of it, under relation names, and tags of types with a serializer.
+#[derive(Debug, Clone, Default)]
+pub struct WorldState {
+    entities: BTreeSet<Entity>,
+    available: Vec<Entity>, // in the order they will be taken
+    components: BTreeMap<String, BTreeMap<Entity, Vec<u8>>>,
+    relations: BTreeSet<Pair>,
+    tags: BTreeSet<Tag>,
+    versions: BTreeMap<String, u64>, // of arrays when serialized, not part of the state
+}
+
+impl WorldState {
+    pub fn new() -> WorldState {
+        WorldState {
+            entities: BTreeSet::new(),
+            available: Vec::new(),
+            components: BTreeMap::new(),
+            relations: BTreeSet::new(),
+            tags: BTreeSet::new(),
+            versions: BTreeMap::new(),
+        }
+    }
+
+    pub fn capture(pool: &EntitiesPool, cm: &ComponentManager, relations: &RelationStore, tags: &Tags) -> WorldState {
+        let mut state = WorldState::new();
+        state.refresh(pool, cm, relations, tags);
+        state
+    }
+
+    // Serializes again only component arrays whose version changed since the last refresh, so
+    // a state kept up to date this way costs the arrays modified in between. Meant for states
+    // refreshed from one and the same world. Relation pairs and tags are collected every time.
+    pub fn refresh(&mut self, pool: &EntitiesPool, cm: &ComponentManager, relations: &RelationStore, tags: &Tags) {
+        self.entities = pool.taken_iter().copied().collect();
+        self.available = pool.available_iter().copied().collect();
+        self.relations = relations.pairs().into_iter()
+            .map(|(name, _, source, target)| (String::from(name), source, target))
+            .collect();
+        self.tags = snapshot::serialize_tags(cm.registry(), tags).into_iter().collect();
+
+        let registry = cm.registry();
+        let mut names = BTreeSet::new();
+        for (id, info) in registry.iter().filter(|(id, _)| cm.is_registered(*id)) {
+            let serializer = match info.serializer.as_ref() {
+                Some(serializer) => serializer,
+                None => continue,
+            };
+            names.insert(info.name.as_str());
+            let version = cm.version(id);
+            if self.versions.get(&info.name) == Some(&version) {
+                continue;
+            }
+
+            let blobs: BTreeMap<Entity, Vec<u8>> = cm.entities_with(id).into_iter()
+                .map(|e| {
+                    let mut blob = Vec::new();
+                    (serializer.write)(cm.get_any(e, id).unwrap(), &mut blob);
+                    (e, blob)
+                })
+                .collect();
+            self.components.insert(info.name.clone(), blobs);
+            self.versions.insert(info.name.clone(), version);
+        }
+        self.components.retain(|name, _| names.contains(name.as_str()));
+        self.versions.retain(|name, _| names.contains(name.as_str()));
+    }
+
+    // Bytes turning this state into the newer one
+    pub fn diff(&self, newer: &WorldState) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(DELTA_MAGIC);
+        DELTA_VERSION.write(&mut out);
+
+        let spawned: Vec<Entity> = newer.entities.difference(&self.entities).copied().collect();
+        let despawned: Vec<Entity> = newer.available.iter()
+            .filter(|e| self.entities.contains(e))
+            .copied()
+            .collect();
+        spawned.write(&mut out);
+        despawned.write(&mut out);
+        let order = free_after(&self.available, &spawned, &despawned) != newer.available;
+        order.write(&mut out);
+        if order {
+            newer.available.write(&mut out);
+        }
+
+        let empty = BTreeMap::new();
+        let names: BTreeSet<&String> = self.components.keys().chain(newer.components.keys()).collect();
+        let mut types = Vec::new();
+        for name in names {
+            let old = self.components.get(name).unwrap_or(&empty);
+            let new = newer.components.get(name).unwrap_or(&empty);
+
+            let added: Vec<(Entity, &Vec<u8>)> = new.iter()
+                .filter(|(e, _)| !old.contains_key(e))
+                .map(|(e, blob)| (*e, blob))
+                .collect();
+            let changed: Vec<(Entity, &Vec<u8>)> = new.iter()
+                .filter(|(e, blob)| old.get(e).is_some_and(|old_blob| old_blob != *blob))
+                .map(|(e, blob)| (*e, blob))
+                .collect();
+            let removed: Vec<Entity> = old.keys()
+                .filter(|e| !new.contains_key(e) && newer.entities.contains(e))
+                .copied()
+                .collect();
+            if !added.is_empty() || !changed.is_empty() || !removed.is_empty() {
+                types.push((name, added, changed, removed));
+            }
+        }
+
+        (types.len() as u32).write(&mut out);
+        for (name, added, changed, removed) in types {
+            name.write(&mut out);
+            for blobs in [added, changed] {
+                (blobs.len() as u32).write(&mut out);
+                for (e, blob) in blobs {
+                    e.write(&mut out);
+                    blob.write(&mut out);
+                }
+            }
+            removed.write(&mut out);
+        }
+
+        let gone = |(_, source, target): &&Pair| despawned.contains(source) || despawned.contains(target);
+        let related: Vec<&Pair> = newer.relations.difference(&self.relations).collect();
+        let unrelated: Vec<&Pair> = self.relations.difference(&newer.relations).filter(|pair| !gone(pair)).collect();
+        for pairs in [related, unrelated] {
+            (pairs.len() as u32).write(&mut out);
+            for (name, source, target) in pairs {
+                name.write(&mut out);
+                source.write(&mut out);
+                target.write(&mut out);
+            }
+        }
+
+        let tagged: Vec<&Tag> = newer.tags.difference(&self.tags).collect();
+        let untagged: Vec<&Tag> = self.tags.difference(&newer.tags).filter(|(_, e, _)| !despawned.contains(e)).collect();
+        for tags in [tagged, untagged] {
+            (tags.len() as u32).write(&mut out);
+            for (name, e, blob) in tags {
+                name.write(&mut out);
+                e.write(&mut out);
+                blob.write(&mut out);
+            }
+        }
+
+        out
+    }
+}
+
+impl PartialEq for WorldState {
+    fn eq(&self, other: &Self) -> bool {
+        self.entities == other.entities && self.available == other.available && self.components == other.components && self.relations == other.relations
+            && self.tags == other.tags
+    }
+}
+
+impl Eq for WorldState {}
+
+// Free ids once a delta without a free id order is applied
+pub(crate) fn free_after(available: &[Entity], spawned: &[Entity], despawned: &[Entity]) -> Vec<Entity> {
+    let spawned: BTreeSet<Entity> = spawned.iter().copied().collect();
+    available.iter()
+        .filter(|e| !spawned.contains(e))
+        .chain(despawned.iter())
+        .copied()
+        .collect()
+}
+
+pub struct Decoded {
+    pub spawned: Vec<Entity>,
+    pub despawned: Vec<Entity>,
+    pub available: Option<Vec<Entity>>,
+    pub added: Vec<(Entity, ComponentType, Box<dyn Any>)>,
+    pub changed: Vec<(Entity, ComponentType, Box<dyn Any>)>,
+    pub removed: Vec<(Entity, ComponentType)>,
+    pub related: Vec<Pair>,
+    pub unrelated: Vec<Pair>,
+    pub tagged: Vec<(Entity, ComponentType, Box<dyn Any>)>,
+    pub untagged: Vec<(Entity, ComponentType, Box<dyn Any>)>,
+}
+
+pub fn decode(mut input: &[u8], registry: &ComponentRegistry) -> Result<Decoded, SnapshotError> {
+    let input = &mut input;
+    if take(input, DELTA_MAGIC.len()).map_err(|_| SnapshotError::BadMagic)? != DELTA_MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = u32::read(input)?;
+    if version != DELTA_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let spawned = Vec::<Entity>::read(input)?;
+    let despawned = Vec::<Entity>::read(input)?;
+    let available = Option::<Vec<Entity>>::read(input)?;
+
+    let mut added = Vec::new();
+    let mut changed = Vec::new();
+    let mut removed = Vec::new();
+    for _ in 0..u32::read(input)? {
+        let name = String::read(input)?;
+        for values in [&mut added, &mut changed] {
+            for _ in 0..u32::read(input)? {
+                let e = Entity::read(input)?;
+                let (id, value) = snapshot::read_blob(registry, &name, input)?;
+                values.push((e, id, value));
+            }
+        }
+        let entities = Vec::<Entity>::read(input)?;
+        if !entities.is_empty() {
+            let id = registry.type_of(&name).ok_or_else(|| SnapshotError::UnknownComponent(name.clone()))?;
+            removed.extend(entities.into_iter().map(|e| (e, id)));
+        }
+    }
+
+    let mut related = Vec::new();
+    let mut unrelated = Vec::new();
+    for pairs in [&mut related, &mut unrelated] {
+        for _ in 0..u32::read(input)? {
+            pairs.push((String::read(input)?, Entity::read(input)?, Entity::read(input)?));
+        }
+    }
+
+    let mut tagged = Vec::new();
+    let mut untagged = Vec::new();
+    for tags in [&mut tagged, &mut untagged] {
+        for _ in 0..u32::read(input)? {
+            let name = String::read(input)?;
+            let e = Entity::read(input)?;
+            let (id, value) = snapshot::read_blob(registry, &name, input)?;
+            tags.push((e, id, value));
+        }
+    }
+
+    if !input.is_empty() {
+        return Err(SnapshotError::InvalidData(String::from("trailing bytes after delta")));
+    }
+    Ok(Decoded { spawned, despawned, available, added, changed, removed, related, unrelated, tagged, untagged })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn world() -> (EntitiesPool, ComponentManager, RelationStore, Tags) {
+        let mut cm = ComponentManager::new();
+        cm.register_named::<u32>("u32");
+        cm.register::<i8>(); // No serializer, not replicated
+        cm.registry_mut().set_serializer::<u32>();
+        let mut relations = RelationStore::new();
+        relations.register_named::<u64>("follows", crate::Cleanup::RemoveRelation);
+        let mut tags = Tags::new();
+        tags.register::<u32>();
+        (EntitiesPool::with_capacity(8), cm, relations, tags)
+    }
+
+    #[test]
+    fn test_diff_decode() {
+        let (mut pool, mut cm, relations, tags) = world();
+        let kept = pool.take();
+        let gone = pool.take();
+        cm.add(kept, 1u32);
+        cm.add(gone, 2u32);
+        cm.add(kept, -1i8);
+        let before = WorldState::capture(&pool, &cm, &relations, &tags);
+        assert_eq!(37, before.diff(&before).len(), "Empty delta is just header and counts");
+
+        pool.back(gone);
+        cm.remove_all(gone);
+        let new = *pool.available_iter().find(|e| **e != gone).unwrap();
+        pool.take_entity(new);
+        cm.add(new, 3u32);
+        *cm.get_mut::<u32>(&kept).unwrap() = 10;
+        *cm.get_mut::<i8>(&kept).unwrap() = 5;
+        let after = WorldState::capture(&pool, &cm, &relations, &tags);
+
+        let delta = decode(&before.diff(&after), cm.registry()).unwrap();
+        assert_eq!((vec![new], vec![gone]), (delta.spawned, delta.despawned));
+        assert_eq!(None, delta.available, "Free ids follow from spawns and despawns");
+        assert_eq!(1, delta.added.len());
+        assert_eq!(Some(&3u32), delta.added[0].2.downcast_ref::<u32>());
+        assert_eq!(1, delta.changed.len());
+        assert_eq!((kept, Some(&10u32)), (delta.changed[0].0, delta.changed[0].2.downcast_ref::<u32>()));
+        assert!(delta.removed.is_empty(), "Removal is implied by despawn");
+
+        cm.remove::<u32>(&kept);
+        let removed = WorldState::capture(&pool, &cm, &relations, &tags);
+        let delta = decode(&after.diff(&removed), cm.registry()).unwrap();
+        assert_eq!(vec![(kept, ComponentType::of::<u32>())], delta.removed);
+
+        let mut order: Vec<Entity> = pool.available_iter().copied().collect();
+        order.reverse();
+        pool.reorder_available(order.clone());
+        let reordered = WorldState::capture(&pool, &cm, &relations, &tags);
+        assert_ne!(removed, reordered);
+        let delta = decode(&removed.diff(&reordered), cm.registry()).unwrap();
+        assert_eq!(Some(order), delta.available);
+    }
+
+    #[test]
+    fn test_diff_relations_and_tags() {
+        let (mut pool, cm, mut relations, mut tags) = world();
+        let (a, b, c) = (pool.take(), pool.take(), pool.take());
+        relations.add::<u64>(a, b);
+        relations.add::<u64>(c, a);
+        tags.add(a, 1u32);
+        tags.add(c, 3u32);
+        let before = WorldState::capture(&pool, &cm, &relations, &tags);
+
+        relations.remove::<u64>(a, b);
+        relations.add::<u64>(b, a);
+        pool.back(c);
+        relations.remove_entity(c, &mut Vec::new());
+        tags.remove_entity(c);
+        tags.remove(a, &1u32);
+        tags.add(b, 2u32);
+        let after = WorldState::capture(&pool, &cm, &relations, &tags);
+
+        let delta = decode(&before.diff(&after), cm.registry()).unwrap();
+        let follows = String::from("follows");
+        assert_eq!(vec![(follows.clone(), b, a)], delta.related);
+        assert_eq!(vec![(follows, a, b)], delta.unrelated, "Pair of despawned c is not listed");
+        assert_eq!(1, delta.tagged.len());
+        assert_eq!((b, Some(&2u32)), (delta.tagged[0].0, delta.tagged[0].2.downcast_ref::<u32>()));
+        assert_eq!(1, delta.untagged.len(), "Tag of despawned c is not listed");
+        assert_eq!((a, Some(&1u32)), (delta.untagged[0].0, delta.untagged[0].2.downcast_ref::<u32>()));
+    }
+
+    #[test]
+    fn test_refresh_follows_versions() {
+        let (mut pool, mut cm, relations, tags) = world();
+        let e = pool.take();
+        cm.add(e, 1u32);
+        let mut state = WorldState::capture(&pool, &cm, &relations, &tags);
+
+        *cm.get_mut::<u32>(&e).unwrap() = 2;
+        let other = pool.take();
+        cm.add(other, -1i8);
+        state.refresh(&pool, &cm, &relations, &tags);
+        assert_eq!(WorldState::capture(&pool, &cm, &relations, &tags), state);
+
+        let version = state.versions["u32"];
+        state.refresh(&pool, &cm, &relations, &tags);
+        assert_eq!(version, state.versions["u32"], "Unchanged array is not serialized again");
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let (mut pool, mut cm, relations, tags) = world();
+        let e = pool.take();
+        cm.add(e, 1u32);
+        let bytes = WorldState::new().diff(&WorldState::capture(&pool, &cm, &relations, &tags));
+
+        assert_eq!(Err(SnapshotError::BadMagic), decode(b"ECSS", cm.registry()).map(|_| ()));
+        assert_eq!(Err(SnapshotError::UnexpectedEof), decode(&bytes[..bytes.len() - 1], cm.registry()).map(|_| ()));
+        assert_eq!(Err(SnapshotError::UnknownComponent(String::from("u32"))), decode(&bytes, &ComponentRegistry::new()).map(|_| ()));
+    }
+}
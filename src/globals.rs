@@ -1,3 +1,6 @@
+use crate::ComponentRegistry;
+use crate::registry::CloneError;
+
 use std::collections::HashMap;
 use std::any::Any;
 
@@ -16,6 +19,25 @@ impl Globals {
         self.globals.insert(String::from(name), Box::new(c));
     }
 
+    pub fn add_boxed(&mut self, name: &str, c: Box<dyn Any>) {
+        self.globals.insert(String::from(name), c);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &dyn Any)> {
+        self.globals.iter().map(|(name, c)| (name.as_str(), c.as_ref()))
+    }
+
+    // Every global has to have clone function registered for its type
+    pub fn try_clone(&self, registry: &ComponentRegistry) -> Result<Globals, CloneError> {
+        let mut globals = HashMap::with_capacity(self.globals.len());
+        for (name, c) in self.globals.iter() {
+            let cloned = registry.clone_value(c.as_ref().type_id(), c.as_ref())
+                .ok_or_else(|| CloneError::Global(name.clone()))?;
+            globals.insert(name.clone(), cloned);
+        }
+        Ok(Globals { globals })
+    }
+
     pub fn get<T: 'static>(&self, name: &str) -> Option<&T> {
         let retval = self.globals.get(name)?;
         retval.downcast_ref::<T>()
@@ -27,6 +49,12 @@ impl Globals {
     }
 }
 
+impl Default for Globals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
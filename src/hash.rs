@@ -0,0 +1,227 @@
+use crate::Entity;
+use crate::ComponentManager;
+use crate::EntitiesPool;
+use crate::relation::RelationStore;
+use crate::names::Tags;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::hash::Hasher;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// FNV-1a, unlike std's DefaultHasher it is guaranteed to give the same result on every
+// platform and compiler version. Integers are fed little endian for the same reason.
+#[derive(Debug, Clone, Copy)]
+pub struct StateHasher {
+    state: u64,
+}
+
+impl StateHasher {
+    pub fn new() -> StateHasher {
+        StateHasher { state: FNV_OFFSET }
+    }
+}
+
+impl Default for StateHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for StateHasher {
+    fn finish(&self) -> u64 {
+        self.state
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= *byte as u64;
+            self.state = self.state.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as u64);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateHash {
+    pub total: u64,
+    pub entities: u64,
+    pub components: BTreeMap<String, u64>, // by registered name
+    pub relations: u64,
+    pub tags: u64,
+}
+
+impl StateHash {
+    // Registered names of component types whose hashes differ or are missing on one side
+    pub fn mismatches(&self, other: &StateHash) -> Vec<String> {
+        let names: BTreeSet<&String> = self.components.keys().chain(other.components.keys()).collect();
+        names.into_iter()
+            .filter(|name| self.components.get(*name) != other.components.get(*name))
+            .cloned()
+            .collect()
+    }
+}
+
+// Entities and components are visited in sorted order, so the result only depends on the
+// world content. Free ids are hashed in the order they will be taken, since that order
+// decides future spawns. Types without a hash function in the registry are skipped, relation
+// pairs are hashed under relation names and tags under registered names of their types.
+pub fn hash_world(pool: &EntitiesPool, cm: &ComponentManager, relations: &RelationStore, tags: &Tags) -> StateHash {
+    let mut taken: Vec<Entity> = pool.taken_iter().copied().collect();
+    taken.sort_unstable();
+    let mut hasher = StateHasher::new();
+    hasher.write_usize(taken.len());
+    for e in taken {
+        hasher.write_u32(e);
+    }
+    for e in pool.available_iter() {
+        hasher.write_u32(*e);
+    }
+    let entities = hasher.finish();
+
+    let registry = cm.registry();
+    let mut components = BTreeMap::new();
+    for (id, info) in registry.iter().filter(|(id, _)| cm.is_registered(*id)) {
+        if info.hash.is_none() {
+            continue;
+        }
+        let mut with = cm.entities_with(id);
+        with.sort_unstable();
+        let mut hasher = StateHasher::new();
+        for e in with {
+            hasher.write_u32(e);
+            registry.hash_value(id, cm.get_any(e, id).unwrap(), &mut hasher);
+        }
+        components.insert(info.name.clone(), hasher.finish());
+    }
+
+    let mut hasher = StateHasher::new();
+    for (name, _, source, target) in relations.pairs() {
+        hasher.write(name.as_bytes());
+        hasher.write_u32(source);
+        hasher.write_u32(target);
+    }
+    let relations = hasher.finish();
+
+    let mut tag_hashes: Vec<(&str, u64)> = tags.hashes().into_iter()
+        .map(|(id, type_name, hash)| (registry.name_of(id).unwrap_or(type_name), hash))
+        .collect();
+    tag_hashes.sort_unstable();
+    let mut hasher = StateHasher::new();
+    for (name, hash) in tag_hashes {
+        hasher.write(name.as_bytes());
+        hasher.write_u64(hash);
+    }
+    let tags = hasher.finish();
+
+    let mut hasher = StateHasher::new();
+    hasher.write_u64(entities);
+    for (name, hash) in components.iter() {
+        hasher.write(name.as_bytes());
+        hasher.write_u64(*hash);
+    }
+    hasher.write_u64(relations);
+    hasher.write_u64(tags);
+    StateHash { total: hasher.finish(), entities, components, relations, tags }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::Hash;
+
+    #[test]
+    fn test_state_hasher_is_fnv() {
+        let mut hasher = StateHasher::new();
+        hasher.write(b"a");
+        assert_eq!(0xaf63dc4c8601ec8c, hasher.finish());
+
+        let mut a = StateHasher::new();
+        7u32.hash(&mut a);
+        let mut b = StateHasher::new();
+        b.write(&[7, 0, 0, 0]);
+        assert_eq!(a.finish(), b.finish(), "Integers are hashed little endian");
+    }
+
+    #[test]
+    fn test_hash_world() {
+        let mut pool = EntitiesPool::with_capacity(8);
+        let mut cm = ComponentManager::new();
+        cm.register_named::<u32>("u32");
+        cm.register_named::<String>("string");
+        cm.register::<f32>(); // Not hashable, skipped
+        cm.registry_mut().set_hash::<u32>();
+        cm.registry_mut().set_hash::<String>();
+        let e1 = pool.take();
+        let e2 = pool.take();
+        cm.add(e1, 1u32);
+        cm.add(e2, 2u32);
+        cm.add(e1, String::from("one"));
+        cm.add(e1, 0.5f32);
+
+        let mut relations = RelationStore::new();
+        relations.register_named::<u64>("follows", crate::Cleanup::RemoveRelation);
+        let mut tags = Tags::new();
+
+        let first = hash_world(&pool, &cm, &relations, &tags);
+        assert_eq!(vec!["string", "u32"], first.components.keys().collect::<Vec<_>>());
+        assert_eq!(first, hash_world(&pool, &cm, &relations, &tags));
+
+        *cm.get_mut::<f32>(&e1).unwrap() = 1.5;
+        assert_eq!(first, hash_world(&pool, &cm, &relations, &tags));
+
+        *cm.get_mut::<u32>(&e2).unwrap() = 3;
+        let second = hash_world(&pool, &cm, &relations, &tags);
+        assert_ne!(first.total, second.total);
+        assert_eq!(first.entities, second.entities);
+        assert_eq!(vec!["u32"], first.mismatches(&second));
+
+        relations.add::<u64>(e1, e2);
+        let third = hash_world(&pool, &cm, &relations, &tags);
+        assert_ne!(second.total, third.total);
+        assert_ne!(second.relations, third.relations);
+        assert!(second.mismatches(&third).is_empty());
+
+        tags.add(e2, "hero");
+        let fourth = hash_world(&pool, &cm, &relations, &tags);
+        assert_ne!(third.total, fourth.total);
+        assert_ne!(third.tags, fourth.tags);
+
+        let e3 = pool.take();
+        let e4 = pool.take();
+        pool.back(e3);
+        pool.back(e4);
+        let fifth = hash_world(&pool, &cm, &relations, &tags);
+        let mut order: Vec<Entity> = pool.available_iter().copied().collect();
+        order.swap(0, 1);
+        pool.reorder_available(order);
+        let sixth = hash_world(&pool, &cm, &relations, &tags);
+        assert_ne!(fifth.entities, sixth.entities, "Same free ids in another order");
+        assert_eq!(fifth.components, sixth.components);
+    }
+}
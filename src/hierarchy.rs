@@ -0,0 +1,135 @@
+use crate::Entity;
+use crate::ComponentType;
+use crate::ComponentManager;
+#[cfg(feature = "snapshot")]
+use crate::snapshot::{Persist, SnapshotError};
+
+use std::fmt;
+
+// Both sides are kept in sync by Coordinator::set_parent/remove_parent, so they should not be
+// added or removed directly
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Parent(pub Entity);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Children(pub Vec<Entity>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HierarchyError {
+    Cycle { child: Entity, parent: Entity }, // parent is the child itself or its descendant
+    NotAlive(Entity),
+}
+
+impl fmt::Display for HierarchyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HierarchyError::Cycle { child, parent } => write!(f, "entity {} can't be a child of its own descendant {}", child, parent),
+            HierarchyError::NotAlive(e) => write!(f, "entity {} is not alive", e),
+        }
+    }
+}
+
+impl std::error::Error for HierarchyError {}
+
+#[cfg(feature = "snapshot")]
+impl Persist for Parent {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.0.write(out);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        Ok(Parent(Entity::read(input)?))
+    }
+}
+
+#[cfg(feature = "snapshot")]
+impl Persist for Children {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.0.write(out);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        Ok(Children(Vec::read(input)?))
+    }
+}
+
+pub fn parent_of(cm: &ComponentManager, e: Entity) -> Option<Entity> {
+    let parent = cm.get_any(e, ComponentType::of::<Parent>())?;
+    Some(parent.downcast_ref::<Parent>().unwrap().0)
+}
+
+pub fn children_of(cm: &ComponentManager, e: Entity) -> &[Entity] {
+    match cm.get_any(e, ComponentType::of::<Children>()) {
+        Some(children) => &children.downcast_ref::<Children>().unwrap().0,
+        None => &[],
+    }
+}
+
+// Parent first, up to the root
+pub struct Ancestors<'a> {
+    cm: &'a ComponentManager,
+    next: Option<Entity>,
+}
+
+impl<'a> Ancestors<'a> {
+    pub fn new(cm: &'a ComponentManager, e: Entity) -> Ancestors<'a> {
+        Ancestors { cm, next: parent_of(cm, e) }
+    }
+}
+
+impl Iterator for Ancestors<'_> {
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Entity> {
+        let e = self.next?;
+        self.next = parent_of(self.cm, e);
+        Some(e)
+    }
+}
+
+// Pre-order, starting with the root itself and visiting children in insertion order
+pub struct DepthFirst<'a> {
+    cm: &'a ComponentManager,
+    stack: Vec<Entity>,
+}
+
+impl<'a> DepthFirst<'a> {
+    pub fn new(cm: &'a ComponentManager, root: Entity) -> DepthFirst<'a> {
+        DepthFirst { cm, stack: vec![root] }
+    }
+}
+
+impl Iterator for DepthFirst<'_> {
+    type Item = Entity;
+
+    fn next(&mut self) -> Option<Entity> {
+        let e = self.stack.pop()?;
+        self.stack.extend(children_of(self.cm, e).iter().rev());
+        Some(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traversal() {
+        // 0 -> 1 -> 3
+        //   -> 2
+        let mut cm = ComponentManager::new();
+        cm.register::<Parent>();
+        cm.register::<Children>();
+        cm.add(0, Children(vec![1, 2]));
+        cm.add(1, Parent(0));
+        cm.add(1, Children(vec![3]));
+        cm.add(2, Parent(0));
+        cm.add(3, Parent(1));
+
+        assert_eq!(vec![1, 0], Ancestors::new(&cm, 3).collect::<Vec<_>>());
+        assert_eq!(0, Ancestors::new(&cm, 0).count());
+        assert_eq!(vec![0, 1, 3, 2], DepthFirst::new(&cm, 0).collect::<Vec<_>>());
+        assert_eq!(vec![1, 3], DepthFirst::new(&cm, 1).collect::<Vec<_>>());
+        assert_eq!(&[] as &[Entity], children_of(&cm, 2));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::Entity;
+use crate::component::AnyComponentArray;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::collections::HashMap;
+use std::ops::RangeBounds;
+use std::hash::Hash;
+use std::any::Any;
+
+// Component type which can be looked up by a key derived from its value, e.g. NetworkId by
+// its number or Team by itself
+pub trait Indexed: Any {
+    type Key: Hash + Ord + Clone + Any;
+
+    fn key(&self) -> Self::Key;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKind {
+    Hash,
+    Ordered, // also supports range queries
+}
+
+#[derive(Clone)]
+enum Buckets<K> {
+    Hash(HashMap<K, BTreeSet<Entity>>),
+    Ordered(BTreeMap<K, BTreeSet<Entity>>),
+}
+
+// Values handed out by ComponentManager::get_mut may be changed at any time later, so such
+// entities are only marked dirty and their keys are recomputed before the next query
+pub struct ValueIndex<T: Indexed> {
+    buckets: Buckets<T::Key>,
+    keys: HashMap<Entity, T::Key>,
+    dirty: HashSet<Entity>,
+}
+
+impl<T: Indexed> ValueIndex<T> {
+    pub fn new(kind: IndexKind) -> ValueIndex<T> {
+        let buckets = match kind {
+            IndexKind::Hash => Buckets::Hash(HashMap::new()),
+            IndexKind::Ordered => Buckets::Ordered(BTreeMap::new()),
+        };
+        ValueIndex { buckets, keys: HashMap::new(), dirty: HashSet::new() }
+    }
+
+    pub fn kind(&self) -> IndexKind {
+        match self.buckets {
+            Buckets::Hash(_) => IndexKind::Hash,
+            Buckets::Ordered(_) => IndexKind::Ordered,
+        }
+    }
+
+    // Ascending order
+    pub fn get(&self, key: &T::Key) -> Vec<Entity> {
+        let entities = match &self.buckets {
+            Buckets::Hash(buckets) => buckets.get(key),
+            Buckets::Ordered(buckets) => buckets.get(key),
+        };
+        entities.into_iter().flatten().copied().collect()
+    }
+
+    // Ordered by key, then by entity
+    pub fn range<R: RangeBounds<T::Key>>(&self, range: R) -> Vec<Entity> {
+        match &self.buckets {
+            Buckets::Ordered(buckets) => buckets.range(range).flat_map(|(_, entities)| entities.iter().copied()).collect(),
+            Buckets::Hash(_) => panic!("Range queries need an ordered index"),
+        }
+    }
+
+    // Priv
+
+    fn insert(&mut self, e: Entity, key: T::Key) {
+        self.remove(e);
+        match &mut self.buckets {
+            Buckets::Hash(buckets) => buckets.entry(key.clone()).or_default().insert(e),
+            Buckets::Ordered(buckets) => buckets.entry(key.clone()).or_default().insert(e),
+        };
+        self.keys.insert(e, key);
+    }
+
+    fn remove(&mut self, e: Entity) {
+        self.dirty.remove(&e);
+        let key = match self.keys.remove(&e) {
+            Some(key) => key,
+            None => return,
+        };
+        let emptied = match &mut self.buckets {
+            Buckets::Hash(buckets) => buckets.get_mut(&key).map(|entities| entities.remove(&e) && entities.is_empty()),
+            Buckets::Ordered(buckets) => buckets.get_mut(&key).map(|entities| entities.remove(&e) && entities.is_empty()),
+        };
+        if emptied == Some(true) {
+            match &mut self.buckets {
+                Buckets::Hash(buckets) => buckets.remove(&key),
+                Buckets::Ordered(buckets) => buckets.remove(&key),
+            };
+        }
+    }
+}
+
+impl<T: Indexed> Clone for ValueIndex<T> {
+    fn clone(&self) -> Self {
+        ValueIndex {
+            buckets: self.buckets.clone(),
+            keys: self.keys.clone(),
+            dirty: self.dirty.clone(),
+        }
+    }
+}
+
+pub trait AnyIndex {
+    fn as_any(&self) -> &dyn Any;
+    fn clone_box(&self) -> Box<dyn AnyIndex>;
+
+    fn update(&mut self, e: Entity, value: &dyn Any);
+    fn remove(&mut self, e: Entity);
+    fn mark_dirty(&mut self, e: Entity);
+    fn refresh(&mut self, array: &dyn AnyComponentArray);
+    fn rebuild(&mut self, array: &dyn AnyComponentArray);
+}
+
+impl<T: Indexed> AnyIndex for ValueIndex<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn AnyIndex> {
+        Box::new(self.clone())
+    }
+
+    fn update(&mut self, e: Entity, value: &dyn Any) {
+        self.insert(e, value.downcast_ref::<T>().unwrap().key());
+    }
+
+    fn remove(&mut self, e: Entity) {
+        ValueIndex::remove(self, e);
+    }
+
+    fn mark_dirty(&mut self, e: Entity) {
+        self.dirty.insert(e);
+    }
+
+    fn refresh(&mut self, array: &dyn AnyComponentArray) {
+        let dirty: Vec<Entity> = self.dirty.drain().collect();
+        for e in dirty {
+            match array.get_any(e) {
+                Some(value) => self.update(e, value),
+                None => ValueIndex::remove(self, e),
+            }
+        }
+    }
+
+    fn rebuild(&mut self, array: &dyn AnyComponentArray) {
+        *self = ValueIndex::new(self.kind());
+        for e in array.entities() {
+            self.update(e, array.get_any(e).unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ComponentArray;
+
+    #[derive(Clone, Copy)]
+    struct NetworkId(u32);
+
+    impl Indexed for NetworkId {
+        type Key = u32;
+
+        fn key(&self) -> u32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_value_index() {
+        let mut array: ComponentArray<NetworkId> = ComponentArray::new("id");
+        let mut index: ValueIndex<NetworkId> = ValueIndex::new(IndexKind::Ordered);
+        for (e, id) in [(1, 42), (2, 7), (3, 42)] {
+            array.add(e, NetworkId(id));
+            AnyIndex::update(&mut index, e, &NetworkId(id));
+        }
+        assert_eq!(vec![1, 3], index.get(&42));
+        assert_eq!(vec![2, 1, 3], index.range(..=42));
+
+        array.get_mut(&2).unwrap().0 = 50;
+        index.mark_dirty(2);
+        index.refresh(&array);
+        assert_eq!(vec![2], index.range(43..));
+        assert!(index.get(&7).is_empty());
+
+        array.remove(&1);
+        AnyIndex::remove(&mut index, 1);
+        assert_eq!(vec![3], index.get(&42));
+
+        index.rebuild(&array);
+        assert_eq!(vec![3, 2], index.range(..));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_hash_index_has_no_ranges() {
+        let index: ValueIndex<NetworkId> = ValueIndex::new(IndexKind::Hash);
+        index.range(1..);
+    }
+}
@@ -10,14 +10,83 @@ pub use coordinator::Coordinator;
 
 pub mod pool;
 pub use pool::EntitiesPool;
+pub use pool::EntityRef;
+pub use pool::Reuse;
+pub use pool::EntityReserver;
 
 pub mod component;
 pub use component::ComponentArray;
 pub use component::ComponentManager;
 
+pub mod index;
+pub use index::Indexed;
+pub use index::IndexKind;
+
+pub mod spatial;
+pub use spatial::Located;
+pub use spatial::SpatialGrid;
+
+pub mod registry;
+pub use registry::ComponentRegistry;
+pub use registry::ClonePolicy;
+
 pub mod system;
 pub use system::System;
 pub use system::SystemManager;
 
+pub mod signature;
+pub use signature::Signature;
+pub use signature::Disabled;
+
+pub mod bundle;
+pub use bundle::Bundle;
+
 pub mod globals;
 pub use globals::Globals;
+
+pub mod changes;
+pub use changes::Change;
+pub use changes::ChangeKind;
+
+pub mod observer;
+pub use observer::Observer;
+pub use observer::Trigger;
+
+pub mod rollback;
+pub use rollback::Tick;
+
+pub mod hash;
+pub use hash::StateHash;
+
+pub mod hierarchy;
+pub use hierarchy::Parent;
+pub use hierarchy::Children;
+pub use hierarchy::HierarchyError;
+
+pub mod names;
+pub use names::Name;
+
+pub mod prefab;
+pub use prefab::Prefab;
+
+pub mod relation;
+pub use relation::Cleanup;
+
+pub mod transform;
+pub use transform::Transform2D;
+pub use transform::Transform3D;
+pub use transform::TransformSystem;
+
+#[cfg(feature = "snapshot")]
+pub mod snapshot;
+#[cfg(feature = "snapshot")]
+pub use snapshot::Persist;
+#[cfg(feature = "snapshot")]
+pub mod delta;
+#[cfg(feature = "snapshot")]
+pub use delta::WorldState;
+
+#[cfg(feature = "scene")]
+pub mod scene;
+#[cfg(feature = "scene")]
+pub use scene::SceneComponent;
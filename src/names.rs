@@ -0,0 +1,346 @@
+use crate::Entity;
+use crate::ComponentType;
+use crate::Indexed;
+use crate::hash::StateHasher;
+#[cfg(feature = "snapshot")]
+use crate::snapshot::{Persist, SnapshotError};
+
+use std::collections::HashSet;
+use std::collections::HashMap;
+use std::any::Any;
+use std::hash::{Hash, Hasher};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Name(pub String);
+
+impl Name {
+    pub fn new(name: &str) -> Name {
+        Name(String::from(name))
+    }
+}
+
+#[cfg(feature = "snapshot")]
+impl Persist for Name {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.0.write(out);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        Ok(Name(String::read(input)?))
+    }
+}
+
+// Coordinator keeps a hash index on names, so lookups follow every kind of modification
+// (get_mut, systems, rollback, snapshots) like any other indexed component
+impl Indexed for Name {
+    type Key = String;
+
+    fn key(&self) -> String {
+        self.0.clone()
+    }
+}
+
+trait AnyTagSet {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn clone_box(&self) -> Box<dyn AnyTagSet>;
+    fn type_name(&self) -> &'static str;
+    fn is_empty(&self) -> bool;
+    fn pairs(&self) -> Vec<(Entity, &dyn Any)>;
+    fn hash(&self) -> u64;
+    fn contains_any(&self, e: Entity, tag: &dyn Any) -> bool;
+    fn add_any(&mut self, e: Entity, tag: Box<dyn Any>);
+    fn remove_any(&mut self, e: Entity, tag: &dyn Any) -> bool;
+    fn copy_entity(&mut self, from: Entity, to: Entity);
+    fn remove_entity(&mut self, e: Entity);
+    fn clear(&mut self);
+}
+
+impl<T: Hash + Eq + Clone> TagSet<T> {
+    fn new() -> TagSet<T> {
+        TagSet { by_tag: HashMap::new(), by_entity: HashMap::new() }
+    }
+
+    fn insert(&mut self, e: Entity, tag: T) {
+        self.by_entity.entry(e).or_default().insert(tag.clone());
+        self.by_tag.entry(tag).or_default().insert(e);
+    }
+
+    fn remove(&mut self, e: Entity, tag: &T) -> bool {
+        let removed = self.by_entity.get_mut(&e).is_some_and(|tags| tags.remove(tag));
+        if removed {
+            let entities = self.by_tag.get_mut(tag).unwrap();
+            entities.remove(&e);
+            if entities.is_empty() {
+                self.by_tag.remove(tag);
+            }
+        }
+        removed
+    }
+}
+
+#[derive(Clone)]
+struct TagSet<T> {
+    by_tag: HashMap<T, HashSet<Entity>>,
+    by_entity: HashMap<Entity, HashSet<T>>,
+}
+
+impl<T: Hash + Eq + Clone + Any> AnyTagSet for TagSet<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn AnyTagSet> {
+        Box::new(self.clone())
+    }
+
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.by_entity.is_empty()
+    }
+
+    fn pairs(&self) -> Vec<(Entity, &dyn Any)> {
+        self.by_entity.iter()
+            .flat_map(|(e, tags)| tags.iter().map(move |tag| (*e, tag as &dyn Any)))
+            .collect()
+    }
+
+    // Tags have no order, so pairs are hashed one by one and their hashes sorted
+    fn hash(&self) -> u64 {
+        let mut hashes: Vec<u64> = self.by_entity.iter()
+            .flat_map(|(e, tags)| tags.iter().map(move |tag| {
+                let mut hasher = StateHasher::new();
+                hasher.write_u32(*e);
+                tag.hash(&mut hasher);
+                hasher.finish()
+            }))
+            .collect();
+        hashes.sort_unstable();
+        let mut hasher = StateHasher::new();
+        for hash in hashes {
+            hasher.write_u64(hash);
+        }
+        hasher.finish()
+    }
+
+    fn contains_any(&self, e: Entity, tag: &dyn Any) -> bool {
+        let tag = tag.downcast_ref::<T>().unwrap();
+        self.by_entity.get(&e).is_some_and(|tags| tags.contains(tag))
+    }
+
+    fn add_any(&mut self, e: Entity, tag: Box<dyn Any>) {
+        self.insert(e, *tag.downcast::<T>().unwrap());
+    }
+
+    fn remove_any(&mut self, e: Entity, tag: &dyn Any) -> bool {
+        self.remove(e, tag.downcast_ref::<T>().unwrap())
+    }
+
+    fn copy_entity(&mut self, from: Entity, to: Entity) {
+        let tags: Vec<T> = self.by_entity.get(&from).into_iter().flatten().cloned().collect();
+        for tag in tags {
+            self.insert(to, tag);
+        }
+    }
+
+    fn remove_entity(&mut self, e: Entity) {
+        for tag in self.by_entity.remove(&e).unwrap_or_default() {
+            let entities = self.by_tag.get_mut(&tag).unwrap();
+            entities.remove(&e);
+            if entities.is_empty() {
+                self.by_tag.remove(&tag);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.by_tag.clear();
+        self.by_entity.clear();
+    }
+}
+
+// Tags are any hashable values, typically &'static str or fieldless enums. Entities carrying
+// a tag are kept in a set per tag value.
+pub struct Tags {
+    sets: HashMap<ComponentType, Box<dyn AnyTagSet>>,
+    empty: HashSet<Entity>,
+}
+
+impl Tags {
+    pub fn new() -> Tags {
+        Tags {
+            sets: HashMap::new(),
+            empty: HashSet::new(),
+        }
+    }
+
+    // Makes the tag type known before any tag of it is added, so tags loaded from outside
+    // (snapshots, deltas) can be put in place
+    pub fn register<T: Hash + Eq + Clone + Any>(&mut self) {
+        self.sets.entry(ComponentType::of::<T>()).or_insert_with(|| Box::new(TagSet::<T>::new()));
+    }
+
+    pub fn is_registered(&self, id: ComponentType) -> bool {
+        self.sets.contains_key(&id)
+    }
+
+    pub fn add<T: Hash + Eq + Clone + Any>(&mut self, e: Entity, tag: T) {
+        self.register::<T>();
+        self.set_mut::<T>().unwrap().insert(e, tag);
+    }
+
+    pub fn remove<T: Hash + Eq + Clone + Any>(&mut self, e: Entity, tag: &T) -> bool {
+        match self.set_mut::<T>() {
+            Some(set) => set.remove(e, tag),
+            None => false,
+        }
+    }
+
+    pub fn has<T: Hash + Eq + Clone + Any>(&self, e: Entity, tag: &T) -> bool {
+        self.tagged(tag).contains(&e)
+    }
+
+    pub fn tagged<T: Hash + Eq + Clone + Any>(&self, tag: &T) -> &HashSet<Entity> {
+        self.sets.get(&ComponentType::of::<T>())
+            .and_then(|set| set.as_any().downcast_ref::<TagSet<T>>().unwrap().by_tag.get(tag))
+            .unwrap_or(&self.empty)
+    }
+
+    // Type erased access, values are of the type id stands for. Adding and removing return
+    // false for unregistered tag types.
+    pub fn pairs(&self) -> Vec<(ComponentType, Entity, &dyn Any)> {
+        self.sets.iter()
+            .flat_map(|(id, set)| set.pairs().into_iter().map(move |(e, tag)| (*id, e, tag)))
+            .collect()
+    }
+
+    pub fn contains_any(&self, id: ComponentType, e: Entity, tag: &dyn Any) -> bool {
+        self.sets.get(&id).is_some_and(|set| set.contains_any(e, tag))
+    }
+
+    pub fn add_any(&mut self, id: ComponentType, e: Entity, tag: Box<dyn Any>) -> bool {
+        match self.sets.get_mut(&id) {
+            Some(set) => {
+                set.add_any(e, tag);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn remove_any(&mut self, id: ComponentType, e: Entity, tag: &dyn Any) -> bool {
+        self.sets.get_mut(&id).is_some_and(|set| set.remove_any(e, tag))
+    }
+
+    // Hash of every tag type having some tags, with the type name as a fallback name
+    pub fn hashes(&self) -> Vec<(ComponentType, &'static str, u64)> {
+        self.sets.iter()
+            .filter(|(_, set)| !set.is_empty())
+            .map(|(id, set)| (*id, set.type_name(), set.hash()))
+            .collect()
+    }
+
+    pub fn copy_entity(&mut self, from: Entity, to: Entity) {
+        for set in self.sets.values_mut() {
+            set.copy_entity(from, to);
+        }
+    }
+
+    pub fn remove_entity(&mut self, e: Entity) {
+        for set in self.sets.values_mut() {
+            set.remove_entity(e);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for set in self.sets.values_mut() {
+            set.clear();
+        }
+    }
+
+    // Priv
+
+    fn set_mut<T: Hash + Eq + Clone + Any>(&mut self) -> Option<&mut TagSet<T>> {
+        let set = self.sets.get_mut(&ComponentType::of::<T>())?;
+        set.as_any_mut().downcast_mut::<TagSet<T>>()
+    }
+}
+
+impl Clone for Tags {
+    fn clone(&self) -> Self {
+        Tags {
+            sets: self.sets.iter().map(|(id, set)| (*id, set.clone_box())).collect(),
+            empty: HashSet::new(),
+        }
+    }
+}
+
+impl Default for Tags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Eq, Hash)]
+    enum Team {
+        Red,
+        Blue,
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut tags = Tags::new();
+        tags.add(1, "enemy");
+        tags.add(2, "enemy");
+        tags.add(1, Team::Red);
+        tags.add(2, Team::Blue);
+
+        assert_eq!(&HashSet::from([1, 2]), tags.tagged(&"enemy"));
+        assert_eq!(&HashSet::from([1]), tags.tagged(&Team::Red));
+        assert!(tags.tagged(&"boss").is_empty());
+        assert!(tags.tagged(&7u8).is_empty(), "Unknown tag type");
+
+        assert!(tags.remove(2, &"enemy"));
+        assert!(!tags.remove(2, &"enemy"));
+        assert!(!tags.has(2, &"enemy"));
+
+        tags.remove_entity(1);
+        assert!(tags.tagged(&"enemy").is_empty());
+        assert!(tags.tagged(&Team::Red).is_empty());
+        assert!(tags.has(2, &Team::Blue));
+    }
+
+    #[test]
+    fn test_tags_type_erased() {
+        let mut tags = Tags::new();
+        let team = ComponentType::of::<Team>();
+        assert!(!tags.add_any(team, 1, Box::new(Team::Red)), "Team is not registered");
+        tags.register::<Team>();
+        assert!(tags.add_any(team, 1, Box::new(Team::Red)));
+        assert!(tags.has(1, &Team::Red));
+        assert!(tags.contains_any(team, 1, &Team::Red));
+
+        tags.copy_entity(1, 2);
+        assert_eq!(&HashSet::from([1, 2]), tags.tagged(&Team::Red));
+        assert_eq!(2, tags.pairs().len());
+        let before = tags.hashes();
+        assert!(tags.remove_any(team, 2, &Team::Red));
+        assert!(!tags.remove_any(team, 2, &Team::Red));
+        assert_ne!(before, tags.hashes());
+
+        let mut same = Tags::new();
+        same.add(1, Team::Red);
+        assert_eq!(tags.hashes(), same.hashes(), "Hash depends on content only");
+    }
+}
@@ -0,0 +1,154 @@
+use crate::Entity;
+use crate::ComponentType;
+use crate::Coordinator;
+use crate::changes::{Change, ChangeKind};
+use crate::registry::CloneError;
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Trigger {
+    OnAdd,
+    OnRemove,
+}
+
+pub trait Observer {
+    fn trigger(&self) -> Trigger;
+    fn component_type(&self) -> ComponentType;
+
+    fn on_trigger(&mut self, e: Entity, coordinator: &mut Coordinator);
+
+    // Independent copy for Coordinator::try_clone, see System::box_clone
+    fn box_clone(&self) -> Option<Rc<RefCell<dyn Observer>>> {
+        None
+    }
+}
+
+type ObserverRef = Rc<RefCell<dyn Observer>>;
+
+// Triggers are queued and only run once the change that caused them is complete, i.e. right
+// after a direct Coordinator call or after the whole deferred closure returned by a system.
+pub struct ObserverManager {
+    observers: HashMap<(ComponentType, Trigger), Vec<(ObserverRef, &'static str)>>, // with type name
+    pending: VecDeque<(Entity, ObserverRef)>,
+    hold: u32,
+}
+
+impl ObserverManager {
+    pub fn new() -> ObserverManager {
+        ObserverManager {
+            observers: HashMap::new(),
+            pending: VecDeque::new(),
+            hold: 0,
+        }
+    }
+
+    pub fn register<T: Observer + 'static>(&mut self, observer: Rc<RefCell<T>>) {
+        let key = {
+            let o = observer.borrow();
+            (o.component_type(), o.trigger())
+        };
+        self.observers.entry(key).or_default().push((observer, std::any::type_name::<T>()));
+    }
+
+    // Pending triggers are not copied
+    pub fn try_clone(&self) -> Result<ObserverManager, CloneError> {
+        let mut observers = HashMap::with_capacity(self.observers.len());
+        for (key, registered) in self.observers.iter() {
+            let mut copies = Vec::with_capacity(registered.len());
+            for (observer, name) in registered {
+                let copy = observer.borrow().box_clone().ok_or_else(|| CloneError::Observer(String::from(*name)))?;
+                copies.push((copy, *name));
+            }
+            observers.insert(*key, copies);
+        }
+        Ok(ObserverManager { observers, pending: VecDeque::new(), hold: 0 })
+    }
+
+    pub fn notify(&mut self, change: &Change) {
+        let trigger = match change.kind {
+            ChangeKind::Add => Trigger::OnAdd,
+            ChangeKind::Remove => Trigger::OnRemove,
+            ChangeKind::Spawn | ChangeKind::Despawn | ChangeKind::Relate | ChangeKind::Unrelate => return,
+        };
+        let component_type = match change.component_type {
+            Some(component_type) => component_type,
+            None => return,
+        };
+
+        if let Some(observers) = self.observers.get(&(component_type, trigger)) {
+            for (observer, _) in observers {
+                self.pending.push_back((change.entity, observer.clone()));
+            }
+        }
+    }
+
+    pub fn hold(&mut self) {
+        self.hold += 1;
+    }
+
+    pub fn release(&mut self) {
+        self.hold -= 1;
+    }
+
+    pub fn next_pending(&mut self) -> Option<(Entity, ObserverRef)> {
+        if self.hold > 0 {
+            return None;
+        }
+        self.pending.pop_front()
+    }
+}
+
+impl Default for ObserverManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct CountingObserver {
+        trigger: Trigger,
+        seen: Vec<Entity>,
+    }
+
+    impl Observer for CountingObserver {
+        fn trigger(&self) -> Trigger {
+            self.trigger
+        }
+
+        fn component_type(&self) -> ComponentType {
+            ComponentType::of::<u32>()
+        }
+
+        fn on_trigger(&mut self, e: Entity, _coordinator: &mut Coordinator) {
+            self.seen.push(e);
+        }
+    }
+
+    #[test]
+    fn test_observer_manager_queues_matching_changes() {
+        let mut om = ObserverManager::new();
+        let on_add = Rc::new(RefCell::new(CountingObserver { trigger: Trigger::OnAdd, seen: vec![] }));
+        om.register(on_add.clone());
+
+        om.notify(&Change::spawn(1));
+        om.notify(&Change::add(1, ComponentType::of::<i32>()));
+        om.notify(&Change::remove(1, ComponentType::of::<u32>()));
+        assert!(om.next_pending().is_none());
+
+        om.hold();
+        om.notify(&Change::add(2, ComponentType::of::<u32>()));
+        assert!(om.next_pending().is_none(), "Held manager should not hand out triggers");
+        om.release();
+
+        let (e, _) = om.next_pending().unwrap();
+        assert_eq!(2, e);
+        assert!(om.next_pending().is_none());
+    }
+}
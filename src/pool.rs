@@ -1,40 +1,271 @@
 use crate::MAX_ENTITIES;
 use crate::Entity;
 
+use std::collections::BTreeSet;
 use std::collections::HashSet;
-use std::collections::hash_set::Iter;
+use std::collections::VecDeque;
+use std::collections::btree_set;
+use std::collections::vec_deque;
+use std::rc::Rc;
+use std::cell::RefCell;
 
+// Weak handle to an entity, stays valid only as long as that exact entity is alive. Ids are
+// reused after despawn, the generation tells the incarnations apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EntityRef {
+    pub entity: Entity,
+    pub generation: u32,
+}
+
+// Order in which ids that went back are handed out again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reuse {
+    Fifo, // after every other free id, so ids are reused as late as possible
+    Lifo, // the most recently freed id first
+}
+
+// Ids are handed out from a free list, so the same sequence of takes and backs gives the same
+// ids every run. A fresh pool hands out 0, 1, 2, ... in both modes.
+#[derive(Clone)]
 pub struct EntitiesPool {
-    available: HashSet<Entity>,
-    taken: HashSet<Entity>,
+    available: VecDeque<Entity>, // front is taken next
+    taken: BTreeSet<Entity>,
+    generations: Vec<u32>, // bumped every time the entity goes back
+    reuse: Reuse,
 }
 
 impl EntitiesPool {
     pub fn new() -> EntitiesPool {
-        let mut available= HashSet::with_capacity(MAX_ENTITIES as usize);
-        for e in (0..MAX_ENTITIES).rev() {
-            available.insert(e);
+        EntitiesPool::with_capacity(MAX_ENTITIES)
+    }
+
+    pub fn with_capacity(max_entities: u32) -> EntitiesPool {
+        EntitiesPool::with_reuse(max_entities, Reuse::Fifo)
+    }
+
+    pub fn with_reuse(max_entities: u32, reuse: Reuse) -> EntitiesPool {
+        EntitiesPool {
+            available: (0..max_entities).collect(),
+            taken: BTreeSet::new(),
+            generations: vec![0; max_entities as usize],
+            reuse,
         }
-        let taken: HashSet<Entity> = HashSet::with_capacity(MAX_ENTITIES as usize);
+    }
+
+    pub fn reuse(&self) -> Reuse {
+        self.reuse
+    }
 
-        EntitiesPool { available, taken }
+    // Applies to ids going back from now on
+    pub fn set_reuse(&mut self, reuse: Reuse) {
+        self.reuse = reuse;
+    }
+
+    // Every id is free again and taken in ascending order as in a fresh pool. Generations keep
+    // increasing, taken ids go back first, so handles from before don't resolve to new entities.
+    pub fn reset(&mut self) {
+        for e in std::mem::take(&mut self.taken) {
+            self.generations[e as usize] = self.generations[e as usize].wrapping_add(1);
+        }
+        self.available = (0..self.generations.len() as Entity).collect();
     }
 
     pub fn take(&mut self) -> Entity {
-        let e = self.available.iter().next().unwrap().clone();
-        self.available.remove(&e);
+        let e = self.available.pop_front().unwrap();
         self.taken.insert(e);
         e
     }
 
     pub fn back(&mut self, e: Entity) {
-        self.available.insert(e);
-        self.taken.remove(&e);
+        if !self.taken.remove(&e) {
+            return;
+        }
+        self.generations[e as usize] = self.generations[e as usize].wrapping_add(1);
+        match self.reuse {
+            Reuse::Fifo => self.available.push_back(e),
+            Reuse::Lifo => self.available.push_front(e),
+        }
+    }
+
+    // None for entities which are not taken, ids out of capacity included
+    pub fn entity_ref(&self, e: Entity) -> Option<EntityRef> {
+        if !self.taken.contains(&e) {
+            return None;
+        }
+        Some(EntityRef { entity: e, generation: *self.generations.get(e as usize)? })
+    }
+
+    pub fn resolve(&self, handle: EntityRef) -> Option<Entity> {
+        let generation = self.generations.get(handle.entity as usize)?;
+        let alive = self.taken.contains(&handle.entity) && *generation == handle.generation;
+        alive.then_some(handle.entity)
+    }
+
+    // Takes exactly this entity, used when ids come from outside (e.g. a replicated world).
+    // Linear in the number of free ids.
+    pub fn take_entity(&mut self, e: Entity) -> bool {
+        match self.available.iter().position(|a| *a == e) {
+            Some(i) => {
+                self.available.remove(i);
+                self.taken.insert(e);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn is_available(&self, e: Entity) -> bool {
+        (e as usize) < self.generations.len() && !self.taken.contains(&e)
+    }
+
+    // All or nothing, in a single pass over the free ids
+    pub fn take_entities(&mut self, entities: &[Entity]) -> bool {
+        let wanted: HashSet<Entity> = entities.iter().copied().collect();
+        if wanted.len() != entities.len() || !wanted.iter().all(|e| self.is_available(*e)) {
+            return false;
+        }
+        self.available.retain(|e| !wanted.contains(e));
+        self.taken.extend(wanted);
+        true
     }
 
-    pub fn taken_iter(&self) -> Iter<'_, Entity> {
+    pub fn is_taken(&self, e: Entity) -> bool {
+        self.taken.contains(&e)
+    }
+
+    pub fn taken_iter(&self) -> btree_set::Iter<'_, Entity> {
         self.taken.iter()
     }
+
+    // In the order the ids will be taken
+    pub fn available_iter(&self) -> vec_deque::Iter<'_, Entity> {
+        self.available.iter()
+    }
+
+    // Puts the free ids in the given order. Pool is left untouched unless every free id is
+    // listed exactly once.
+    pub fn reorder_available(&mut self, order: Vec<Entity>) -> bool {
+        if order.len() != self.available.len() {
+            return false;
+        }
+        let mut seen = vec![false; self.generations.len()];
+        for e in order.iter() {
+            match seen.get_mut(*e as usize) {
+                Some(seen) if !*seen && !self.taken.contains(e) => *seen = true,
+                _ => return false,
+            }
+        }
+        self.available = order.into_iter().collect();
+        true
+    }
+
+    pub fn generations(&self) -> &[u32] {
+        &self.generations
+    }
+
+    // Available ids are taken in the given order, so this also seeds the pool. Every id below
+    // the capacity has to be either available or taken, exactly once, and there has to be
+    // a generation for each of them. Pool is left untouched otherwise.
+    pub fn restore(&mut self, available: Vec<Entity>, taken: Vec<Entity>, generations: Vec<u32>) -> bool {
+        let capacity = self.generations.len();
+        if generations.len() != capacity || available.len() + taken.len() != capacity {
+            return false;
+        }
+        let mut seen = vec![false; capacity];
+        for e in available.iter().chain(taken.iter()) {
+            match seen.get_mut(*e as usize) {
+                Some(seen) if !*seen => *seen = true,
+                _ => return false,
+            }
+        }
+
+        self.available = available.into_iter().collect();
+        self.taken = taken.into_iter().collect();
+        self.generations = generations;
+        true
+    }
+}
+
+impl Default for EntitiesPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Lent {
+    pool: EntitiesPool,
+    reserved: Vec<Entity>,
+}
+
+// Shared allocator for systems. The coordinator lends its pool for the time of apply, so ids
+// reserved there are taken right away and nobody else gets them. They become spawned entities
+// when the coordinator takes the pool back, before deferred updates run.
+#[derive(Clone, Default)]
+pub struct EntityReserver {
+    lent: Rc<RefCell<Option<Lent>>>,
+}
+
+impl EntityReserver {
+    pub fn new() -> EntityReserver {
+        EntityReserver::default()
+    }
+
+    // None once the pool runs out of ids, and outside of apply when no pool is lent
+    pub fn reserve(&self) -> Option<Entity> {
+        let mut lent = self.lent.borrow_mut();
+        let lent = lent.as_mut()?;
+        let e = lent.pool.available.pop_front()?;
+        lent.pool.taken.insert(e);
+        lent.reserved.push(e);
+        Some(e)
+    }
+
+    pub fn is_lent(&self) -> bool {
+        self.lent.borrow().is_some()
+    }
+
+    pub fn lend(&self, pool: EntitiesPool) {
+        *self.lent.borrow_mut() = Some(Lent { pool, reserved: Vec::new() });
+    }
+
+    // Pool and ids reserved meanwhile, in reservation order
+    pub fn give_back(&self) -> (EntitiesPool, Vec<Entity>) {
+        let lent = self.lent.borrow_mut().take().expect("Pool is not lent");
+        (lent.pool, lent.reserved)
+    }
+
+    pub(crate) fn lend_from<'a>(&self, owner: &'a mut EntitiesPool) -> Loan<'a> {
+        self.lend(std::mem::replace(owner, EntitiesPool::with_capacity(0)));
+        Loan { reserver: self.clone(), owner }
+    }
+}
+
+// Puts the lent pool back where it came from when dropped, also when a system panics. Ids
+// reserved by then go back as well, nobody got to see them spawned.
+pub(crate) struct Loan<'a> {
+    reserver: EntityReserver,
+    owner: &'a mut EntitiesPool,
+}
+
+impl Loan<'_> {
+    // Ids reserved meanwhile stay taken
+    pub(crate) fn finish(self) -> Vec<Entity> {
+        let (pool, reserved) = self.reserver.give_back();
+        *self.owner = pool;
+        reserved
+    }
+}
+
+impl Drop for Loan<'_> {
+    fn drop(&mut self) {
+        if self.reserver.is_lent() {
+            let (pool, reserved) = self.reserver.give_back();
+            *self.owner = pool;
+            for e in reserved {
+                self.owner.back(e);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -54,6 +285,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_pool_iter() {
         let mut ep = EntitiesPool::new();
         let mut taken: HashSet<Entity> = HashSet::new();
@@ -68,4 +300,123 @@ mod tests {
 
         assert_eq!(expected, taken);
     }
+
+    #[test]
+    fn test_pool_generations() {
+        let mut ep = EntitiesPool::with_capacity(1);
+        let e = ep.take();
+        let handle = ep.entity_ref(e).unwrap();
+        assert_eq!(Some(e), ep.resolve(handle));
+
+        ep.back(e);
+        assert_eq!(None, ep.resolve(handle));
+        assert_eq!(None, ep.entity_ref(e));
+        assert_eq!(e, ep.take(), "Only id is reused");
+        assert_eq!(None, ep.resolve(handle));
+        assert_eq!(Some(e), ep.resolve(ep.entity_ref(e).unwrap()));
+
+        assert_eq!(None, ep.entity_ref(7), "Out of capacity");
+        assert_eq!(None, ep.resolve(EntityRef { entity: 7, generation: 0 }));
+    }
+
+    #[test]
+    fn test_pool_take_entity() {
+        let mut ep = EntitiesPool::with_capacity(4);
+        assert!(ep.take_entity(2));
+        assert!(!ep.take_entity(2));
+        assert!(!ep.take_entity(4), "Entity out of capacity");
+        assert!(ep.is_taken(2));
+        assert!(!ep.available_iter().any(|e| *e == 2));
+
+        assert!(!ep.take_entities(&[0, 2]), "Entity 2 is taken already");
+        assert!(!ep.take_entities(&[0, 0]));
+        assert!(ep.is_available(0));
+        assert!(ep.take_entities(&[3, 0]));
+        assert_eq!(vec![1], ep.available_iter().copied().collect::<Vec<Entity>>());
+    }
+
+    #[test]
+    fn test_pool_reuse_order() {
+        let mut fifo = EntitiesPool::with_capacity(4);
+        let mut lifo = EntitiesPool::with_reuse(4, Reuse::Lifo);
+        for pool in [&mut fifo, &mut lifo] {
+            assert_eq!(vec![0, 1, 2], (0..3).map(|_| pool.take()).collect::<Vec<Entity>>());
+            pool.back(1);
+            pool.back(0);
+            pool.back(0); // Already back, ignored
+        }
+        assert_eq!(vec![3, 1, 0], (0..3).map(|_| fifo.take()).collect::<Vec<Entity>>());
+        assert_eq!(vec![0, 1, 3], (0..3).map(|_| lifo.take()).collect::<Vec<Entity>>());
+    }
+
+    #[test]
+    fn test_pool_reset_and_seed() {
+        let mut ep = EntitiesPool::with_capacity(4);
+        let e = ep.take();
+        let handle = ep.entity_ref(e).unwrap();
+        ep.back(e);
+        let e = ep.take();
+        let second = ep.entity_ref(e).unwrap();
+        ep.reset();
+        assert_eq!(0, ep.take());
+        assert_eq!(None, ep.resolve(handle));
+        assert_eq!(None, ep.resolve(second), "Generations keep increasing");
+        assert_eq!(Some(0), ep.resolve(EntityRef { entity: 0, generation: 1 }));
+        assert_eq!(vec![1, 2, 3], ep.available_iter().copied().collect::<Vec<Entity>>());
+
+        assert!(!ep.reorder_available(vec![3, 2]), "Entity 1 is missing");
+        assert!(!ep.reorder_available(vec![3, 2, 0]), "Entity 0 is taken");
+        assert!(!ep.reorder_available(vec![3, 3, 1]));
+        assert!(ep.reorder_available(vec![3, 1, 2]));
+        assert_eq!(3, ep.take());
+
+        let generations = ep.generations().to_vec();
+        assert!(!ep.restore(vec![2, 3], vec![0], generations.clone()), "Entity 1 is missing");
+        assert!(!ep.restore(vec![2, 3, 1], vec![0, 1], generations.clone()), "Entity 1 is twice");
+        assert!(!ep.restore(vec![2, 3, 4], vec![0], generations.clone()), "Entity 4 is out of capacity");
+        assert!(!ep.restore(vec![2, 3, 1], vec![0], vec![0]));
+        assert!(ep.restore(vec![2, 3, 1], vec![0], vec![5, 0, 0, 0]));
+        assert_eq!(Some(0), ep.resolve(EntityRef { entity: 0, generation: 5 }));
+        assert_eq!(vec![2, 3, 1], ep.available_iter().copied().collect::<Vec<Entity>>());
+        assert_eq!(2, ep.take());
+    }
+
+    #[test]
+    fn test_reserver() {
+        let reserver = EntityReserver::new();
+        assert!(!reserver.is_lent());
+        reserver.lend(EntitiesPool::with_capacity(4));
+        let shared = reserver.clone();
+        assert_eq!(Some(0), reserver.reserve());
+        assert_eq!(Some(1), shared.reserve());
+
+        let (mut pool, reserved) = reserver.give_back();
+        assert!(!shared.is_lent());
+        assert_eq!(vec![0, 1], reserved);
+        assert!(pool.is_taken(1));
+        assert_eq!(2, pool.take());
+
+        reserver.lend(pool);
+        assert_eq!(Some(3), reserver.reserve());
+        assert_eq!(None, reserver.reserve(), "Out of ids");
+        let (pool, reserved) = reserver.give_back();
+        assert_eq!((vec![3], 4), (reserved, pool.taken_iter().count()));
+
+        let mut owner = EntitiesPool::with_capacity(4);
+        let kept = reserver.lend_from(&mut owner);
+        assert_eq!(Some(0), reserver.reserve());
+        assert_eq!(vec![0], kept.finish());
+        assert!(owner.is_taken(0));
+        let dropped = reserver.lend_from(&mut owner);
+        assert_eq!(Some(1), reserver.reserve());
+        drop(dropped);
+        assert!(!reserver.is_lent());
+        assert!(!owner.is_taken(1), "Reserved ids go back unless the loan is finished");
+        assert_eq!(vec![2, 3, 1], owner.available_iter().copied().collect::<Vec<Entity>>());
+    }
+
+    #[test]
+    fn test_reserve_outside_apply() {
+        assert_eq!(None, EntityReserver::new().reserve());
+    }
 }
@@ -0,0 +1,120 @@
+use crate::ComponentType;
+use crate::ComponentRegistry;
+use crate::registry::CloneError;
+
+use std::any::Any;
+use std::rc::Rc;
+
+// Template for spawning entities. Component values are copied into every instance with clone
+// functions from the registry, children are instantiated as child entities. Children are
+// shared, so one prefab can be nested in many others.
+pub struct Prefab {
+    components: Vec<(ComponentType, Box<dyn Any>)>,
+    children: Vec<Rc<Prefab>>,
+}
+
+impl Prefab {
+    pub fn new() -> Prefab {
+        Prefab {
+            components: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    // Replaces value of the same type if there is one already
+    pub fn with<T: Any>(self, component: T) -> Prefab {
+        self.with_boxed(ComponentType::of::<T>(), Box::new(component))
+    }
+
+    pub fn with_boxed(mut self, id: ComponentType, component: Box<dyn Any>) -> Prefab {
+        self.components.retain(|(other, _)| *other != id);
+        self.components.push((id, component));
+        self
+    }
+
+    pub fn with_child(mut self, child: Rc<Prefab>) -> Prefab {
+        self.children.push(child);
+        self
+    }
+
+    pub fn component_types(&self) -> impl Iterator<Item = ComponentType> + '_ {
+        self.components.iter().map(|(id, _)| *id)
+    }
+
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        let (_, value) = self.components.iter().find(|(id, _)| *id == ComponentType::of::<T>())?;
+        value.downcast_ref::<T>()
+    }
+
+    pub fn children(&self) -> &[Rc<Prefab>] {
+        &self.children
+    }
+
+    // Copies every value of the whole tree up front, so a missing clone function is found
+    // before anything is spawned
+    pub fn clone_values(&self, registry: &ComponentRegistry) -> Result<Instance, CloneError> {
+        let mut components = Vec::with_capacity(self.components.len());
+        for (id, value) in self.components.iter() {
+            let name = || CloneError::Component(String::from(registry.name_of(*id).unwrap_or_default()));
+            components.push((*id, registry.clone_value(*id, value.as_ref()).ok_or_else(name)?));
+        }
+
+        let children = self.children.iter()
+            .map(|child| child.clone_values(registry))
+            .collect::<Result<Vec<Instance>, CloneError>>()?;
+        Ok(Instance { components, children })
+    }
+}
+
+impl Default for Prefab {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Values for a single instantiation of a prefab
+pub struct Instance {
+    pub components: Vec<(ComponentType, Box<dyn Any>)>,
+    pub children: Vec<Instance>,
+}
+
+impl Instance {
+    // Entities the whole tree spawns
+    pub fn count(&self) -> usize {
+        1 + self.children.iter().map(Instance::count).sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Health(u32);
+
+    #[test]
+    fn test_prefab_values() {
+        let part = Rc::new(Prefab::new().with(Health(1)));
+        let prefab = Prefab::new()
+            .with(Health(10))
+            .with(Health(20))
+            .with(1u8)
+            .with_child(part.clone())
+            .with_child(part);
+        assert_eq!(Some(&Health(20)), prefab.get::<Health>());
+        assert_eq!(2, prefab.component_types().count());
+
+        let mut registry = ComponentRegistry::new();
+        registry.register::<Health>("health");
+        registry.set_clone::<Health>();
+        registry.register::<u8>("level");
+        assert_eq!(Some(CloneError::Component(String::from("level"))), prefab.clone_values(&registry).err());
+
+        registry.set_clone::<u8>();
+        let instance = prefab.clone_values(&registry).unwrap();
+        assert_eq!(2, instance.components.len());
+        assert_eq!(2, instance.children.len());
+        assert_eq!(3, instance.count());
+        assert_eq!(Some(&Health(1)), instance.children[1].components[0].1.downcast_ref::<Health>());
+    }
+}
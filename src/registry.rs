@@ -0,0 +1,277 @@
+use crate::Entity;
+use crate::ComponentType;
+#[cfg(feature = "snapshot")]
+use crate::snapshot::{Persist, Serializer};
+
+use std::collections::HashMap;
+use std::any::Any;
+use std::hash::{Hash, Hasher};
+use std::fmt;
+
+type DebugFn = fn(&dyn Any, &mut fmt::Formatter<'_>) -> fmt::Result;
+type CloneFn = fn(&dyn Any) -> Box<dyn Any>;
+type HashFn = fn(&dyn Any, &mut dyn Hasher);
+
+// Per type metadata. Name is stable (used in saves, tools and scripts), type_name is only
+// informative and may change between compiler versions. Optional entries are filled in by
+// set_debug/set_clone/set_default/set_hash/set_serializer for types supporting them.
+#[derive(Clone)]
+pub struct ComponentInfo {
+    pub name: String,
+    pub type_name: &'static str,
+    pub size: usize,
+    pub align: usize,
+    pub debug: Option<DebugFn>,
+    pub clone: Option<CloneFn>,
+    pub default: Option<fn() -> Box<dyn Any>>,
+    pub hash: Option<HashFn>,
+    #[cfg(feature = "snapshot")]
+    pub serializer: Option<Serializer>,
+}
+
+impl ComponentInfo {
+    fn of<T: Any>(name: &str) -> ComponentInfo {
+        ComponentInfo {
+            name: String::from(name),
+            type_name: std::any::type_name::<T>(),
+            size: std::mem::size_of::<T>(),
+            align: std::mem::align_of::<T>(),
+            debug: None,
+            clone: None,
+            default: None,
+            hash: None,
+            #[cfg(feature = "snapshot")]
+            serializer: None,
+        }
+    }
+}
+
+fn debug_erased<T: fmt::Debug + Any>(value: &dyn Any, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    value.downcast_ref::<T>().unwrap().fmt(f)
+}
+
+fn clone_erased<T: Clone + Any>(value: &dyn Any) -> Box<dyn Any> {
+    Box::new(value.downcast_ref::<T>().unwrap().clone())
+}
+
+fn default_erased<T: Default + Any>() -> Box<dyn Any> {
+    Box::new(T::default())
+}
+
+fn hash_erased<T: Hash + Any>(value: &dyn Any, mut state: &mut dyn Hasher) {
+    value.downcast_ref::<T>().unwrap().hash(&mut state);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloneError {
+    Component(String), // registered name of the component type
+    Global(String),    // key of the global
+    System(String),    // type name of the system
+    Observer(String),  // type name of the observer
+    OutOfEntities { needed: usize, available: usize },
+    NotAlive(Entity),  // entity to copy
+}
+
+impl fmt::Display for CloneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloneError::Component(name) => write!(f, "component '{}' has no clone function", name),
+            CloneError::Global(key) => write!(f, "global '{}' has no clone function", key),
+            CloneError::System(name) => write!(f, "system '{}' can't be cloned", name),
+            CloneError::Observer(name) => write!(f, "observer '{}' can't be cloned", name),
+            CloneError::OutOfEntities { needed, available } => write!(f, "{} entities don't fit into {} free entities", needed, available),
+            CloneError::NotAlive(e) => write!(f, "entity {} is not alive", e),
+        }
+    }
+}
+
+impl std::error::Error for CloneError {}
+
+// What to do with a component type without clone function when copying an entity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClonePolicy {
+    Skip,
+    Error,
+}
+
+struct DebugValue<'a> {
+    value: &'a dyn Any,
+    debug: DebugFn,
+}
+
+impl fmt::Debug for DebugValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.debug)(self.value, f)
+    }
+}
+
+#[derive(Clone)]
+pub struct ComponentRegistry {
+    infos: HashMap<ComponentType, ComponentInfo>,
+    by_name: HashMap<String, ComponentType>,
+}
+
+impl ComponentRegistry {
+    pub fn new() -> ComponentRegistry {
+        ComponentRegistry {
+            infos: HashMap::new(),
+            by_name: HashMap::new(),
+        }
+    }
+
+    // Adds the type or renames it if it is already known
+    pub fn register<T: Any>(&mut self, name: &str) {
+        let id = ComponentType::of::<T>();
+        if let Some(other) = self.by_name.get(name) {
+            if *other != id {
+                panic!("Component name '{}' is already taken by other type", name);
+            }
+            return;
+        }
+
+        let info = self.infos.entry(id).or_insert_with(|| ComponentInfo::of::<T>(name));
+        self.by_name.remove(&info.name);
+        info.name = String::from(name);
+        self.by_name.insert(String::from(name), id);
+    }
+
+    pub fn info(&self, id: ComponentType) -> Option<&ComponentInfo> {
+        self.infos.get(&id)
+    }
+
+    pub fn info_of<T: Any>(&self) -> Option<&ComponentInfo> {
+        self.info(ComponentType::of::<T>())
+    }
+
+    pub fn name_of(&self, id: ComponentType) -> Option<&str> {
+        self.infos.get(&id).map(|info| info.name.as_str())
+    }
+
+    pub fn type_of(&self, name: &str) -> Option<ComponentType> {
+        self.by_name.get(name).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ComponentType, &ComponentInfo)> {
+        self.infos.iter().map(|(id, info)| (*id, info))
+    }
+
+    pub fn set_debug<T: fmt::Debug + Any>(&mut self) {
+        self.entry::<T>().debug = Some(debug_erased::<T>);
+    }
+
+    pub fn set_clone<T: Clone + Any>(&mut self) {
+        self.entry::<T>().clone = Some(clone_erased::<T>);
+    }
+
+    pub fn set_default<T: Default + Any>(&mut self) {
+        self.entry::<T>().default = Some(default_erased::<T>);
+    }
+
+    // Hash has to be deterministic, i.e. must not depend on addresses or HashMap order
+    pub fn set_hash<T: Hash + Any>(&mut self) {
+        self.entry::<T>().hash = Some(hash_erased::<T>);
+    }
+
+    #[cfg(feature = "snapshot")]
+    pub fn set_serializer<T: Persist + Any>(&mut self) {
+        self.entry::<T>().serializer = Some(Serializer::of::<T>());
+    }
+
+    pub fn debug_string(&self, id: ComponentType, value: &dyn Any) -> Option<String> {
+        let debug = self.infos.get(&id)?.debug?;
+        Some(format!("{:?}", DebugValue { value, debug }))
+    }
+
+    pub fn clone_value(&self, id: ComponentType, value: &dyn Any) -> Option<Box<dyn Any>> {
+        let clone = self.infos.get(&id)?.clone?;
+        Some(clone(value))
+    }
+
+    pub fn default_value(&self, id: ComponentType) -> Option<Box<dyn Any>> {
+        let default = self.infos.get(&id)?.default?;
+        Some(default())
+    }
+
+    pub fn hash_value(&self, id: ComponentType, value: &dyn Any, state: &mut dyn Hasher) -> bool {
+        match self.infos.get(&id).and_then(|info| info.hash) {
+            Some(hash) => {
+                hash(value, state);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Priv
+
+    fn entry<T: Any>(&mut self) -> &mut ComponentInfo {
+        let id = ComponentType::of::<T>();
+        if !self.infos.contains_key(&id) {
+            self.register::<T>(std::any::type_name::<T>());
+        }
+        self.infos.get_mut(&id).unwrap()
+    }
+}
+
+impl Default for ComponentRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, Default, PartialEq)]
+    struct Health {
+        hp: u16,
+    }
+
+    #[test]
+    fn test_registry_metadata() {
+        let mut r = ComponentRegistry::new();
+        r.register::<Health>("health");
+
+        let id = ComponentType::of::<Health>();
+        let info = r.info(id).unwrap();
+        assert_eq!("health", info.name);
+        assert!(info.type_name.ends_with("Health"));
+        assert_eq!((2, 2), (info.size, info.align));
+        assert_eq!(Some(id), r.type_of("health"));
+
+        r.register::<Health>("hp");
+        assert_eq!(None, r.type_of("health"));
+        assert_eq!(Some("hp"), r.name_of(id));
+        assert_eq!(1, r.iter().count());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_registry_names_are_unique() {
+        let mut r = ComponentRegistry::new();
+        r.register::<Health>("health");
+        r.register::<u32>("health");
+    }
+
+    #[test]
+    fn test_registry_vtable() {
+        let mut r = ComponentRegistry::new();
+        let id = ComponentType::of::<Health>();
+        let value = Health { hp: 7 };
+
+        assert_eq!(None, r.debug_string(id, &value));
+        assert!(r.clone_value(id, &value).is_none());
+
+        r.set_debug::<Health>();
+        r.set_clone::<Health>();
+        r.set_default::<Health>();
+        assert_eq!(Some(std::any::type_name::<Health>()), r.name_of(id), "Unregistered type is named after its type");
+
+        assert_eq!(Some(String::from("Health { hp: 7 }")), r.debug_string(id, &value));
+        let cloned = r.clone_value(id, &value).unwrap();
+        assert_eq!(Some(&value), cloned.downcast_ref::<Health>());
+        let default = r.default_value(id).unwrap();
+        assert_eq!(Some(&Health::default()), default.downcast_ref::<Health>());
+    }
+}
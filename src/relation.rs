@@ -0,0 +1,280 @@
+use crate::Entity;
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::any::{Any, TypeId};
+
+// What happens to (source, target) pairs when the target is despawned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cleanup {
+    RemoveRelation,
+    DespawnSource,
+    Keep, // pair stays, pointing to a dead entity
+}
+
+#[derive(Clone)]
+struct RelationTable {
+    name: String, // snapshots, deltas and hashes refer to the relation by it
+    cleanup: Cleanup,
+    forward: HashMap<Entity, BTreeSet<Entity>>, // source -> targets
+    reverse: HashMap<Entity, BTreeSet<Entity>>, // target -> sources
+}
+
+impl RelationTable {
+    fn unlink(&mut self, source: Entity, target: Entity) -> bool {
+        let removed = remove_from(&mut self.forward, source, target);
+        remove_from(&mut self.reverse, target, source);
+        removed
+    }
+}
+
+fn remove_from(map: &mut HashMap<Entity, BTreeSet<Entity>>, key: Entity, value: Entity) -> bool {
+    let set = match map.get_mut(&key) {
+        Some(set) => set,
+        None => return false,
+    };
+    let removed = set.remove(&value);
+    if set.is_empty() {
+        map.remove(&key);
+    }
+    removed
+}
+
+// Relations are plain marker types (struct Likes;), any type can be one once registered.
+// Both directions are indexed, so targets of a source and sources of a target are cheap.
+#[derive(Clone)]
+pub struct RelationStore {
+    tables: HashMap<TypeId, RelationTable>,
+}
+
+impl RelationStore {
+    pub fn new() -> RelationStore {
+        RelationStore {
+            tables: HashMap::new(),
+        }
+    }
+
+    pub fn register<R: Any>(&mut self, cleanup: Cleanup) {
+        self.register_named::<R>(std::any::type_name::<R>(), cleanup);
+    }
+
+    pub fn register_named<R: Any>(&mut self, name: &str, cleanup: Cleanup) {
+        if self.type_of(name).is_some_and(|other| other != TypeId::of::<R>()) {
+            panic!("Relation name '{}' is already taken by other type", name);
+        }
+        let table = self.tables.entry(TypeId::of::<R>()).or_insert_with(|| RelationTable {
+            name: String::from(name),
+            cleanup,
+            forward: HashMap::new(),
+            reverse: HashMap::new(),
+        });
+        table.name = String::from(name);
+        table.cleanup = cleanup;
+    }
+
+    pub fn is_registered(&self, relation: TypeId) -> bool {
+        self.tables.contains_key(&relation)
+    }
+
+    pub fn name_of(&self, relation: TypeId) -> Option<&str> {
+        Some(self.tables.get(&relation)?.name.as_str())
+    }
+
+    pub fn type_of(&self, name: &str) -> Option<TypeId> {
+        self.tables.iter().find(|(_, table)| table.name == name).map(|(id, _)| *id)
+    }
+
+    // Returns false if the pair was there already
+    pub fn add<R: Any>(&mut self, source: Entity, target: Entity) -> bool {
+        self.table_mut::<R>();
+        self.add_pair(TypeId::of::<R>(), source, target)
+    }
+
+    pub fn remove<R: Any>(&mut self, source: Entity, target: Entity) -> bool {
+        self.table_mut::<R>().unlink(source, target)
+    }
+
+    // Type erased add and remove, false also for unregistered relations
+    pub fn add_pair(&mut self, relation: TypeId, source: Entity, target: Entity) -> bool {
+        let table = match self.tables.get_mut(&relation) {
+            Some(table) => table,
+            None => return false,
+        };
+        table.reverse.entry(target).or_default().insert(source);
+        table.forward.entry(source).or_default().insert(target)
+    }
+
+    pub fn remove_pair(&mut self, relation: TypeId, source: Entity, target: Entity) -> bool {
+        match self.tables.get_mut(&relation) {
+            Some(table) => table.unlink(source, target),
+            None => false,
+        }
+    }
+
+    // Every (relation, source, target), sorted by relation name and then by entities
+    pub fn pairs(&self) -> Vec<(&str, TypeId, Entity, Entity)> {
+        let mut pairs: Vec<(&str, TypeId, Entity, Entity)> = self.tables.iter()
+            .flat_map(|(id, table)| table.forward.iter().flat_map(move |(source, targets)| {
+                targets.iter().map(move |target| (table.name.as_str(), *id, *source, *target))
+            }))
+            .collect();
+        pairs.sort_unstable_by(|a, b| (a.0, a.2, a.3).cmp(&(b.0, b.2, b.3)));
+        pairs
+    }
+
+    pub fn contains<R: Any>(&self, source: Entity, target: Entity) -> bool {
+        self.targets::<R>(source).any(|t| t == target)
+    }
+
+    pub fn contains_pair(&self, relation: TypeId, source: Entity, target: Entity) -> bool {
+        self.tables.get(&relation)
+            .and_then(|table| table.forward.get(&source))
+            .is_some_and(|targets| targets.contains(&target))
+    }
+
+    // Ascending order
+    pub fn targets<R: Any>(&self, source: Entity) -> impl Iterator<Item = Entity> + '_ {
+        self.table::<R>().forward.get(&source).into_iter().flatten().copied()
+    }
+
+    // Ascending order
+    pub fn sources<R: Any>(&self, target: Entity) -> impl Iterator<Item = Entity> + '_ {
+        self.table::<R>().reverse.get(&target).into_iter().flatten().copied()
+    }
+
+    // Drops every pair with e as source and applies cleanup policies to pairs with e as
+    // target. Dropped pairs are appended to unlinked. Returns sources which should be
+    // despawned as well.
+    pub fn remove_entity(&mut self, e: Entity, unlinked: &mut Vec<(TypeId, Entity, Entity)>) -> Vec<Entity> {
+        let mut despawn = Vec::new();
+        for (id, table) in self.tables.iter_mut() {
+            for target in table.forward.remove(&e).unwrap_or_default() {
+                remove_from(&mut table.reverse, target, e);
+                unlinked.push((*id, e, target));
+            }
+
+            if table.cleanup == Cleanup::Keep {
+                continue;
+            }
+            for source in table.reverse.remove(&e).unwrap_or_default() {
+                remove_from(&mut table.forward, source, e);
+                unlinked.push((*id, source, e));
+                if table.cleanup == Cleanup::DespawnSource {
+                    despawn.push(source);
+                }
+            }
+        }
+        despawn.sort_unstable();
+        despawn.dedup();
+        despawn
+    }
+
+    pub fn clear(&mut self) {
+        for table in self.tables.values_mut() {
+            table.forward.clear();
+            table.reverse.clear();
+        }
+    }
+
+    // Priv
+
+    fn table<R: Any>(&self) -> &RelationTable {
+        match self.tables.get(&TypeId::of::<R>()) {
+            Some(table) => table,
+            None => panic!("Relation type shoud be registered prior to its use"),
+        }
+    }
+
+    fn table_mut<R: Any>(&mut self) -> &mut RelationTable {
+        match self.tables.get_mut(&TypeId::of::<R>()) {
+            Some(table) => table,
+            None => panic!("Relation type shoud be registered prior to its use"),
+        }
+    }
+}
+
+impl Default for RelationStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Likes;
+    struct OwnedBy;
+    struct Targets;
+
+    #[test]
+    fn test_relation_queries() {
+        let mut rs = RelationStore::new();
+        rs.register::<Likes>(Cleanup::RemoveRelation);
+        rs.add::<Likes>(1, 10);
+        rs.add::<Likes>(2, 10);
+        rs.add::<Likes>(1, 20);
+
+        assert_eq!(vec![10, 20], rs.targets::<Likes>(1).collect::<Vec<_>>());
+        assert_eq!(vec![1, 2], rs.sources::<Likes>(10).collect::<Vec<_>>());
+        assert!(rs.contains::<Likes>(2, 10));
+
+        assert!(rs.remove::<Likes>(2, 10));
+        assert!(!rs.remove::<Likes>(2, 10));
+        assert_eq!(vec![1], rs.sources::<Likes>(10).collect::<Vec<_>>());
+        assert!(!rs.add::<Likes>(1, 10), "Pair is there already");
+    }
+
+    #[test]
+    fn test_pairs_by_name() {
+        let mut rs = RelationStore::new();
+        rs.register_named::<Likes>("likes", Cleanup::RemoveRelation);
+        rs.register_named::<OwnedBy>("owned_by", Cleanup::DespawnSource);
+        rs.add::<OwnedBy>(3, 1);
+        rs.add::<Likes>(2, 1);
+        rs.add::<Likes>(1, 2);
+
+        let likes = TypeId::of::<Likes>();
+        assert_eq!(Some(likes), rs.type_of("likes"));
+        assert_eq!(Some("owned_by"), rs.name_of(TypeId::of::<OwnedBy>()));
+        assert_eq!(vec![("likes", likes, 1, 2), ("likes", likes, 2, 1), ("owned_by", TypeId::of::<OwnedBy>(), 3, 1)], rs.pairs());
+
+        assert!(rs.remove_pair(likes, 2, 1));
+        assert!(!rs.add_pair(TypeId::of::<Targets>(), 1, 2), "Targets is not registered");
+        assert_eq!(2, rs.pairs().len());
+
+        rs.register_named::<Likes>("likes", Cleanup::DespawnSource); // Same type, only policy changes
+        assert_eq!(Some(likes), rs.type_of("likes"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_relation_names_are_unique() {
+        let mut rs = RelationStore::new();
+        rs.register_named::<Likes>("likes", Cleanup::RemoveRelation);
+        rs.register_named::<OwnedBy>("likes", Cleanup::RemoveRelation);
+    }
+
+    #[test]
+    fn test_cleanup_policies() {
+        let mut rs = RelationStore::new();
+        rs.register::<Likes>(Cleanup::RemoveRelation);
+        rs.register::<OwnedBy>(Cleanup::DespawnSource);
+        rs.register::<Targets>(Cleanup::Keep);
+        rs.add::<Likes>(1, 10);
+        rs.add::<OwnedBy>(2, 10);
+        rs.add::<OwnedBy>(3, 10);
+        rs.add::<Targets>(4, 10);
+        rs.add::<Likes>(10, 5);
+
+        let mut unlinked = Vec::new();
+        assert_eq!(vec![2, 3], rs.remove_entity(10, &mut unlinked));
+        assert_eq!(4, unlinked.len());
+        assert!(unlinked.contains(&(TypeId::of::<Likes>(), 10, 5)));
+        assert!(!unlinked.contains(&(TypeId::of::<Targets>(), 4, 10)), "Kept pair is not unlinked");
+        assert_eq!(0, rs.targets::<Likes>(1).count());
+        assert_eq!(0, rs.sources::<Likes>(5).count(), "Pairs with despawned source are always removed");
+        assert_eq!(0, rs.targets::<OwnedBy>(2).count());
+        assert_eq!(vec![10], rs.targets::<Targets>(4).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::EntitiesPool;
+use crate::Globals;
+use crate::component::Checkpoint;
+use crate::relation::RelationStore;
+use crate::names::Tags;
+
+use std::collections::VecDeque;
+use std::fmt;
+
+pub type Tick = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RollbackError {
+    UnknownTick(Tick), // never saved or already dropped from the buffer
+}
+
+impl fmt::Display for RollbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RollbackError::UnknownTick(tick) => write!(f, "tick {} is not in the rollback buffer", tick),
+        }
+    }
+}
+
+impl std::error::Error for RollbackError {}
+
+pub struct SavedTick {
+    pub tick: Tick,
+    pub pool: EntitiesPool,
+    pub components: Checkpoint,
+    pub globals: Globals,
+    pub relations: RelationStore,
+    pub tags: Tags,
+}
+
+// Keeps last `capacity` saved ticks, the oldest one is dropped when a new one doesn't fit
+pub struct RollbackBuffer {
+    capacity: usize,
+    saved: VecDeque<SavedTick>,
+    next_tick: Tick,
+}
+
+impl RollbackBuffer {
+    pub fn new(capacity: usize) -> RollbackBuffer {
+        RollbackBuffer {
+            capacity,
+            saved: VecDeque::with_capacity(capacity),
+            next_tick: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.saved.len() > capacity {
+            self.saved.pop_front();
+        }
+    }
+
+    pub fn next_tick(&self) -> Tick {
+        self.next_tick
+    }
+
+    pub fn latest(&self) -> Option<&SavedTick> {
+        self.saved.back()
+    }
+
+    pub fn ticks(&self) -> impl Iterator<Item = Tick> + '_ {
+        self.saved.iter().map(|saved| saved.tick)
+    }
+
+    // None when the capacity is 0, nothing is kept then
+    pub fn push(&mut self, pool: EntitiesPool, components: Checkpoint, globals: Globals, relations: RelationStore, tags: Tags) -> Option<Tick> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let tick = self.next_tick;
+        self.next_tick += 1;
+
+        if self.saved.len() == self.capacity {
+            self.saved.pop_front();
+        }
+        self.saved.push_back(SavedTick { tick, pool, components, globals, relations, tags });
+        Some(tick)
+    }
+
+    // Ticks saved after the given one are dropped, the next saved tick follows it
+    pub fn rewind(&mut self, tick: Tick) -> Result<&SavedTick, RollbackError> {
+        if !self.saved.iter().any(|saved| saved.tick == tick) {
+            return Err(RollbackError::UnknownTick(tick));
+        }
+
+        while self.saved.back().is_some_and(|saved| saved.tick > tick) {
+            self.saved.pop_back();
+        }
+        self.next_tick = tick + 1;
+        Ok(self.saved.back().unwrap())
+    }
+}
+
+impl Default for RollbackBuffer {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
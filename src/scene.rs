@@ -0,0 +1,639 @@
+use crate::ComponentType;
+
+use std::collections::HashMap;
+use std::any::Any;
+use std::fmt;
+
+// Scene files list entities with their components, referenced by registered names:
+//
+//   # comment
+//   entity {
+//       Position { x: 1, y: -2 }
+//       Name "player"
+//       Tags ["hero", "blue"]
+//       Player
+//   }
+//
+// Component value is optional (unit components), struct fields and list items are separated
+// by commas, trailing comma is allowed.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Unit,
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+    List(Vec<Value>),
+    Struct(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn field(&self, name: &str) -> Result<&Value, String> {
+        match self {
+            Value::Struct(fields) => fields.iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value)
+                .ok_or_else(|| format!("missing field '{}'", name)),
+            _ => Err(format!("expected struct with field '{}'", name)),
+        }
+    }
+
+    pub fn as_i64(&self) -> Result<i64, String> {
+        match self {
+            Value::Int(v) => Ok(*v),
+            _ => Err(String::from("expected integer")),
+        }
+    }
+
+    pub fn as_f64(&self) -> Result<f64, String> {
+        match self {
+            Value::Int(v) => Ok(*v as f64),
+            Value::Float(v) => Ok(*v),
+            _ => Err(String::from("expected number")),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool, String> {
+        match self {
+            Value::Bool(v) => Ok(*v),
+            _ => Err(String::from("expected bool")),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, String> {
+        match self {
+            Value::Str(v) => Ok(v),
+            _ => Err(String::from("expected string")),
+        }
+    }
+
+    pub fn as_list(&self) -> Result<&[Value], String> {
+        match self {
+            Value::List(v) => Ok(v),
+            _ => Err(String::from("expected list")),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Unit => Ok(()),
+            Value::Int(v) => write!(f, "{}", v),
+            Value::Float(v) => write!(f, "{:?}", v), // inf, -inf and NaN are read back too
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::Str(v) => {
+                // Exactly the escapes Parser::string_value understands, anything else is raw
+                write!(f, "\"")?;
+                for c in v.chars() {
+                    match c {
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            },
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            },
+            Value::Struct(fields) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, " {}: {}", name, value)?;
+                }
+                write!(f, " }}")
+            },
+        }
+    }
+}
+
+pub trait SceneComponent: Sized {
+    fn from_value(value: &Value) -> Result<Self, String>;
+    fn to_value(&self) -> Value;
+}
+
+macro_rules! impl_scene_component_for_int {
+    ($($t:ty),+) => {
+        $(
+            impl SceneComponent for $t {
+                fn from_value(value: &Value) -> Result<Self, String> {
+                    <$t>::try_from(value.as_i64()?).map_err(|_| format!("value out of range for {}", stringify!($t)))
+                }
+
+                fn to_value(&self) -> Value {
+                    Value::Int(*self as i64)
+                }
+            }
+        )+
+    };
+}
+
+impl_scene_component_for_int!(u8, u16, u32, i8, i16, i32, i64);
+
+impl SceneComponent for f32 {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        Ok(value.as_f64()? as f32)
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Float(*self as f64)
+    }
+}
+
+impl SceneComponent for f64 {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        value.as_f64()
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Float(*self)
+    }
+}
+
+impl SceneComponent for bool {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        value.as_bool()
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Bool(*self)
+    }
+}
+
+impl SceneComponent for String {
+    fn from_value(value: &Value) -> Result<Self, String> {
+        value.as_str().map(String::from)
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Str(self.clone())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SceneError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedComponent {
+    pub name: String,
+    pub value: Value,
+    pub line: usize,
+    pub column: usize,
+}
+
+pub type ParsedEntity = Vec<ParsedComponent>;
+
+pub fn parse(text: &str) -> Result<Vec<ParsedEntity>, SceneError> {
+    let mut parser = Parser { chars: text.chars().collect(), pos: 0, line: 1, column: 1 };
+    let mut entities = Vec::new();
+
+    parser.skip_whitespace();
+    while !parser.at_end() {
+        let (line, column) = (parser.line, parser.column);
+        let keyword = parser.ident()?;
+        if keyword != "entity" {
+            return Err(SceneError { line, column, message: format!("expected 'entity', found '{}'", keyword) });
+        }
+        parser.expect('{')?;
+
+        let mut components = Vec::new();
+        loop {
+            parser.skip_whitespace();
+            if parser.peek() == Some('}') {
+                parser.bump();
+                break;
+            }
+            let (line, column) = (parser.line, parser.column);
+            let name = parser.ident()?;
+            parser.skip_inline_whitespace();
+            let value = match parser.peek() {
+                None | Some('\n') | Some('}') | Some('#') => Value::Unit,
+                _ => parser.value()?,
+            };
+            components.push(ParsedComponent { name, value, line, column });
+        }
+        entities.push(components);
+        parser.skip_whitespace();
+    }
+
+    Ok(entities)
+}
+
+pub fn write(entities: &[ParsedEntity]) -> String {
+    let mut out = String::new();
+    for (i, components) in entities.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        out.push_str("entity {\n");
+        for component in components {
+            out.push_str("    ");
+            out.push_str(&component.name);
+            if component.value != Value::Unit {
+                out.push(' ');
+                out.push_str(&component.value.to_string());
+            }
+            out.push('\n');
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Parser {
+    fn at_end(&self) -> bool {
+        self.pos >= self.chars.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, message: String) -> SceneError {
+        SceneError { line: self.line, column: self.column, message }
+    }
+
+    fn unexpected(&self, expected: &str) -> SceneError {
+        match self.peek() {
+            Some(c) => self.error(format!("expected {}, found '{}'", expected, c)),
+            None => self.error(format!("expected {}, found end of file", expected)),
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while !matches!(self.peek(), None | Some('\n')) {
+            self.bump();
+        }
+    }
+
+    fn skip_inline_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => self.skip_comment(),
+                '\n' => break,
+                c if c.is_whitespace() => { self.bump(); },
+                _ => break,
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => self.skip_comment(),
+                c if c.is_whitespace() => { self.bump(); },
+                _ => break,
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), SceneError> {
+        self.skip_whitespace();
+        if self.peek() == Some(expected) {
+            self.bump();
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("'{}'", expected)))
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, SceneError> {
+        self.skip_whitespace();
+        let mut ident = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || c == '_' {
+                ident.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+        match ident.chars().next() {
+            Some(c) if !c.is_ascii_digit() => Ok(ident),
+            _ => Err(self.unexpected("identifier")),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, SceneError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('{') => self.struct_value(),
+            Some('[') => self.list_value(),
+            Some('"') => self.string_value(),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number_value(),
+            Some(c) if c.is_alphabetic() => {
+                let (line, column) = (self.line, self.column);
+                match self.ident()?.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    "inf" => Ok(Value::Float(f64::INFINITY)),
+                    "NaN" => Ok(Value::Float(f64::NAN)),
+                    other => Err(SceneError { line, column, message: format!("expected value, found '{}'", other) }),
+                }
+            },
+            _ => Err(self.unexpected("value")),
+        }
+    }
+
+    // Parses comma separated items up to closing character
+    fn items<T>(&mut self, close: char, mut item: impl FnMut(&mut Parser) -> Result<T, SceneError>) -> Result<Vec<T>, SceneError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some(close) {
+                self.bump();
+                return Ok(items);
+            }
+            items.push(item(self)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(',') => { self.bump(); },
+                Some(c) if c == close => {},
+                _ => return Err(self.unexpected(&format!("',' or '{}'", close))),
+            }
+        }
+    }
+
+    fn struct_value(&mut self) -> Result<Value, SceneError> {
+        self.bump();
+        let fields = self.items('}', |p| {
+            let name = p.ident()?;
+            p.expect(':')?;
+            Ok((name, p.value()?))
+        })?;
+        Ok(Value::Struct(fields))
+    }
+
+    fn list_value(&mut self) -> Result<Value, SceneError> {
+        self.bump();
+        Ok(Value::List(self.items(']', |p| p.value())?))
+    }
+
+    fn string_value(&mut self) -> Result<Value, SceneError> {
+        self.bump();
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(Value::Str(s)),
+                Some('\\') => match self.bump() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    _ => return Err(self.error(String::from("invalid escape sequence"))),
+                },
+                Some(c) => s.push(c),
+                None => return Err(self.error(String::from("unterminated string"))),
+            }
+        }
+    }
+
+    fn number_value(&mut self) -> Result<Value, SceneError> {
+        let (line, column) = (self.line, self.column);
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '+' || c == '.' {
+                text.push(c);
+                self.bump();
+            } else {
+                break;
+            }
+        }
+
+        let is_float = text.contains(['.', 'e', 'E']) || text.contains("inf") || text.contains("NaN");
+        let parsed = match is_float {
+            true => text.parse::<f64>().map(Value::Float).ok(),
+            false => text.parse::<i64>().map(Value::Int).ok(),
+        };
+        parsed.ok_or(SceneError { line, column, message: format!("invalid number '{}'", text) })
+    }
+}
+
+type FromValueFn = fn(&Value) -> Result<Box<dyn Any>, String>;
+
+#[derive(Clone)]
+struct Factory {
+    name: String,
+    from_value: FromValueFn,
+    to_value: fn(&dyn Any) -> Value,
+}
+
+fn from_value_erased<T: SceneComponent + Any>(value: &Value) -> Result<Box<dyn Any>, String> {
+    Ok(Box::new(T::from_value(value)?))
+}
+
+fn to_value_erased<T: SceneComponent + Any>(component: &dyn Any) -> Value {
+    component.downcast_ref::<T>().unwrap().to_value()
+}
+
+#[derive(Clone)]
+pub struct SceneRegistry {
+    by_type: HashMap<ComponentType, Factory>,
+    by_name: HashMap<String, ComponentType>,
+}
+
+impl SceneRegistry {
+    pub fn new() -> SceneRegistry {
+        SceneRegistry {
+            by_type: HashMap::new(),
+            by_name: HashMap::new(),
+        }
+    }
+
+    pub fn register<T: SceneComponent + Any>(&mut self, name: &str) {
+        let id = ComponentType::of::<T>();
+        if let Some(previous) = self.by_type.remove(&id) {
+            self.by_name.remove(&previous.name);
+        }
+        if self.by_name.contains_key(name) {
+            panic!("Scene name '{}' is already taken by other type", name);
+        }
+
+        self.by_name.insert(String::from(name), id);
+        self.by_type.insert(id, Factory {
+            name: String::from(name),
+            from_value: from_value_erased::<T>,
+            to_value: to_value_erased::<T>,
+        });
+    }
+
+    pub fn name_of(&self, id: ComponentType) -> Option<&str> {
+        self.by_type.get(&id).map(|f| f.name.as_str())
+    }
+
+    pub fn types(&self) -> impl Iterator<Item = ComponentType> + '_ {
+        self.by_type.keys().copied()
+    }
+
+    pub fn build(&self, component: &ParsedComponent) -> Result<(ComponentType, Box<dyn Any>), SceneError> {
+        let error = |message| SceneError { line: component.line, column: component.column, message };
+        let id = match self.by_name.get(&component.name) {
+            Some(id) => *id,
+            None => return Err(error(format!("unknown component '{}'", component.name))),
+        };
+        let value = (self.by_type[&id].from_value)(&component.value)
+            .map_err(|message| error(format!("{}: {}", component.name, message)))?;
+        Ok((id, value))
+    }
+
+    pub fn describe(&self, id: ComponentType, component: &dyn Any) -> Option<ParsedComponent> {
+        let factory = self.by_type.get(&id)?;
+        Some(ParsedComponent {
+            name: factory.name.clone(),
+            value: (factory.to_value)(component),
+            line: 0,
+            column: 0,
+        })
+    }
+}
+
+impl Default for SceneRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scene() {
+        let text = r#"
+            # Two entities
+            entity {
+                Position { x: 1, y: -2, }
+                Name "hero \"one\""
+                Tags ["a", "b"]
+                Speed 1.5
+                Player  # unit component
+            }
+            entity {}
+        "#;
+
+        let entities = parse(text).unwrap();
+        assert_eq!(2, entities.len());
+        assert!(entities[1].is_empty());
+
+        let components = &entities[0];
+        let names: Vec<&str> = components.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(vec!["Position", "Name", "Tags", "Speed", "Player"], names);
+        assert_eq!(Value::Struct(vec![
+            (String::from("x"), Value::Int(1)),
+            (String::from("y"), Value::Int(-2)),
+        ]), components[0].value);
+        assert_eq!((4, 17), (components[0].line, components[0].column));
+        assert_eq!(Value::Str(String::from("hero \"one\"")), components[1].value);
+        assert_eq!(Value::List(vec![Value::Str(String::from("a")), Value::Str(String::from("b"))]), components[2].value);
+        assert_eq!(Value::Float(1.5), components[3].value);
+        assert_eq!(Value::Unit, components[4].value);
+    }
+
+    #[test]
+    fn test_parse_errors_report_position() {
+        let err = parse("entity {\n    Position { x: 1 y: 2 }\n}").unwrap_err();
+        assert_eq!((2, 21), (err.line, err.column));
+
+        let err = parse("entity {\n  Name \"open").unwrap_err();
+        assert_eq!("unterminated string", err.message);
+
+        let err = parse("thing {}").unwrap_err();
+        assert_eq!((1, 1), (err.line, err.column));
+
+        let err = parse("entity {\n  Health 12x\n}").unwrap_err();
+        assert_eq!((2, 10, String::from("invalid number '12x'")), (err.line, err.column, err.message));
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let text = "entity {\n    Position { x: 1, y: -2 }\n    Player\n    Name \"a\\nb\"\n}\n\nentity {\n    Scale 2.0\n}\n";
+        let entities = parse(text).unwrap();
+        assert_eq!(text, write(&entities));
+    }
+
+    #[test]
+    fn test_write_round_trip_special_values() {
+        let strings = ["tab\there", "cr\rlf\n", "nul\0", "quote' \" back\\", "zażółć \u{1F600} \u{7f}"];
+        let floats = [f64::INFINITY, f64::NEG_INFINITY, -0.0, 1e300];
+        let mut components: Vec<ParsedComponent> = strings.iter()
+            .map(|s| ParsedComponent { name: String::from("Text"), value: Value::Str(String::from(*s)), line: 0, column: 0 })
+            .collect();
+        components.extend(floats.iter()
+            .map(|v| ParsedComponent { name: String::from("Scale"), value: Value::Float(*v), line: 0, column: 0 }));
+        components.push(ParsedComponent { name: String::from("Scale"), value: Value::Float(f64::NAN), line: 0, column: 0 });
+
+        let parsed = parse(&write(&[components.clone()])).unwrap();
+        assert_eq!(components.len(), parsed[0].len());
+        for (written, read) in components.iter().zip(parsed[0].iter()) {
+            match (&written.value, &read.value) {
+                (Value::Float(a), Value::Float(b)) if a.is_nan() => assert!(b.is_nan()),
+                (Value::Float(a), Value::Float(b)) => assert_eq!(a.to_bits(), b.to_bits()),
+                (a, b) => assert_eq!(a, b),
+            }
+        }
+    }
+
+    #[test]
+    fn test_registry_builds_components() {
+        let mut registry = SceneRegistry::new();
+        registry.register::<u32>("Health");
+
+        let entities = parse("entity {\n  Health 10\n  Health -1\n  Mana 3\n}").unwrap();
+        let (id, value) = registry.build(&entities[0][0]).unwrap();
+        assert_eq!(ComponentType::of::<u32>(), id);
+        assert_eq!(Some(&10u32), value.downcast_ref::<u32>());
+
+        let err = registry.build(&entities[0][1]).unwrap_err();
+        assert_eq!((3, 3), (err.line, err.column));
+        let err = registry.build(&entities[0][2]).unwrap_err();
+        assert_eq!("unknown component 'Mana'", err.message);
+
+        let described = registry.describe(id, value.as_ref()).unwrap();
+        assert_eq!(("Health", Value::Int(10)), (described.name.as_str(), described.value));
+    }
+}
@@ -0,0 +1,130 @@
+use crate::ComponentType;
+#[cfg(feature = "snapshot")]
+use crate::snapshot::{Persist, SnapshotError};
+
+use std::collections::HashSet;
+use std::any::Any;
+
+// Marker set by Coordinator::set_enabled. Disabled entities are implicitly excluded from every
+// signature, unless it requires Disabled or asks for include_disabled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Disabled;
+
+#[cfg(feature = "snapshot")]
+impl Persist for Disabled {
+    fn write(&self, _out: &mut Vec<u8>) {}
+
+    fn read(_input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        Ok(Disabled)
+    }
+}
+
+// Entity fits the signature when it has all required types, none of excluded types and, if any
+// any_of types are given, at least one of them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Signature {
+    required: HashSet<ComponentType>,
+    excluded: HashSet<ComponentType>,
+    any_of: HashSet<ComponentType>,
+    include_disabled: bool,
+}
+
+impl Signature {
+    pub fn new() -> Signature {
+        Signature::default()
+    }
+
+    pub fn from_required(required: HashSet<ComponentType>) -> Signature {
+        Signature { required, ..Signature::default() }
+    }
+
+    pub fn require<T: Any>(mut self) -> Signature {
+        self.required.insert(ComponentType::of::<T>());
+        self
+    }
+
+    pub fn exclude<T: Any>(mut self) -> Signature {
+        self.excluded.insert(ComponentType::of::<T>());
+        self
+    }
+
+    pub fn any_of<T: Any>(mut self) -> Signature {
+        self.any_of.insert(ComponentType::of::<T>());
+        self
+    }
+
+    pub fn include_disabled(mut self) -> Signature {
+        self.include_disabled = true;
+        self
+    }
+
+    pub fn required(&self) -> &HashSet<ComponentType> {
+        &self.required
+    }
+
+    pub fn excluded(&self) -> &HashSet<ComponentType> {
+        &self.excluded
+    }
+
+    pub fn any(&self) -> &HashSet<ComponentType> {
+        &self.any_of
+    }
+
+    pub fn matches(&self, component_types: &HashSet<ComponentType>) -> bool {
+        let disabled = ComponentType::of::<Disabled>();
+        if !self.include_disabled && !self.required.contains(&disabled) && component_types.contains(&disabled) {
+            return false;
+        }
+
+        self.required.is_subset(component_types)
+            && self.excluded.is_disjoint(component_types)
+            && (self.any_of.is_empty() || !self.any_of.is_disjoint(component_types))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Enemy;
+    struct Dead;
+    struct Melee;
+    struct Ranged;
+
+    fn types(types: Vec<ComponentType>) -> HashSet<ComponentType> {
+        types.into_iter().collect()
+    }
+
+    #[test]
+    fn test_signature_required_and_excluded() {
+        let s = Signature::new().require::<Enemy>().exclude::<Dead>();
+
+        assert!(s.matches(&types(vec![ComponentType::of::<Enemy>()])));
+        assert!(!s.matches(&types(vec![ComponentType::of::<Enemy>(), ComponentType::of::<Dead>()])));
+        assert!(!s.matches(&types(vec![ComponentType::of::<Dead>()])));
+        assert!(!s.matches(&HashSet::new()));
+    }
+
+    #[test]
+    fn test_signature_any_of() {
+        let s = Signature::new().require::<Enemy>().any_of::<Melee>().any_of::<Ranged>();
+
+        assert!(!s.matches(&types(vec![ComponentType::of::<Enemy>()])));
+        assert!(s.matches(&types(vec![ComponentType::of::<Enemy>(), ComponentType::of::<Melee>()])));
+        assert!(s.matches(&types(vec![ComponentType::of::<Enemy>(), ComponentType::of::<Ranged>()])));
+        assert!(!s.matches(&types(vec![ComponentType::of::<Melee>(), ComponentType::of::<Ranged>()])));
+
+        let empty = Signature::new();
+        assert!(empty.matches(&HashSet::new()), "Empty signature matches every entity");
+    }
+
+    #[test]
+    fn test_signature_skips_disabled() {
+        let disabled = types(vec![ComponentType::of::<Enemy>(), ComponentType::of::<Disabled>()]);
+
+        assert!(!Signature::new().require::<Enemy>().matches(&disabled));
+        assert!(!Signature::new().matches(&disabled));
+        assert!(Signature::new().require::<Enemy>().include_disabled().matches(&disabled));
+        assert!(Signature::new().require::<Disabled>().matches(&disabled));
+    }
+}
@@ -0,0 +1,417 @@
+use crate::Entity;
+use crate::ComponentType;
+use crate::ComponentManager;
+use crate::EntitiesPool;
+use crate::Globals;
+use crate::ComponentRegistry;
+use crate::relation::RelationStore;
+use crate::names::Tags;
+
+use std::any::Any;
+use std::fmt;
+
+// Layout (all integers little endian, strings and byte blobs are u32 length prefixed):
+//   magic "ECSS", version u32
+//   pool: available entities in reuse order, taken entities (u32 count + u32 ids),
+//         generations of all entities (u32 count + u32 values)
+//   components: u32 type count, per type: name, u32 count, per entity: id, blob
+//   relations: u32 pair count, per pair: relation name, source, target
+//   tags: u32 count, per tag: type name, entity, blob
+//   globals: u32 count, per global: key, type name, blob
+// Types, taken entities, relation pairs, tags and globals are written in sorted order so the
+// same world gives the same bytes. Only types with a serializer in the registry are written,
+// under their registered names, for tags and globals too. Relations are written under names
+// given by register_relation_named.
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"ECSS";
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    UnexpectedEof,
+    BadMagic,
+    UnsupportedVersion(u32),
+    UnknownComponent(String),
+    UnknownRelation(String),
+    InvalidData(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::UnexpectedEof => write!(f, "unexpected end of snapshot data"),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::UnknownComponent(name) => write!(f, "no serializer registered for '{}'", name),
+            SnapshotError::UnknownRelation(name) => write!(f, "no relation registered as '{}'", name),
+            SnapshotError::InvalidData(what) => write!(f, "invalid snapshot data: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+pub trait Persist: Sized {
+    fn write(&self, out: &mut Vec<u8>);
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError>;
+}
+
+pub fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], SnapshotError> {
+    if input.len() < n {
+        return Err(SnapshotError::UnexpectedEof);
+    }
+    let (head, tail) = input.split_at(n);
+    *input = tail;
+    Ok(head)
+}
+
+macro_rules! impl_persist_for_number {
+    ($($t:ty),+) => {
+        $(
+            impl Persist for $t {
+                fn write(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+                    let bytes = take(input, std::mem::size_of::<$t>())?;
+                    Ok(<$t>::from_le_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )+
+    };
+}
+
+impl_persist_for_number!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl Persist for bool {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        match u8::read(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            v => Err(SnapshotError::InvalidData(format!("{} is not a bool", v))),
+        }
+    }
+}
+
+impl Persist for String {
+    fn write(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).write(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        let len = u32::read(input)? as usize;
+        let bytes = take(input, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| SnapshotError::InvalidData(String::from("string is not utf-8")))
+    }
+}
+
+impl<T: Persist> Persist for Vec<T> {
+    fn write(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).write(out);
+        for item in self {
+            item.write(out);
+        }
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        let len = u32::read(input)? as usize;
+        let mut items = Vec::with_capacity(len.min(input.len()));
+        for _ in 0..len {
+            items.push(T::read(input)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Persist> Persist for Option<T> {
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Some(value) => {
+                true.write(out);
+                value.write(out);
+            },
+            None => false.write(out),
+        }
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        match bool::read(input)? {
+            true => Ok(Some(T::read(input)?)),
+            false => Ok(None),
+        }
+    }
+}
+
+type ReadFn = fn(&mut &[u8]) -> Result<Box<dyn Any>, SnapshotError>;
+
+// Type erased Persist implementation, kept in ComponentRegistry for every type that opted in
+#[derive(Clone)]
+pub struct Serializer {
+    pub write: fn(&dyn Any, &mut Vec<u8>),
+    pub read: ReadFn,
+}
+
+impl Serializer {
+    pub fn of<T: Persist + Any>() -> Serializer {
+        Serializer {
+            write: write_erased::<T>,
+            read: read_erased::<T>,
+        }
+    }
+}
+
+fn write_erased<T: Persist + Any>(value: &dyn Any, out: &mut Vec<u8>) {
+    value.downcast_ref::<T>().unwrap().write(out);
+}
+
+fn read_erased<T: Persist + Any>(input: &mut &[u8]) -> Result<Box<dyn Any>, SnapshotError> {
+    Ok(Box::new(T::read(input)?))
+}
+
+pub(crate) fn serializer(registry: &ComponentRegistry, id: ComponentType) -> Option<&Serializer> {
+    registry.info(id)?.serializer.as_ref()
+}
+
+fn write_blob(serializer: &Serializer, value: &dyn Any, out: &mut Vec<u8>) {
+    let mut blob = Vec::new();
+    (serializer.write)(value, &mut blob);
+    (blob.len() as u32).write(out);
+    out.extend_from_slice(&blob);
+}
+
+pub(crate) fn read_blob(registry: &ComponentRegistry, name: &str, input: &mut &[u8]) -> Result<(ComponentType, Box<dyn Any>), SnapshotError> {
+    let len = u32::read(input)? as usize;
+    let mut blob = take(input, len)?;
+
+    let unknown = || SnapshotError::UnknownComponent(String::from(name));
+    let id = registry.type_of(name).ok_or_else(unknown)?;
+    let value = (serializer(registry, id).ok_or_else(unknown)?.read)(&mut blob)?;
+    if !blob.is_empty() {
+        return Err(SnapshotError::InvalidData(format!("trailing bytes after '{}'", name)));
+    }
+    Ok((id, value))
+}
+
+pub struct Decoded {
+    pub available: Vec<Entity>,
+    pub taken: Vec<Entity>,
+    pub generations: Vec<u32>,
+    pub components: Vec<(Entity, ComponentType, Box<dyn Any>)>,
+    pub relations: Vec<(String, Entity, Entity)>,
+    pub tags: Vec<(Entity, ComponentType, Box<dyn Any>)>,
+    pub globals: Vec<(String, Box<dyn Any>)>,
+}
+
+// Type name, entity and blob of every tag whose type has a serializer, sorted
+pub(crate) fn serialize_tags(registry: &ComponentRegistry, tags: &Tags) -> Vec<(String, Entity, Vec<u8>)> {
+    let mut serialized: Vec<(String, Entity, Vec<u8>)> = tags.pairs().into_iter()
+        .filter_map(|(id, e, tag)| {
+            let mut blob = Vec::new();
+            (serializer(registry, id)?.write)(tag, &mut blob);
+            Some((String::from(registry.name_of(id)?), e, blob))
+        })
+        .collect();
+    serialized.sort_unstable();
+    serialized
+}
+
+pub fn encode(pool: &EntitiesPool, cm: &ComponentManager, relations: &RelationStore, tags: &Tags, globals: &Globals) -> Vec<u8> {
+    let registry = cm.registry();
+    let mut out = Vec::new();
+    out.extend_from_slice(SNAPSHOT_MAGIC);
+    SNAPSHOT_VERSION.write(&mut out);
+
+    let available: Vec<Entity> = pool.available_iter().copied().collect();
+    available.write(&mut out);
+    let taken: Vec<Entity> = pool.taken_iter().copied().collect();
+    taken.write(&mut out);
+    pool.generations().to_vec().write(&mut out);
+
+    let mut types: Vec<(&str, ComponentType, &Serializer)> = registry.iter()
+        .filter(|(id, _)| cm.is_registered(*id))
+        .filter_map(|(id, info)| Some((info.name.as_str(), id, info.serializer.as_ref()?)))
+        .collect();
+    types.sort_unstable_by_key(|(name, _, _)| *name);
+    (types.len() as u32).write(&mut out);
+    for (name, id, serializer) in types {
+        String::from(name).write(&mut out);
+        let mut entities = cm.entities_with(id);
+        entities.sort_unstable();
+        (entities.len() as u32).write(&mut out);
+        for e in entities {
+            e.write(&mut out);
+            write_blob(serializer, cm.get_any(e, id).unwrap(), &mut out);
+        }
+    }
+
+    let pairs = relations.pairs();
+    (pairs.len() as u32).write(&mut out);
+    for (name, _, source, target) in pairs {
+        String::from(name).write(&mut out);
+        source.write(&mut out);
+        target.write(&mut out);
+    }
+
+    let tags = serialize_tags(registry, tags);
+    (tags.len() as u32).write(&mut out);
+    for (name, e, blob) in tags {
+        name.write(&mut out);
+        e.write(&mut out);
+        blob.write(&mut out);
+    }
+
+    let mut persisted: Vec<(&str, &str, &Serializer, &dyn Any)> = globals.iter()
+        .filter_map(|(key, value)| {
+            let id = value.type_id();
+            Some((key, registry.name_of(id)?, serializer(registry, id)?, value))
+        })
+        .collect();
+    persisted.sort_unstable_by_key(|(key, _, _, _)| *key);
+    (persisted.len() as u32).write(&mut out);
+    for (key, name, serializer, value) in persisted {
+        String::from(key).write(&mut out);
+        String::from(name).write(&mut out);
+        write_blob(serializer, value, &mut out);
+    }
+
+    out
+}
+
+pub fn decode(mut input: &[u8], registry: &ComponentRegistry) -> Result<Decoded, SnapshotError> {
+    let input = &mut input;
+    if take(input, SNAPSHOT_MAGIC.len()).map_err(|_| SnapshotError::BadMagic)? != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = u32::read(input)?;
+    if version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let available = Vec::<Entity>::read(input)?;
+    let taken = Vec::<Entity>::read(input)?;
+    let generations = Vec::<u32>::read(input)?;
+
+    let mut components = Vec::new();
+    for _ in 0..u32::read(input)? {
+        let name = String::read(input)?;
+        for _ in 0..u32::read(input)? {
+            let e = Entity::read(input)?;
+            let (id, value) = read_blob(registry, &name, input)?;
+            components.push((e, id, value));
+        }
+    }
+
+    let mut relations = Vec::new();
+    for _ in 0..u32::read(input)? {
+        relations.push((String::read(input)?, Entity::read(input)?, Entity::read(input)?));
+    }
+
+    let mut tags = Vec::new();
+    for _ in 0..u32::read(input)? {
+        let name = String::read(input)?;
+        let e = Entity::read(input)?;
+        let (id, value) = read_blob(registry, &name, input)?;
+        tags.push((e, id, value));
+    }
+
+    let mut globals = Vec::new();
+    for _ in 0..u32::read(input)? {
+        let key = String::read(input)?;
+        let name = String::read(input)?;
+        let (_, value) = read_blob(registry, &name, input)?;
+        globals.push((key, value));
+    }
+
+    if !input.is_empty() {
+        return Err(SnapshotError::InvalidData(String::from("trailing bytes after snapshot")));
+    }
+    Ok(Decoded { available, taken, generations, components, relations, tags, globals })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Persist + PartialEq + fmt::Debug>(value: T) {
+        let mut out = Vec::new();
+        value.write(&mut out);
+        let mut input = &out[..];
+        assert_eq!(value, T::read(&mut input).unwrap());
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn test_persist_round_trip() {
+        round_trip(7u8);
+        round_trip(-7i64);
+        round_trip(1.5f32);
+        round_trip(true);
+        round_trip(String::from("zażółć"));
+        round_trip(vec![1u32, 2, 3]);
+        round_trip(Some(vec![String::from("a")]));
+        round_trip(None::<u16>);
+    }
+
+    #[test]
+    fn test_persist_errors() {
+        let mut input: &[u8] = &[1, 2];
+        assert_eq!(Err(SnapshotError::UnexpectedEof), u32::read(&mut input));
+
+        let mut input: &[u8] = &[2];
+        assert!(matches!(bool::read(&mut input), Err(SnapshotError::InvalidData(_))));
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let mut pool = EntitiesPool::with_capacity(4);
+        let e = pool.take();
+        let mut cm = ComponentManager::new();
+        cm.register_named::<u32>("u32");
+        cm.register::<i8>(); // No serializer, not persisted
+        cm.registry_mut().set_serializer::<u32>();
+        cm.registry_mut().register::<String>("string");
+        cm.registry_mut().set_serializer::<String>();
+        cm.add(e, 5u32);
+        cm.add(e, -1i8);
+        let mut globals = Globals::new();
+        globals.add("level", String::from("intro"));
+        globals.add("not persisted", 1.5f64);
+        let mut relations = RelationStore::new();
+        relations.register_named::<u32>("counts", crate::Cleanup::Keep);
+        relations.add::<u32>(e, 3);
+        let mut tags = Tags::new();
+        tags.add(e, String::from("hero"));
+        tags.add(e, 'x'); // No serializer, not persisted
+
+        let bytes = encode(&pool, &cm, &relations, &tags, &globals);
+        assert_eq!(bytes, encode(&pool, &cm, &relations, &tags, &globals), "Encoding should be stable");
+
+        let decoded = decode(&bytes, cm.registry()).unwrap();
+        assert_eq!(vec![e], decoded.taken);
+        assert_eq!(3, decoded.available.len());
+        assert_eq!(1, decoded.components.len());
+        let (de, id, value) = &decoded.components[0];
+        assert_eq!((e, ComponentType::of::<u32>()), (*de, *id));
+        assert_eq!(Some(&5u32), value.downcast_ref::<u32>());
+        assert_eq!(1, decoded.globals.len());
+        assert_eq!("level", decoded.globals[0].0);
+        assert_eq!(vec![(String::from("counts"), e, 3)], decoded.relations);
+        assert_eq!(1, decoded.tags.len());
+        assert_eq!((e, ComponentType::of::<String>()), (decoded.tags[0].0, decoded.tags[0].1));
+        assert_eq!(Some(&String::from("hero")), decoded.tags[0].2.downcast_ref::<String>());
+
+        assert_eq!(Err(SnapshotError::BadMagic), decode(b"NOPE", cm.registry()).map(|_| ()));
+        assert_eq!(Err(SnapshotError::UnexpectedEof), decode(&bytes[..bytes.len() - 1], cm.registry()).map(|_| ()));
+
+        let mut other = ComponentRegistry::new();
+        other.register::<u32>("u32");
+        other.set_serializer::<u32>();
+        other.register::<String>("string"); // Known, but without serializer
+        assert_eq!(Err(SnapshotError::UnknownComponent(String::from("string"))), decode(&bytes, &other).map(|_| ()));
+    }
+}
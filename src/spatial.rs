@@ -0,0 +1,347 @@
+use crate::Entity;
+use crate::component::AnyComponentArray;
+use crate::index::AnyIndex;
+use crate::transform::{LocalTransform, GlobalTransform, Transform2D};
+
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::any::Any;
+
+type Cell = (i64, i64); // wide enough that neighbours of saturated cells are rarely needed
+
+// Component with a position in the plane, spatial indexes are maintained from it
+pub trait Located: Any {
+    fn location(&self) -> [f32; 2];
+}
+
+impl Located for LocalTransform<Transform2D> {
+    fn location(&self) -> [f32; 2] {
+        self.0.translation
+    }
+}
+
+impl Located for GlobalTransform<Transform2D> {
+    fn location(&self) -> [f32; 2] {
+        self.0.translation
+    }
+}
+
+// Uniform grid of square cells, only cells holding some entity are stored. Cell size should be
+// about the typical query radius.
+#[derive(Clone)]
+pub struct SpatialGrid {
+    cell_size: f32,
+    cells: HashMap<Cell, BTreeSet<Entity>>,
+    points: HashMap<Entity, [f32; 2]>,
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f32) -> SpatialGrid {
+        if cell_size.is_nan() || cell_size <= 0.0 {
+            panic!("Cell size has to be positive");
+        }
+        SpatialGrid {
+            cell_size,
+            cells: HashMap::new(),
+            points: HashMap::new(),
+        }
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn get(&self, e: Entity) -> Option<[f32; 2]> {
+        self.points.get(&e).copied()
+    }
+
+    // Moves the entity if it is there already
+    pub fn insert(&mut self, e: Entity, point: [f32; 2]) {
+        let cell = self.cell_of(point);
+        if let Some(old) = self.points.insert(e, point) {
+            let old = self.cell_of(old);
+            if old == cell {
+                return;
+            }
+            self.remove_from_cell(old, e);
+        }
+        self.cells.entry(cell).or_default().insert(e);
+    }
+
+    pub fn remove(&mut self, e: Entity) -> bool {
+        match self.points.remove(&e) {
+            Some(point) => {
+                self.remove_from_cell(self.cell_of(point), e);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.points.clear();
+    }
+
+    // Ascending order, the boundary is included
+    pub fn within_radius(&self, center: [f32; 2], radius: f32) -> Vec<Entity> {
+        let min = [center[0] - radius, center[1] - radius];
+        let max = [center[0] + radius, center[1] + radius];
+        let mut found: Vec<Entity> = self.candidates(min, max)
+            .filter(|e| distance_squared(self.points[e], center) <= radius * radius)
+            .collect();
+        found.sort_unstable();
+        found
+    }
+
+    // Ascending order, the boundary is included
+    pub fn within_aabb(&self, min: [f32; 2], max: [f32; 2]) -> Vec<Entity> {
+        let mut found: Vec<Entity> = self.candidates(min, max)
+            .filter(|e| {
+                let p = self.points[e];
+                min[0] <= p[0] && p[0] <= max[0] && min[1] <= p[1] && p[1] <= max[1]
+            })
+            .collect();
+        found.sort_unstable();
+        found
+    }
+
+    // Up to k entities ordered by distance, ties by entity. Searches rings of cells around the
+    // point until no unvisited cell can hold anything closer than the k-th entity found. Once
+    // the searched area has more cells than there are points, every point is checked instead.
+    pub fn nearest(&self, point: [f32; 2], k: usize) -> Vec<Entity> {
+        if k == 0 || self.points.is_empty() {
+            return Vec::new();
+        }
+
+        let center = self.cell_of(point);
+        let mut found: Vec<(f32, Entity)> = Vec::new();
+        let mut ring: i64 = 0;
+        loop {
+            let side = 2 * ring + 1;
+            if side * side > self.points.len() as i64 {
+                found = self.points.iter().map(|(e, p)| (distance_squared(*p, point), *e)).collect();
+                found.sort_unstable_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+                break;
+            }
+
+            for cell in ring_cells(center, ring) {
+                if let Some(entities) = self.cells.get(&cell) {
+                    found.extend(entities.iter().map(|e| (distance_squared(self.points[e], point), *e)));
+                }
+            }
+            found.sort_unstable_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+            let reach = ring as f32 * self.cell_size;
+            let done = found.len() >= k && found[k - 1].0 <= reach * reach;
+            if done || found.len() == self.points.len() {
+                break;
+            }
+            ring += 1;
+        }
+        found.into_iter().take(k).map(|(_, e)| e).collect()
+    }
+
+    // Priv
+
+    // Casts saturate, so points beyond the cell range end up in the border cells
+    fn cell_of(&self, point: [f32; 2]) -> Cell {
+        ((point[0] / self.cell_size).floor() as i64, (point[1] / self.cell_size).floor() as i64)
+    }
+
+    fn remove_from_cell(&mut self, cell: Cell, e: Entity) {
+        let entities = self.cells.get_mut(&cell).unwrap();
+        entities.remove(&e);
+        if entities.is_empty() {
+            self.cells.remove(&cell);
+        }
+    }
+
+    fn candidates(&self, min: [f32; 2], max: [f32; 2]) -> impl Iterator<Item = Entity> + '_ {
+        let (x0, y0) = self.cell_of(min);
+        let (x1, y1) = self.cell_of(max);
+        let width = x1.saturating_sub(x0).saturating_add(1);
+        let area = width.saturating_mul(y1.saturating_sub(y0).saturating_add(1));
+        // Walking every cell of a huge area is slower than checking every point
+        let cells: Vec<&BTreeSet<Entity>> = if area > self.cells.len() as i64 {
+            self.cells.iter()
+                .filter(|((x, y), _)| x0 <= *x && *x <= x1 && y0 <= *y && *y <= y1)
+                .map(|(_, entities)| entities)
+                .collect()
+        } else {
+            (x0..=x1).flat_map(|x| (y0..=y1).map(move |y| (x, y)))
+                .filter_map(|cell| self.cells.get(&cell))
+                .collect()
+        };
+        cells.into_iter().flatten().copied()
+    }
+}
+
+// Grid kept up to date by ComponentManager from component T. Values handed out by get_mut
+// are picked up before the next query, like in ValueIndex.
+pub struct SpatialIndex<T: Located> {
+    grid: SpatialGrid,
+    dirty: HashSet<Entity>,
+    located: PhantomData<T>,
+}
+
+impl<T: Located> SpatialIndex<T> {
+    pub fn new(cell_size: f32) -> SpatialIndex<T> {
+        SpatialIndex {
+            grid: SpatialGrid::new(cell_size),
+            dirty: HashSet::new(),
+            located: PhantomData,
+        }
+    }
+
+    pub fn grid(&self) -> &SpatialGrid {
+        &self.grid
+    }
+}
+
+impl<T: Located> Clone for SpatialIndex<T> {
+    fn clone(&self) -> Self {
+        SpatialIndex {
+            grid: self.grid.clone(),
+            dirty: self.dirty.clone(),
+            located: PhantomData,
+        }
+    }
+}
+
+impl<T: Located> AnyIndex for SpatialIndex<T> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn clone_box(&self) -> Box<dyn AnyIndex> {
+        Box::new(self.clone())
+    }
+
+    fn update(&mut self, e: Entity, value: &dyn Any) {
+        self.dirty.remove(&e);
+        self.grid.insert(e, value.downcast_ref::<T>().unwrap().location());
+    }
+
+    fn remove(&mut self, e: Entity) {
+        self.dirty.remove(&e);
+        self.grid.remove(e);
+    }
+
+    fn mark_dirty(&mut self, e: Entity) {
+        self.dirty.insert(e);
+    }
+
+    fn refresh(&mut self, array: &dyn AnyComponentArray) {
+        let dirty: Vec<Entity> = self.dirty.drain().collect();
+        for e in dirty {
+            match array.get_any(e) {
+                Some(value) => self.update(e, value),
+                None => AnyIndex::remove(self, e),
+            }
+        }
+    }
+
+    fn rebuild(&mut self, array: &dyn AnyComponentArray) {
+        self.grid.clear();
+        self.dirty.clear();
+        for e in array.entities() {
+            self.update(e, array.get_any(e).unwrap());
+        }
+    }
+}
+
+fn distance_squared(a: [f32; 2], b: [f32; 2]) -> f32 {
+    let (dx, dy) = (a[0] - b[0], a[1] - b[1]);
+    dx * dx + dy * dy
+}
+
+// Cells at Chebyshev distance ring from the center cell, those past the cell range are left out
+fn ring_cells(center: Cell, ring: i64) -> Vec<Cell> {
+    let (cx, cy) = center;
+    if ring == 0 {
+        return vec![center];
+    }
+    let mut cells = Vec::with_capacity(8 * ring as usize);
+    for d in -ring..=ring {
+        cells.extend(cx.checked_add(d).zip(cy.checked_sub(ring)));
+        cells.extend(cx.checked_add(d).zip(cy.checked_add(ring)));
+    }
+    for d in -ring + 1..ring {
+        cells.extend(cx.checked_sub(ring).zip(cy.checked_add(d)));
+        cells.extend(cx.checked_add(ring).zip(cy.checked_add(d)));
+    }
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grid_queries() {
+        let mut grid = SpatialGrid::new(10.0);
+        grid.insert(1, [0.0, 0.0]);
+        grid.insert(2, [5.0, 0.0]);
+        grid.insert(3, [-12.0, 3.0]);
+        grid.insert(4, [100.0, 100.0]);
+
+        assert_eq!(vec![1, 2], grid.within_radius([0.0, 0.0], 5.0));
+        assert_eq!(vec![1, 2, 3], grid.within_radius([0.0, 0.0], 12.5));
+        assert_eq!(vec![1, 3], grid.within_aabb([-20.0, -1.0], [1.0, 5.0]));
+        assert_eq!(vec![4, 2, 1], grid.nearest([90.0, 90.0], 3));
+        assert_eq!(vec![3, 1, 2, 4], grid.nearest([-11.0, 0.0], 10));
+        assert!(grid.nearest([0.0, 0.0], 0).is_empty());
+
+        grid.insert(4, [1.0, 1.0]);
+        assert_eq!(vec![1, 4], grid.nearest([0.0, 0.0], 2));
+        assert!(grid.within_radius([100.0, 100.0], 1.0).is_empty());
+
+        assert!(grid.remove(1));
+        assert!(!grid.remove(1));
+        assert_eq!(vec![2, 4], grid.within_aabb([-1.0, -1.0], [5.0, 5.0]));
+        assert_eq!(3, grid.len());
+    }
+
+    #[test]
+    fn test_nearest_looks_past_first_hit() {
+        let mut grid = SpatialGrid::new(1.0);
+        grid.insert(1, [1.9, 0.5]); // neighbouring cell but farther
+        grid.insert(2, [0.5, 1.2]);
+        grid.insert(3, [0.5, -3.0]);
+        assert_eq!(vec![2, 1], grid.nearest([0.1, 0.5], 2));
+    }
+
+    #[test]
+    fn test_far_and_extreme_points() {
+        let mut grid = SpatialGrid::new(1.0);
+        grid.insert(1, [1e6, 0.0]);
+        assert_eq!(vec![1], grid.nearest([0.0, 0.0], 1), "Far point is found without walking every ring");
+
+        grid.insert(2, [f32::MAX, f32::MAX]);
+        grid.insert(3, [f32::MIN, 0.0]);
+        grid.insert(4, [f32::INFINITY, 0.0]);
+        assert_eq!(vec![2, 1], grid.nearest([f32::MAX, f32::MAX], 2));
+        assert_eq!(vec![3], grid.nearest([f32::MIN, 1.0], 1));
+        assert_eq!(vec![2], grid.within_aabb([1e30, 1e30], [f32::INFINITY, f32::INFINITY]));
+        assert_eq!(vec![1, 3], grid.within_aabb([f32::MIN, -1.0], [1e7, 1.0]));
+        assert_eq!(vec![4], grid.within_aabb([1e30, -1.0], [f32::INFINITY, 1.0]));
+
+        for x in -2..=2 {
+            for y in -2..=2 {
+                grid.insert(10 + (x + 2) as u32 * 5 + (y + 2) as u32, [x as f32, y as f32]);
+            }
+        }
+        assert_eq!(vec![22, 27, 23, 21, 17], grid.nearest([0.2, 0.1], 5));
+    }
+}
@@ -3,102 +3,204 @@ use crate::ComponentManager;
 use crate::ComponentType;
 use crate::SystemType;
 use crate::Coordinator;
+use crate::Signature;
+use crate::registry::CloneError;
 
 use std::collections::HashSet;
 use std::collections::HashMap;
 use std::any::Any;
 use std::rc::Rc;
-use std::cell::{Ref, RefCell};
+use std::cell::RefCell;
 
+pub type Deferred = Box<dyn Fn(&mut Coordinator)>;
+
+// Matched entities are tracked by SystemManager and handed to apply in ascending order,
+// add/remove are just notifications about entities entering or leaving the system.
 pub trait System {
-    fn add(&mut self, e: Entity);
-    fn remove(&mut self, e: Entity);
+    fn add(&mut self, _e: Entity) {}
+    fn remove(&mut self, _e: Entity) {}
+
+    fn get_signature(&self) -> &Signature;
+    fn apply(&mut self, entities: &[Entity], cm: &mut ComponentManager) -> Deferred;
 
-    fn get_component_types(&self) -> &HashSet<ComponentType>;
-    fn apply(&mut self, cm: &mut ComponentManager) -> Box<dyn Fn(&mut Coordinator)>;
+    // Independent copy for Coordinator::try_clone. Systems without one can't be forked, as
+    // sharing them would let the fork change state of the original.
+    fn box_clone(&self) -> Option<Rc<RefCell<dyn System>>> {
+        None
+    }
 }
 
 pub struct SystemManager {
-    system_component_types: HashMap<SystemType, HashSet<ComponentType>>,
-    systems               : HashMap<SystemType, Rc<RefCell<dyn System>>>,
+    system_signatures: HashMap<SystemType, Signature>,
+    system_entities  : HashMap<SystemType, Vec<Entity>>, // sorted
+    systems          : HashMap<SystemType, Rc<RefCell<dyn System>>>,
+    system_names     : HashMap<SystemType, &'static str>,
 }
 
 impl SystemManager {
     pub fn new() -> SystemManager{
         SystemManager {
-            system_component_types: HashMap::new(),
+            system_signatures: HashMap::new(),
+            system_entities: HashMap::new(),
             systems: HashMap::new(),
+            system_names: HashMap::new(),
         }
     }
 
     pub fn register<T: System + Any>(&mut self, system: Rc<RefCell<T>>) {
         let sys_id = SystemType::of::<T>();
-        self.system_component_types.insert(sys_id, system.borrow().get_component_types().clone());
+        self.system_signatures.insert(sys_id, system.borrow().get_signature().clone());
+        self.system_entities.insert(sys_id, Vec::new());
         self.systems.insert(sys_id, system);
+        self.system_names.insert(sys_id, std::any::type_name::<T>());
+    }
+
+    pub fn try_clone(&self) -> Result<SystemManager, CloneError> {
+        let mut systems = HashMap::with_capacity(self.systems.len());
+        for (sys_id, system) in self.systems.iter() {
+            let copy = system.borrow().box_clone()
+                .ok_or_else(|| CloneError::System(String::from(self.system_names[sys_id])))?;
+            systems.insert(*sys_id, copy);
+        }
+
+        Ok(SystemManager {
+            system_signatures: self.system_signatures.clone(),
+            system_entities: self.system_entities.clone(),
+            systems,
+            system_names: self.system_names.clone(),
+        })
+    }
+
+    // Re-evaluates membership of e in every system, for both added and removed components
+    pub fn update_entity(&mut self, e: Entity, component_types: &HashSet<ComponentType>) {
+        for (sys_id, sys) in self.systems.iter_mut() {
+            let fit_for_sys = self.system_signatures[sys_id].matches(component_types);
+            let entities = self.system_entities.get_mut(sys_id).unwrap();
+            match (fit_for_sys, entities.binary_search(&e)) {
+                (true, Err(pos)) => {
+                    entities.insert(pos, e);
+                    sys.borrow_mut().add(e);
+                },
+                (false, Ok(pos)) => {
+                    entities.remove(pos);
+                    sys.borrow_mut().remove(e);
+                },
+                _ => {},
+            }
+        }
+    }
+
+    // Same as update_entity, but walks the systems once for the whole batch
+    pub fn update_entities(&mut self, batch: &[(Entity, &HashSet<ComponentType>)]) {
+        for (sys_id, sys) in self.systems.iter_mut() {
+            let signature = &self.system_signatures[sys_id];
+            let entities = self.system_entities.get_mut(sys_id).unwrap();
+
+            let mut joined = Vec::new();
+            let mut left = Vec::new();
+            for (e, component_types) in batch {
+                let fit_for_sys = signature.matches(component_types);
+                match (fit_for_sys, entities.binary_search(e)) {
+                    (true, Err(_)) => joined.push(*e),
+                    (false, Ok(_)) => left.push(*e),
+                    _ => {},
+                }
+            }
+            joined.sort_unstable();
+            joined.dedup();
+            left.sort_unstable();
+            left.dedup();
+
+            entities.retain(|e| left.binary_search(e).is_err());
+            entities.extend_from_slice(&joined);
+            entities.sort_unstable();
+
+            let mut sys = sys.borrow_mut();
+            for e in joined {
+                sys.add(e);
+            }
+            for e in left {
+                sys.remove(e);
+            }
+        }
+    }
+
+    pub fn remove_entity(&mut self, e: Entity) {
+        for (sys_id, sys) in self.systems.iter_mut() {
+            let entities = self.system_entities.get_mut(sys_id).unwrap();
+            if let Ok(pos) = entities.binary_search(&e) {
+                entities.remove(pos);
+                sys.borrow_mut().remove(e);
+            }
+        }
     }
 
-    pub fn add_component(&mut self, e: Entity, component_types: &HashSet<ComponentType>) { // TODO: this
-                                                                                    // method
-                                                                                    // should
-                                                                                    // rather be
-                                                                                    // called
-                                                                                    // update_components?
-        for (_, sys) in self.systems.iter_mut() {
-            let fit_for_sys = sys.borrow().get_component_types().is_subset(component_types);
-            if fit_for_sys {
-                sys.borrow_mut().add(e);
+    pub fn clear_entities(&mut self) {
+        for (sys_id, sys) in self.systems.iter_mut() {
+            let entities = self.system_entities.get_mut(sys_id).unwrap();
+            for e in entities.drain(..) {
+                sys.borrow_mut().remove(e);
             }
         }
     }
 
-    pub fn apply_all(&mut self, cm: &mut ComponentManager) -> Vec<Box<dyn Fn(&mut Coordinator)>> {
+    pub fn entities<T: System + Any>(&self) -> &[Entity] {
+        match self.system_entities.get(&SystemType::of::<T>()) {
+            Some(entities) => entities,
+            None => &[],
+        }
+    }
+
+    pub fn apply_all(&mut self, cm: &mut ComponentManager) -> Vec<Deferred> {
         let mut result = Vec::new();
-        for (_, system) in self.systems.iter_mut() {
-            let outcome = system.borrow_mut().apply(cm);
+        for (sys_id, system) in self.systems.iter_mut() {
+            let outcome = system.borrow_mut().apply(&self.system_entities[sys_id], cm);
             result.push(outcome);
         }
         result
     }
 }
 
+impl Default for SystemManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     struct TestSystem {
-        entities: HashSet<Entity>,
-        component_types: HashSet<ComponentType>,
+        notified: Vec<(Entity, bool)>, // (entity, true if added)
+        signature: Signature,
     }
 
     impl TestSystem {
         fn new() -> TestSystem {
             TestSystem {
-                entities: HashSet::new(),
-                component_types: HashSet::new(),
+                notified: Vec::new(),
+                signature: Signature::new(),
             }
         }
-
-        fn get_entities(&mut self) -> &HashSet<Entity> {
-            &self.entities
-        }
     }
 
     impl System for TestSystem {
         fn add(&mut self, e: Entity) {
-            self.entities.insert(e);
+            self.notified.push((e, true));
         }
 
         fn remove(&mut self, e: Entity) {
-            self.entities.remove(&e);
+            self.notified.push((e, false));
         }
 
-        fn get_component_types(&self) -> &HashSet<ComponentType> {
-            &self.component_types
+        fn get_signature(&self) -> &Signature {
+            &self.signature
         }
 
-        fn apply(&mut self, cm: &mut ComponentManager) -> Box<dyn Fn(&mut Coordinator)> {
+        fn apply(&mut self, entities: &[Entity], cm: &mut ComponentManager) -> Box<dyn Fn(&mut Coordinator)> {
 
-            for e in self.entities.iter() {
+            for e in entities {
                 let v = cm.get_mut::<i32>(e).unwrap();
                 *v += 1;
             }
@@ -115,17 +217,27 @@ mod tests {
     fn test_system() {
         let e1: Entity = 1;
         let e2: Entity = 2;
+        let e3: Entity = 3;
+        let types = HashSet::from_iter(vec![ComponentType::of::<i32>()]);
+
+        let mut sm = SystemManager::new();
+        let test_sys = Rc::new(RefCell::new(TestSystem::new()));
+        sm.register(test_sys.clone());
 
-        let mut s = TestSystem::new();
-        s.add(e1);
-        s.add(e2);
-        s.remove(e1);
+        sm.update_entity(e3, &types);
+        sm.update_entity(e1, &types);
+        sm.update_entity(e2, &types);
+        sm.update_entity(e2, &types); // Already a member, no second notification
+        sm.remove_entity(e1);
 
-        let expected: HashSet<Entity> = vec![e2].into_iter().collect();
-        assert_eq!(expected, *s.get_entities());
+        assert_eq!(&[e2, e3], sm.entities::<TestSystem>(), "Entities are kept in ascending order");
+        assert_eq!(
+            vec![(e3, true), (e1, true), (e2, true), (e1, false)],
+            test_sys.borrow().notified);
     }
 
     #[test]
+    #[allow(unused_mut)]
     fn test_system_manager() {
         let e1: Entity = 1; // Will become a part of TestSystem
         let e2: Entity = 2; // Will not be a part of TestSystem
@@ -144,15 +256,37 @@ mod tests {
         // existig entities managed by SM will be checked if they sghould be added to newly added
         // system
         sm.register(test_sys.clone()); // TODO: sys_id is not needed
-        sm.add_component(e1, &HashSet::from_iter(vec![ComponentType::of::<i32>()]));
-        assert_eq!(
-            HashSet::from_iter(vec![e1]),
-            test_sys.borrow().entities);
-        test_sys.borrow_mut().apply(&mut cm);
+        sm.update_entity(e1, &HashSet::from_iter(vec![ComponentType::of::<i32>()]));
+        assert_eq!(&[e1], sm.entities::<TestSystem>());
+        let _ = sm.apply_all(&mut cm);
 
         assert_eq!(Some(&(v1+1)), cm.get(&e1), "Should be incremented as this entity IS a part of a TestSystem");
         assert_eq!(Some(&(v2)), cm.get(&e2), "Should not be incremented as this entity IS NOT part of a TestSystem");
     }
 
-}
+    #[test]
+    fn test_system_manager_reevaluates_on_remove_and_exclusion() {
+        struct Enemy;
+        struct Dead;
+        let enemy = ComponentType::of::<Enemy>();
+        let dead = ComponentType::of::<Dead>();
+
+        let mut sm = SystemManager::new();
+        let mut sys = TestSystem::new();
+        sys.signature = Signature::new().require::<Enemy>().exclude::<Dead>();
+        sm.register(Rc::new(RefCell::new(sys)));
 
+        let e: Entity = 1;
+        sm.update_entity(e, &HashSet::from_iter(vec![enemy]));
+        assert_eq!(&[e], sm.entities::<TestSystem>());
+
+        sm.update_entity(e, &HashSet::from_iter(vec![enemy, dead]));
+        assert!(sm.entities::<TestSystem>().is_empty(), "Adding excluded component leaves the system");
+
+        sm.update_entity(e, &HashSet::from_iter(vec![enemy]));
+        assert_eq!(&[e], sm.entities::<TestSystem>(), "Removing excluded component rejoins");
+
+        sm.update_entity(e, &HashSet::new());
+        assert!(sm.entities::<TestSystem>().is_empty(), "Removing required component leaves the system");
+    }
+}
@@ -0,0 +1,323 @@
+use crate::Entity;
+use crate::ComponentManager;
+use crate::ComponentType;
+use crate::System;
+use crate::Signature;
+use crate::system::Deferred;
+use crate::hierarchy;
+
+use std::collections::HashSet;
+use std::collections::HashMap;
+use std::any::Any;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+pub trait Transform: Copy + PartialEq + Any {
+    const IDENTITY: Self;
+
+    // Child given relative to self, result relative to whatever self is relative to
+    fn mul(&self, child: &Self) -> Self;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform2D {
+    pub translation: [f32; 2],
+    pub rotation: f32, // radians, counterclockwise
+    pub scale: [f32; 2],
+}
+
+impl Transform2D {
+    pub fn from_translation(x: f32, y: f32) -> Transform2D {
+        Transform2D { translation: [x, y], ..Self::IDENTITY }
+    }
+
+    pub fn transform_point(&self, p: [f32; 2]) -> [f32; 2] {
+        let (sin, cos) = self.rotation.sin_cos();
+        let (x, y) = (p[0] * self.scale[0], p[1] * self.scale[1]);
+        [self.translation[0] + x * cos - y * sin, self.translation[1] + x * sin + y * cos]
+    }
+}
+
+impl Transform for Transform2D {
+    const IDENTITY: Transform2D = Transform2D { translation: [0.0; 2], rotation: 0.0, scale: [1.0; 2] };
+
+    fn mul(&self, child: &Transform2D) -> Transform2D {
+        Transform2D {
+            translation: self.transform_point(child.translation),
+            rotation: self.rotation + child.rotation,
+            scale: [self.scale[0] * child.scale[0], self.scale[1] * child.scale[1]],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform3D {
+    pub translation: [f32; 3],
+    pub rotation: [f32; 4], // unit quaternion x, y, z, w
+    pub scale: [f32; 3],
+}
+
+impl Transform3D {
+    pub fn from_translation(x: f32, y: f32, z: f32) -> Transform3D {
+        Transform3D { translation: [x, y, z], ..Self::IDENTITY }
+    }
+
+    pub fn from_axis_angle(axis: [f32; 3], angle: f32) -> Transform3D {
+        let (sin, cos) = (angle / 2.0).sin_cos();
+        let rotation = [axis[0] * sin, axis[1] * sin, axis[2] * sin, cos];
+        Transform3D { rotation, ..Self::IDENTITY }
+    }
+
+    pub fn transform_point(&self, p: [f32; 3]) -> [f32; 3] {
+        let scaled = [p[0] * self.scale[0], p[1] * self.scale[1], p[2] * self.scale[2]];
+        let r = rotate(self.rotation, scaled);
+        [self.translation[0] + r[0], self.translation[1] + r[1], self.translation[2] + r[2]]
+    }
+}
+
+impl Transform for Transform3D {
+    const IDENTITY: Transform3D = Transform3D {
+        translation: [0.0; 3],
+        rotation: [0.0, 0.0, 0.0, 1.0],
+        scale: [1.0; 3],
+    };
+
+    fn mul(&self, child: &Transform3D) -> Transform3D {
+        let s = self.scale;
+        let c = child.scale;
+        Transform3D {
+            translation: self.transform_point(child.translation),
+            rotation: quat_mul(self.rotation, child.rotation),
+            scale: [s[0] * c[0], s[1] * c[1], s[2] * c[2]],
+        }
+    }
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+// v + 2w(q x v) + 2q x (q x v)
+fn rotate(q: [f32; 4], v: [f32; 3]) -> [f32; 3] {
+    let u = [q[0], q[1], q[2]];
+    let t = cross(u, v).map(|x| 2.0 * x);
+    let ut = cross(u, t);
+    [v[0] + q[3] * t[0] + ut[0], v[1] + q[3] * t[1] + ut[1], v[2] + q[3] * t[2] + ut[2]]
+}
+
+fn quat_mul(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    [
+        a[3] * b[0] + a[0] * b[3] + a[1] * b[2] - a[2] * b[1],
+        a[3] * b[1] - a[0] * b[2] + a[1] * b[3] + a[2] * b[0],
+        a[3] * b[2] + a[0] * b[1] - a[1] * b[0] + a[2] * b[3],
+        a[3] * b[3] - a[0] * b[0] - a[1] * b[1] - a[2] * b[2],
+    ]
+}
+
+// Relative to the parent, or to the world for entities without a transformed parent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocalTransform<T>(pub T);
+
+// Written by TransformSystem, added by it to entities which don't have it yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GlobalTransform<T>(pub T);
+
+pub type LocalTransform2D = LocalTransform<Transform2D>;
+pub type GlobalTransform2D = GlobalTransform<Transform2D>;
+pub type LocalTransform3D = LocalTransform<Transform3D>;
+pub type GlobalTransform3D = GlobalTransform<Transform3D>;
+
+// Computes GlobalTransform of every entity with LocalTransform from its Parent chain. Entity
+// is dirty when its local transform or parent changed since the previous apply, only dirty
+// entities and their descendants are recomputed. Entities whose parent chain loops are left
+// alone and reported in cycles().
+#[derive(Clone)]
+pub struct TransformSystem<T: Transform> {
+    signature: Signature,
+    seen: HashMap<Entity, (T, Option<Entity>)>,
+    recomputed: Vec<Entity>,
+    cycles: Vec<Vec<Entity>>,
+}
+
+impl<T: Transform> TransformSystem<T> {
+    pub fn new() -> TransformSystem<T> {
+        TransformSystem {
+            signature: Signature::new().require::<LocalTransform<T>>(),
+            seen: HashMap::new(),
+            recomputed: Vec::new(),
+            cycles: Vec::new(),
+        }
+    }
+
+    // Entities recomputed by the last apply, in traversal order
+    pub fn recomputed(&self) -> &[Entity] {
+        &self.recomputed
+    }
+
+    // Parent loops found by the last apply
+    pub fn cycles(&self) -> &[Vec<Entity>] {
+        &self.cycles
+    }
+
+    // Priv
+
+    // Marks every entity with whether its parent chain ends in a root, loops are recorded
+    fn find_cycles(&mut self, entities: &[Entity], parents: &HashMap<Entity, Option<Entity>>) -> HashMap<Entity, bool> {
+        self.cycles.clear();
+        let mut rooted: HashMap<Entity, bool> = HashMap::with_capacity(entities.len());
+        for e in entities {
+            let mut path = Vec::new();
+            let mut on_path = HashSet::new();
+            let mut current = *e;
+            let ok = loop {
+                if let Some(ok) = rooted.get(&current) {
+                    break *ok;
+                }
+                if !on_path.insert(current) {
+                    let start = path.iter().position(|p| *p == current).unwrap();
+                    self.cycles.push(path[start..].to_vec());
+                    break false;
+                }
+                path.push(current);
+                match parents[&current] {
+                    Some(parent) => current = parent,
+                    None => break true,
+                }
+            };
+            for p in path {
+                rooted.insert(p, ok);
+            }
+        }
+        rooted
+    }
+}
+
+impl<T: Transform> Default for TransformSystem<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn local<T: Transform>(cm: &ComponentManager, e: Entity) -> T {
+    let local = cm.get_any(e, ComponentType::of::<LocalTransform<T>>()).unwrap();
+    local.downcast_ref::<LocalTransform<T>>().unwrap().0
+}
+
+impl<T: Transform> System for TransformSystem<T> {
+    fn remove(&mut self, e: Entity) {
+        self.seen.remove(&e);
+    }
+
+    fn get_signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn box_clone(&self) -> Option<Rc<RefCell<dyn System>>> {
+        Some(Rc::new(RefCell::new(self.clone())))
+    }
+
+    fn apply(&mut self, entities: &[Entity], cm: &mut ComponentManager) -> Deferred {
+        // Parent without a local transform doesn't take part, its child is a root
+        let members: HashSet<Entity> = entities.iter().copied().collect();
+        let mut parents = HashMap::with_capacity(entities.len());
+        let mut children: HashMap<Entity, Vec<Entity>> = HashMap::new();
+        for e in entities {
+            let parent = hierarchy::parent_of(cm, *e).filter(|p| members.contains(p));
+            parents.insert(*e, parent);
+            if let Some(parent) = parent {
+                children.entry(parent).or_default().push(*e);
+            }
+        }
+
+        let rooted = self.find_cycles(entities, &parents);
+        let global_id = ComponentType::of::<GlobalTransform<T>>();
+        let mut dirty = HashSet::new();
+        for e in entities {
+            if !rooted[e] {
+                self.seen.remove(e);
+                continue;
+            }
+            let current = (local::<T>(cm, *e), parents[e]);
+            if self.seen.insert(*e, current) != Some(current) || cm.get_any(*e, global_id).is_none() {
+                dirty.insert(*e);
+            }
+        }
+
+        // Clean subtrees without any dirty entity below are not visited at all
+        let mut leads_to_dirty = HashSet::new();
+        for e in dirty.iter() {
+            let mut current = Some(*e);
+            while let Some(c) = current {
+                if !leads_to_dirty.insert(c) {
+                    break;
+                }
+                current = parents[&c];
+            }
+        }
+
+        self.recomputed.clear();
+        let mut missing = Vec::new();
+        let roots = entities.iter().filter(|e| parents[*e].is_none() && leads_to_dirty.contains(*e));
+        for root in roots {
+            let mut stack = vec![(*root, T::IDENTITY, false)];
+            while let Some((e, parent_global, parent_recomputed)) = stack.pop() {
+                let recompute = parent_recomputed || dirty.contains(&e);
+                let global = if recompute {
+                    let global = parent_global.mul(&local::<T>(cm, e));
+                    match cm.get_mut::<GlobalTransform<T>>(&e) {
+                        Some(current) => current.0 = global,
+                        None => missing.push((e, global)),
+                    }
+                    self.recomputed.push(e);
+                    global
+                } else {
+                    cm.get::<GlobalTransform<T>>(&e).unwrap().0
+                };
+
+                for child in children.get(&e).map(|c| c.as_slice()).unwrap_or(&[]).iter().rev() {
+                    if recompute || leads_to_dirty.contains(child) {
+                        stack.push((*child, global, recompute));
+                    }
+                }
+            }
+        }
+
+        Box::new(move |coordinator| {
+            for (e, global) in missing.iter() {
+                coordinator.add_component(*e, GlobalTransform(*global));
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(expected: [f32; 2], actual: [f32; 2]) {
+        assert!((expected[0] - actual[0]).abs() < 1e-5 && (expected[1] - actual[1]).abs() < 1e-5,
+            "{:?} != {:?}", expected, actual);
+    }
+
+    #[test]
+    fn test_transform_2d() {
+        let parent = Transform2D { translation: [1.0, 0.0], rotation: std::f32::consts::FRAC_PI_2, scale: [2.0, 2.0] };
+        let child = Transform2D::from_translation(1.0, 0.0);
+        let global = parent.mul(&child);
+        assert_close([1.0, 2.0], global.translation);
+        assert_eq!([2.0, 2.0], global.scale);
+        assert_eq!(parent, Transform2D::IDENTITY.mul(&parent));
+    }
+
+    #[test]
+    fn test_transform_3d() {
+        let parent = Transform3D::from_axis_angle([0.0, 0.0, 1.0], std::f32::consts::FRAC_PI_2);
+        let child = Transform3D::from_translation(1.0, 0.0, 0.0);
+        let global = parent.mul(&child);
+        assert_close([0.0, 1.0], [global.translation[0], global.translation[1]]);
+        assert!(global.translation[2].abs() < 1e-5);
+
+        let twice = parent.mul(&parent).transform_point([1.0, 0.0, 0.0]);
+        assert_close([-1.0, 0.0], [twice[0], twice[1]]);
+    }
+}